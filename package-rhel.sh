@@ -134,20 +134,33 @@ After=network-online.target
 Wants=network-online.target
 
 [Service]
-Type=simple
+Type=notify
+NotifyAccess=main
 User=${SERVICE_USER}
 Group=${SERVICE_GROUP}
-WorkingDirectory=/
+WorkingDirectory=${STATE_DIR}
 ExecStart=/usr/bin/${PKGNAME} --daemon
+ExecReload=/bin/kill -HUP \$MAINPID
+WatchdogSec=60
+KillMode=mixed
+TimeoutStopSec=30
 Restart=always
 RestartSec=2
 Environment=RUST_BACKTRACE=1
+RuntimeDirectory=magicbot
+RuntimeDirectoryPreserve=yes
+UMask=0027
 
 # Hardening (safe defaults; loosen if you hit permission issues)
 NoNewPrivileges=true
 PrivateTmp=true
-ProtectSystem=full
+ProtectSystem=strict
 ProtectHome=true
+ProtectKernelTunables=true
+ProtectKernelModules=true
+ProtectControlGroups=true
+RestrictSUIDSGID=true
+LockPersonality=true
 
 # Allow writing state/log/run dirs
 ReadWritePaths=${STATE_DIR} ${RUN_DIR} ${LOG_DIR}
@@ -243,9 +256,9 @@ install -D -m 0755 %{name} %{buildroot}%{_bindir}/%{name}
 install -D -m 0644 %{name}.service %{buildroot}%{_unitdir}/%{name}.service
 
 # state/log/run dirs (owned by service user)
-install -d -m 0755 %{buildroot}%{_localstatedir}/lib/magicbot
-install -d -m 0755 %{buildroot}%{_localstatedir}/log/magicbot
-install -d -m 0755 %{buildroot}%{_rundir}/magicbot
+install -d -m 0750 %{buildroot}%{_localstatedir}/lib/magicbot
+install -d -m 0750 %{buildroot}%{_localstatedir}/log/magicbot
+install -d -m 0750 %{buildroot}%{_rundir}/magicbot
 
 %pre
 # Create magicbot user/group if not exist
@@ -0,0 +1,190 @@
+use crate::paths;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::sync::Mutex;
+use std::thread;
+
+// 同一种状况 10 分钟内只报一次
+const ALERT_THROTTLE_SECS: i64 = 600;
+
+static LAST_RAISED: Mutex<Option<HashMap<Condition, i64>>> = Mutex::new(None);
+static CONFIG: Mutex<Option<AlertConfig>> = Mutex::new(None);
+
+// global.json 里的 "alerts" 段。告警总会写日志和 LOG_DIR/alerts.jsonl；
+// 配了 webhook_url 再额外 POST 一份（走 curl，Signal 本身可能正被限流）
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AlertConfig {
+	pub webhook_url: Option<String>,
+	// 需要知会的管理员（号码/用户名等），随告警一起带上
+	pub admins: Vec<String>,
+}
+
+pub fn configure(cfg: &AlertConfig) {
+	*CONFIG.lock().unwrap() = Some(cfg.clone());
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+	Debug,
+	Info,
+	Warn,
+	Error,
+}
+
+// signal-cli 输出里需要人工介入的已知状况
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Condition {
+	CaptchaRequired,
+	RateLimited,
+	OutboundPaused,
+	Unregistered,
+	UntrustedIdentity,
+}
+
+impl Condition {
+	pub fn hint(self) -> &'static str {
+		match self {
+			Condition::CaptchaRequired => "需要完成 captcha：菜单 8 或提交 signalcaptcha:// token",
+			Condition::RateLimited => "账号被限流，发送会失败，需提交 rate limit challenge",
+			Condition::OutboundPaused => "已暂停外发，提交 captcha 后自动恢复",
+			Condition::Unregistered => "账号已失效/未注册，需要重新注册或绑定",
+			Condition::UntrustedIdentity => "有联系人的安全码变了，消息可能无法送达",
+		}
+	}
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct DaemonEvent {
+	pub ts: i64,
+	pub condition: Condition,
+	pub detail: String,
+}
+
+impl DaemonEvent {
+	pub fn new(condition: Condition, detail: &str) -> Self {
+		DaemonEvent {
+			ts: Utc::now().timestamp(),
+			condition,
+			detail: detail.to_string(),
+		}
+	}
+}
+
+pub fn alerts_path() -> PathBuf {
+	paths::log_dir().join("alerts.jsonl")
+}
+
+// signal-cli 日志行形如 "WARN  ManagerImpl - ..." 或带时间戳/线程名的 verbose 格式
+pub fn classify(line: &str) -> (Severity, Option<Condition>) {
+	let sev = line
+		.split_whitespace()
+		.take(4)
+		.find_map(|tok| match tok.trim_matches(|c: char| !c.is_ascii_alphabetic()) {
+			"ERROR" | "SEVERE" => Some(Severity::Error),
+			"WARN" | "WARNING" => Some(Severity::Warn),
+			"INFO" => Some(Severity::Info),
+			"DEBUG" | "TRACE" => Some(Severity::Debug),
+			_ => None,
+		})
+		.unwrap_or(Severity::Warn);
+
+	(sev, detect_condition(line))
+}
+
+pub fn detect_condition(text: &str) -> Option<Condition> {
+	let l = text.to_lowercase();
+	if l.contains("captcha required") || l.contains("captcharequired") {
+		return Some(Condition::CaptchaRequired);
+	}
+	if l.contains("rate limit") || l.contains("ratelimit") || l.contains("proof required") || l.contains("proofrequired") {
+		return Some(Condition::RateLimited);
+	}
+	if l.contains("unregistered") || l.contains("not registered") || l.contains("authorizationfailed") {
+		return Some(Condition::Unregistered);
+	}
+	if l.contains("untrusted identity") || l.contains("untrustedidentity") {
+		return Some(Condition::UntrustedIdentity);
+	}
+	None
+}
+
+// 转发 signal-cli 的一行 stderr 到本进程日志；命中已知状况时触发告警
+pub fn forward_stderr_line(line: &str) {
+	let line = line.trim();
+	if line.is_empty() {
+		return;
+	}
+	let (sev, cond) = classify(line);
+	match sev {
+		Severity::Error | Severity::Warn => eprintln!("[WRN] signal-cli: {line}"),
+		Severity::Info => println!("[INF] signal-cli: {line}"),
+		Severity::Debug => {}
+	}
+	// INFO 行里出现方法名（如 submitRateLimitChallenge）不算
+	if let Some(c) = cond.filter(|_| sev >= Severity::Warn) {
+		raise(&DaemonEvent::new(c, line));
+	}
+}
+
+pub fn raise(ev: &DaemonEvent) {
+	{
+		let mut guard = LAST_RAISED.lock().unwrap();
+		let last = guard.get_or_insert_with(HashMap::new);
+		if let Some(t) = last.get(&ev.condition) {
+			if ev.ts - t < ALERT_THROTTLE_SECS {
+				return;
+			}
+		}
+		last.insert(ev.condition, ev.ts);
+	}
+
+	let cfg = CONFIG.lock().unwrap().clone().unwrap_or_default();
+	let admins = cfg.admins.join(", ");
+	eprintln!("[ALERT] {:?}: {} ({}) admins=[{admins}]", ev.condition, ev.condition.hint(), ev.detail);
+
+	let payload = serde_json::json!({
+		"ts": ev.ts,
+		"condition": ev.condition,
+		"hint": ev.condition.hint(),
+		"detail": ev.detail,
+		"admins": cfg.admins,
+		"text": format!("[magicbot] {}: {}", ev.condition.hint(), ev.detail),
+	})
+	.to_string();
+
+	let _ = fs::create_dir_all(paths::log_dir());
+	match OpenOptions::new().create(true).append(true).open(alerts_path()) {
+		Ok(mut f) => {
+			let _ = writeln!(f, "{payload}");
+		}
+		Err(e) => eprintln!("[WRN] write {}: {e}", alerts_path().display()),
+	}
+
+	if let Some(url) = cfg.webhook_url.filter(|u| !u.trim().is_empty()) {
+		thread::spawn(move || {
+			let r = Command::new("curl")
+				.arg("-fsS")
+				.arg("-m")
+				.arg("10")
+				.arg("-H")
+				.arg("Content-Type: application/json")
+				.arg("-d")
+				.arg(&payload)
+				.arg(&url)
+				.stdout(Stdio::null())
+				.output();
+			match r {
+				Ok(out) if out.status.success() => {}
+				Ok(out) => eprintln!("[WRN] alert webhook failed: {}", String::from_utf8_lossy(&out.stderr).trim()),
+				Err(e) => eprintln!("[WRN] alert webhook failed: {e}"),
+			}
+		});
+	}
+}
@@ -0,0 +1,589 @@
+use crate::proc::{describe_exit, drain_stderr, supervise_child, terminate, StderrTail};
+use crate::ratelimit::{HitKind, RateLimitHit};
+use crate::rpc::JsonRpc;
+use crate::{run_ok, run_signal_json};
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::VecDeque;
+use std::io::{BufRead, BufReader};
+use std::process::{Child, Command, Stdio};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Identity {
+	pub id: String,
+	pub number: Option<String>,
+	pub name: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct GroupFull {
+	pub id: String,
+	pub name: String,
+	pub admins: Vec<Identity>,
+	pub members: Vec<Identity>,
+	#[serde(default)]
+	pub permissions: GroupPermissions,
+}
+
+// listGroups 里的 permissionAddMember / permissionSendMessage / permissionEditDetails
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct GroupPermissions {
+	pub add_member: Option<String>,
+	pub send_messages: Option<String>,
+	pub edit_details: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct GroupUpdate {
+	pub remove_members: Vec<String>,
+	pub permission_add_member: Option<String>,
+	pub permission_send_messages: Option<String>,
+	pub permission_edit_details: Option<String>,
+}
+
+impl GroupUpdate {
+	pub fn remove_member(who: &str) -> Self {
+		GroupUpdate {
+			remove_members: vec![who.to_string()],
+			..Default::default()
+		}
+	}
+
+	pub fn permissions(add_member: &str, send_messages: &str, edit_details: &str) -> Self {
+		GroupUpdate {
+			permission_add_member: Some(add_member.to_string()),
+			permission_send_messages: Some(send_messages.to_string()),
+			permission_edit_details: Some(edit_details.to_string()),
+			..Default::default()
+		}
+	}
+}
+
+// Signal 的全部 I/O 都经过这里；receive 产出的每一行与 `signal-cli -o json receive` 同格式
+pub trait Backend: Send + Sync {
+	fn receive(&self) -> Result<Receiver<String>>;
+	fn send_group_message(&self, gid: &str, msg: &str) -> Result<()>;
+	fn update_group(&self, gid: &str, upd: &GroupUpdate) -> Result<()>;
+	fn list_groups(&self) -> Result<Vec<GroupFull>>;
+	fn list_contacts(&self) -> Result<Vec<Identity>>;
+	fn submit_rate_limit_challenge(&self, challenge: &str, captcha: &str) -> Result<()>;
+
+	// receive 流结束后调用：说明接收进程为什么退出
+	fn receiver_exit_reason(&self) -> Option<String> {
+		None
+	}
+
+	// 外发被暂停（限流）时为 true，重试队列据此不消耗重试次数
+	fn outbound_paused(&self) -> bool {
+		false
+	}
+
+	// 收到会改变群/联系人信息的事件时调用，只有带缓存的实现关心
+	fn invalidate(&self, _scope: CacheScope) {}
+
+	// 守护进程退出时调用：结束常驻的接收进程
+	fn shutdown(&self) {}
+
+	// 接收进程还在跑、没有卡住；systemd 看门狗据此决定要不要喂狗
+	fn receiver_healthy(&self) -> bool {
+		true
+	}
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum CacheScope {
+	Groups,
+	Contacts,
+}
+
+// 每个动作起一个 signal-cli 进程
+pub struct SubprocessBackend {
+	acc: String,
+	cfgdir: Option<String>,
+	receiver: Mutex<Option<(Child, StderrTail)>>,
+}
+
+impl SubprocessBackend {
+	pub fn new(acc: &str, cfgdir: Option<&str>) -> Self {
+		SubprocessBackend {
+			acc: acc.to_string(),
+			cfgdir: cfgdir.map(|s| s.to_string()),
+			receiver: Mutex::new(None),
+		}
+	}
+
+	fn command(&self) -> Command {
+		let mut cmd = Command::new("signal-cli");
+		if let Some(d) = &self.cfgdir {
+			cmd.arg("--config").arg(d);
+		}
+		cmd.arg("-u").arg(&self.acc);
+		cmd
+	}
+
+	fn json(&self, args: &[&str]) -> Result<Value> {
+		run_signal_json(Command::new("signal-cli"), self.cfgdir.as_deref(), Some(&self.acc), args)
+	}
+
+	fn stop_receiver(&self) {
+		if let Some((mut child, _)) = self.receiver.lock().unwrap().take() {
+			terminate(&mut child);
+		}
+	}
+}
+
+impl Drop for SubprocessBackend {
+	fn drop(&mut self) {
+		self.stop_receiver();
+	}
+}
+
+impl Backend for SubprocessBackend {
+	fn receive(&self) -> Result<Receiver<String>> {
+		self.stop_receiver();
+
+		let mut cmd = self.command();
+		cmd.arg("-o").arg("json").arg("receive");
+		cmd.arg("-t").arg("-1");
+		cmd.arg("--ignore-attachments");
+		cmd.stdout(Stdio::piped()).stderr(Stdio::piped());
+		supervise_child(&mut cmd);
+		let mut child = cmd.spawn().context("spawn signal-cli receive")?;
+		let stdout = child.stdout.take().ok_or_else(|| anyhow!("no stdout"))?;
+		let stderr = child.stderr.take().ok_or_else(|| anyhow!("no stderr"))?;
+		let tail = drain_stderr(stderr);
+
+		let (tx, rx) = mpsc::channel();
+		thread::spawn(move || {
+			for line in BufReader::new(stdout).lines() {
+				let Ok(line) = line else { break };
+				if tx.send(line).is_err() {
+					break;
+				}
+			}
+		});
+		*self.receiver.lock().unwrap() = Some((child, tail));
+		Ok(rx)
+	}
+
+	fn receiver_exit_reason(&self) -> Option<String> {
+		let mut guard = self.receiver.lock().unwrap();
+		let (child, tail) = guard.as_mut()?;
+		Some(describe_exit(child, tail))
+	}
+
+	fn shutdown(&self) {
+		self.stop_receiver();
+	}
+
+	fn receiver_healthy(&self) -> bool {
+		match self.receiver.lock().unwrap().as_mut() {
+			Some((child, _)) => matches!(child.try_wait(), Ok(None)),
+			None => false,
+		}
+	}
+
+	fn send_group_message(&self, gid: &str, msg: &str) -> Result<()> {
+		let mut cmd = self.command();
+		cmd.arg("send").arg("-g").arg(gid).arg("-m").arg(msg);
+		run_ok(&mut cmd)
+	}
+
+	fn update_group(&self, gid: &str, upd: &GroupUpdate) -> Result<()> {
+		let mut cmd = self.command();
+		cmd.arg("updateGroup").arg("-g").arg(gid);
+		for who in &upd.remove_members {
+			cmd.arg("--remove-member").arg(who);
+		}
+		if let Some(p) = &upd.permission_add_member {
+			cmd.arg("--set-permission-add-member").arg(p);
+		}
+		if let Some(p) = &upd.permission_send_messages {
+			cmd.arg("--set-permission-send-messages").arg(p);
+		}
+		if let Some(p) = &upd.permission_edit_details {
+			cmd.arg("--set-permission-edit-details").arg(p);
+		}
+		run_ok(&mut cmd)
+	}
+
+	fn list_groups(&self) -> Result<Vec<GroupFull>> {
+		parse_groups(&self.json(&["-o", "json", "listGroups"])?)
+	}
+
+	fn list_contacts(&self) -> Result<Vec<Identity>> {
+		Ok(parse_contacts(&self.json(&[
+			"-o",
+			"json",
+			"listContacts",
+			"--all-recipients",
+			"--detailed",
+		])?))
+	}
+
+	fn submit_rate_limit_challenge(&self, challenge: &str, captcha: &str) -> Result<()> {
+		let mut cmd = self.command();
+		cmd.arg("submitRateLimitChallenge");
+		cmd.arg("--challenge").arg(challenge).arg("--captcha").arg(captcha);
+		run_ok(&mut cmd)
+	}
+}
+
+// 常驻 signal-cli jsonRpc：receive 时拉起进程，之后的调用都走这条连接；连接不可用时退回子进程
+pub struct JsonRpcBackend {
+	acc: String,
+	cfgdir: Option<String>,
+	rpc: Mutex<Option<Arc<JsonRpc>>>,
+	fallback: SubprocessBackend,
+}
+
+impl JsonRpcBackend {
+	pub fn new(acc: &str, cfgdir: Option<&str>) -> Self {
+		JsonRpcBackend {
+			acc: acc.to_string(),
+			cfgdir: cfgdir.map(|s| s.to_string()),
+			rpc: Mutex::new(None),
+			fallback: SubprocessBackend::new(acc, cfgdir),
+		}
+	}
+
+	// None = 没有可用的 jsonRpc 连接，调用方应走子进程
+	fn call(&self, method: &str, params: Value) -> Option<Result<Value>> {
+		let rpc = self.rpc.lock().unwrap().clone().filter(|r| r.is_alive())?;
+		let r = rpc.call(method, params);
+		if r.is_err() && !rpc.is_alive() {
+			eprintln!("[WRN] jsonRpc connection lost, falling back to signal-cli subprocess");
+			return None;
+		}
+		Some(r)
+	}
+}
+
+impl Backend for JsonRpcBackend {
+	fn receive(&self) -> Result<Receiver<String>> {
+		if let Some(old) = self.rpc.lock().unwrap().take() {
+			old.shutdown();
+		}
+		match JsonRpc::spawn(&self.acc, self.cfgdir.as_deref()) {
+			Ok((rpc, rx)) => {
+				println!("[INF] signal-cli jsonRpc started");
+				*self.rpc.lock().unwrap() = Some(Arc::new(rpc));
+				Ok(rx)
+			}
+			Err(e) => {
+				eprintln!("[WRN] jsonRpc unavailable, falling back to signal-cli receive: {e:#}");
+				self.fallback.receive()
+			}
+		}
+	}
+
+	fn send_group_message(&self, gid: &str, msg: &str) -> Result<()> {
+		if let Some(r) = self.call("send", json!({ "groupId": gid, "message": msg })) {
+			return check_send_results(&r?);
+		}
+		self.fallback.send_group_message(gid, msg)
+	}
+
+	fn update_group(&self, gid: &str, upd: &GroupUpdate) -> Result<()> {
+		let mut params = json!({ "groupId": gid });
+		if !upd.remove_members.is_empty() {
+			params["removeMember"] = json!(upd.remove_members);
+		}
+		if let Some(p) = &upd.permission_add_member {
+			params["setPermissionAddMember"] = json!(p);
+		}
+		if let Some(p) = &upd.permission_send_messages {
+			params["setPermissionSendMessages"] = json!(p);
+		}
+		if let Some(p) = &upd.permission_edit_details {
+			params["setPermissionEditDetails"] = json!(p);
+		}
+		if let Some(r) = self.call("updateGroup", params) {
+			return r.map(|_| ());
+		}
+		self.fallback.update_group(gid, upd)
+	}
+
+	fn list_groups(&self) -> Result<Vec<GroupFull>> {
+		if let Some(r) = self.call("listGroups", json!({})) {
+			return parse_groups(&r?);
+		}
+		self.fallback.list_groups()
+	}
+
+	fn list_contacts(&self) -> Result<Vec<Identity>> {
+		if let Some(r) = self.call("listContacts", json!({ "allRecipients": true, "detailed": true })) {
+			return Ok(parse_contacts(&r?));
+		}
+		self.fallback.list_contacts()
+	}
+
+	fn submit_rate_limit_challenge(&self, challenge: &str, captcha: &str) -> Result<()> {
+		let params = json!({ "challenge": challenge, "captcha": captcha });
+		if let Some(r) = self.call("submitRateLimitChallenge", params) {
+			return r.map(|_| ());
+		}
+		self.fallback.submit_rate_limit_challenge(challenge, captcha)
+	}
+
+	fn receiver_exit_reason(&self) -> Option<String> {
+		if let Some(rpc) = self.rpc.lock().unwrap().clone() {
+			return Some(rpc.exit_reason());
+		}
+		self.fallback.receiver_exit_reason()
+	}
+
+	fn shutdown(&self) {
+		if let Some(rpc) = self.rpc.lock().unwrap().take() {
+			rpc.shutdown();
+		}
+		self.fallback.shutdown();
+	}
+
+	fn receiver_healthy(&self) -> bool {
+		if let Some(rpc) = self.rpc.lock().unwrap().clone() {
+			return rpc.is_healthy();
+		}
+		self.fallback.receiver_healthy()
+	}
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum FakeAction {
+	Send { gid: String, msg: String },
+	UpdateGroup { gid: String, upd: GroupUpdate },
+	SubmitChallenge { challenge: String, captcha: String },
+}
+
+#[derive(Default)]
+struct FakeState {
+	incoming: Vec<String>,
+	groups: Vec<GroupFull>,
+	contacts: Vec<Identity>,
+	actions: Vec<FakeAction>,
+	failures: VecDeque<String>,
+}
+
+// 内存里的假 Signal：预置群/联系人/收件，记录所有发出的动作，可按顺序注入失败
+#[derive(Default)]
+pub struct FakeBackend {
+	state: Mutex<FakeState>,
+}
+
+impl FakeBackend {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	pub fn push_incoming(&self, line: &str) {
+		self.state.lock().unwrap().incoming.push(line.to_string());
+	}
+
+	pub fn set_groups(&self, groups: Vec<GroupFull>) {
+		self.state.lock().unwrap().groups = groups;
+	}
+
+	pub fn set_contacts(&self, contacts: Vec<Identity>) {
+		self.state.lock().unwrap().contacts = contacts;
+	}
+
+	// 下一次 send/updateGroup 以 err 失败
+	#[cfg(test)]
+	pub fn fail_next(&self, err: &str) {
+		self.state.lock().unwrap().failures.push_back(err.to_string());
+	}
+
+	#[cfg(test)]
+	pub fn actions(&self) -> Vec<FakeAction> {
+		self.state.lock().unwrap().actions.clone()
+	}
+
+	pub fn take_actions(&self) -> Vec<FakeAction> {
+		std::mem::take(&mut self.state.lock().unwrap().actions)
+	}
+
+	fn record(&self, action: FakeAction) -> Result<()> {
+		let mut st = self.state.lock().unwrap();
+		if let Some(err) = st.failures.pop_front() {
+			return Err(anyhow!("{err}"));
+		}
+		st.actions.push(action);
+		Ok(())
+	}
+}
+
+impl Backend for FakeBackend {
+	fn receive(&self) -> Result<Receiver<String>> {
+		let (tx, rx) = mpsc::channel();
+		for line in self.state.lock().unwrap().incoming.drain(..) {
+			let _ = tx.send(line);
+		}
+		Ok(rx)
+	}
+
+	fn send_group_message(&self, gid: &str, msg: &str) -> Result<()> {
+		self.record(FakeAction::Send {
+			gid: gid.to_string(),
+			msg: msg.to_string(),
+		})
+	}
+
+	fn update_group(&self, gid: &str, upd: &GroupUpdate) -> Result<()> {
+		self.record(FakeAction::UpdateGroup {
+			gid: gid.to_string(),
+			upd: upd.clone(),
+		})?;
+		let mut st = self.state.lock().unwrap();
+		if let Some(g) = st.groups.iter_mut().find(|g| g.id == gid) {
+			g.members.retain(|m| !upd.remove_members.contains(&m.id));
+			g.admins.retain(|m| !upd.remove_members.contains(&m.id));
+		}
+		Ok(())
+	}
+
+	fn list_groups(&self) -> Result<Vec<GroupFull>> {
+		Ok(self.state.lock().unwrap().groups.clone())
+	}
+
+	fn list_contacts(&self) -> Result<Vec<Identity>> {
+		Ok(self.state.lock().unwrap().contacts.clone())
+	}
+
+	fn submit_rate_limit_challenge(&self, challenge: &str, captcha: &str) -> Result<()> {
+		self.record(FakeAction::SubmitChallenge {
+			challenge: challenge.to_string(),
+			captcha: captcha.to_string(),
+		})
+	}
+}
+
+// jsonRpc 的 send 即使部分接收者失败也返回成功；限流类失败要当成错误抛出，供上层暂停外发
+fn check_send_results(v: &Value) -> Result<()> {
+	let Some(results) = v.get("results").and_then(|r| r.as_array()) else {
+		return Ok(());
+	};
+	for r in results {
+		let kind = match r.get("type").and_then(|t| t.as_str()).unwrap_or("") {
+			"PROOF_REQUIRED_FAILURE" => HitKind::ProofRequired,
+			"RATE_LIMIT_FAILURE" => HitKind::RateLimit,
+			_ => continue,
+		};
+		return Err(RateLimitHit {
+			kind,
+			challenge: r
+				.get("token")
+				.and_then(|t| t.as_str())
+				.filter(|t| !t.is_empty())
+				.map(|t| t.to_string()),
+			retry_after: r.get("retryAfterSeconds").and_then(|t| t.as_u64()).filter(|s| *s > 0),
+		}
+		.into());
+	}
+	Ok(())
+}
+
+pub fn parse_groups(v: &Value) -> Result<Vec<GroupFull>> {
+	let arr = v.as_array().ok_or_else(|| anyhow!("listGroups not array"))?;
+	let mut out = vec![];
+	for g in arr {
+		let id = g.get("id").and_then(|x| x.as_str()).unwrap_or("").to_string();
+		let name = g.get("name").and_then(|x| x.as_str()).unwrap_or("").to_string();
+
+		let admins = parse_identities(g.get("admins"));
+		let members = parse_identities(g.get("members"));
+		let perm = |k: &str| g.get(k).and_then(|x| x.as_str()).map(|s| s.to_string());
+		let permissions = GroupPermissions {
+			add_member: perm("permissionAddMember"),
+			send_messages: perm("permissionSendMessage"),
+			edit_details: perm("permissionEditDetails"),
+		};
+
+		if !id.is_empty() {
+			out.push(GroupFull {
+				id,
+				name,
+				admins,
+				members,
+				permissions,
+			});
+		}
+	}
+	Ok(out)
+}
+
+fn parse_identities(v: Option<&Value>) -> Vec<Identity> {
+	let mut out = vec![];
+	let Some(v) = v else { return out };
+	let Some(arr) = v.as_array() else { return out };
+
+	for it in arr {
+		let uuid = it.get("uuid").and_then(|x| x.as_str()).map(|s| s.to_string());
+		let number = it.get("number").and_then(|x| x.as_str()).map(|s| s.to_string());
+
+		let id = uuid.or(number.clone()).unwrap_or_else(|| "".to_string());
+		if id.is_empty() {
+			continue;
+		}
+
+		out.push(Identity { id, number, name: None });
+	}
+	out
+}
+
+fn parse_contacts(v: &Value) -> Vec<Identity> {
+	let mut out = vec![];
+	let Some(arr) = v.as_array() else { return out };
+	for c in arr {
+		let uuid = c.get("uuid").and_then(|x| x.as_str()).map(|s| s.to_string());
+		let number = c.get("number").and_then(|x| x.as_str()).map(|s| s.to_string());
+		let name = c.get("name").and_then(|x| x.as_str()).map(|s| s.to_string());
+
+		let id = uuid.or(number.clone()).unwrap_or_default();
+		if id.is_empty() {
+			continue;
+		}
+		out.push(Identity { id, number, name });
+	}
+	out
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn hit(v: Value) -> RateLimitHit {
+		let e = check_send_results(&v).unwrap_err();
+		e.downcast_ref::<RateLimitHit>().cloned().unwrap()
+	}
+
+	#[test]
+	fn send_results_without_failures_are_ok() {
+		assert!(check_send_results(&json!({ "timestamp": 1 })).is_ok());
+		assert!(check_send_results(&json!({ "results": [{ "type": "SUCCESS" }] })).is_ok());
+	}
+
+	#[test]
+	fn proof_required_without_retry_after() {
+		let h = hit(json!({ "results": [
+			{ "type": "SUCCESS" },
+			{ "type": "PROOF_REQUIRED_FAILURE", "token": "tok-1" },
+		] }));
+		assert_eq!(h.kind, HitKind::ProofRequired);
+		assert_eq!(h.challenge.as_deref(), Some("tok-1"));
+		assert_eq!(h.retry_after, None);
+	}
+
+	#[test]
+	fn rate_limit_failure_keeps_its_kind() {
+		let h = hit(json!({ "results": [{ "type": "RATE_LIMIT_FAILURE", "retryAfterSeconds": 300 }] }));
+		assert_eq!(h.kind, HitKind::RateLimit);
+		assert_eq!(h.challenge, None);
+		assert_eq!(h.retry_after, Some(300));
+		assert_eq!(h.to_string(), "rate limited, retry after 300s");
+	}
+}
@@ -0,0 +1,508 @@
+use crate::cli::{self, json_mode, positionals, report, usage, EXIT_INVALID, EXIT_NOT_READY};
+use crate::{groups_dir, has_flag, load_global, lock, paths, run_ok, schema, theme, APP};
+use anyhow::{anyhow, Context, Result};
+use chrono::{Local, Utc};
+use dialoguer::Confirm;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeSet;
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::IsTerminal;
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::path::{Component, Path, PathBuf};
+use std::process::Command;
+
+const FORMAT: &str = "magicbot-backup";
+// 归档布局或 manifest 有不兼容的改动时加一
+const FORMAT_VERSION: u32 = 1;
+const MANIFEST: &str = "manifest.json";
+// 状态目录里要带走的；pid、缓存快照、outbox 之类跟着本机走的不带
+const STATE_ITEMS: [&str; 4] = ["global.json", "groups", "marks", "state"];
+
+// 归档里的布局：manifest.json、state/<STATE_ITEMS>、signal-cli/（可选）
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct Manifest {
+	format: String,
+	format_version: u32,
+	app_version: String,
+	created_at: i64,
+	host: String,
+	global_schema: u32,
+	group_schema: u32,
+	signal_data: bool,
+	files: Vec<FileEntry>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct FileEntry {
+	path: String,
+	size: u64,
+	sha256: String,
+}
+
+// 临时目录，出错返回时也会删掉。里面是状态文件和账号密钥的副本，只给属主进
+struct Scratch(PathBuf);
+
+impl Scratch {
+	fn new(parent: &Path, tag: &str) -> Result<Self> {
+		let p = parent.join(format!(".{APP}-{tag}-{}", std::process::id()));
+		let _ = fs::remove_dir_all(&p);
+		fs::DirBuilder::new()
+			.mode(0o700)
+			.create(&p)
+			.with_context(|| format!("create dir {}", p.display()))?;
+		Ok(Scratch(p))
+	}
+}
+
+impl Drop for Scratch {
+	fn drop(&mut self) {
+		let _ = fs::remove_dir_all(&self.0);
+	}
+}
+
+fn hostname() -> String {
+	fs::read_to_string("/proc/sys/kernel/hostname")
+		.map(|s| s.trim().to_string())
+		.unwrap_or_else(|_| "unknown".to_string())
+}
+
+// 没配 signal_cli_config_dir 时 signal-cli 用 $XDG_DATA_HOME/signal-cli（服务用户下就是它 home 里那份）
+fn signal_dir(configured: Option<&str>) -> PathBuf {
+	if let Some(d) = configured {
+		return PathBuf::from(d);
+	}
+	env::var_os("XDG_DATA_HOME")
+		.map(PathBuf::from)
+		.unwrap_or_else(|| PathBuf::from(env::var_os("HOME").unwrap_or_default()).join(".local/share"))
+		.join("signal-cli")
+}
+
+fn sha256_file(p: &Path) -> Result<String> {
+	let mut f = File::open(p).with_context(|| format!("open {}", p.display()))?;
+	let mut h = Sha256::new();
+	std::io::copy(&mut f, &mut h).with_context(|| format!("read {}", p.display()))?;
+	Ok(format!("{:x}", h.finalize()))
+}
+
+// root 下所有普通文件（相对路径排好序）
+fn walk(root: &Path, rel: &Path, out: &mut Vec<PathBuf>) -> Result<()> {
+	let dir = root.join(rel);
+	let mut entries: Vec<_> = fs::read_dir(&dir)
+		.with_context(|| format!("read dir {}", dir.display()))?
+		.flatten()
+		.collect();
+	entries.sort_by_key(|e| e.file_name());
+	for e in entries {
+		let rel = rel.join(e.file_name());
+		let ft = e.file_type()?;
+		if ft.is_dir() {
+			walk(root, &rel, out)?;
+		} else if ft.is_file() {
+			out.push(rel);
+		}
+	}
+	Ok(())
+}
+
+fn entries(root: &Path) -> Result<Vec<FileEntry>> {
+	let mut files = vec![];
+	walk(root, Path::new(""), &mut files)?;
+	files
+		.into_iter()
+		.filter(|p| p != Path::new(MANIFEST))
+		.map(|rel| {
+			let p = root.join(&rel);
+			Ok(FileEntry {
+				path: rel.to_string_lossy().into_owned(),
+				size: fs::metadata(&p)?.len(),
+				sha256: sha256_file(&p)?,
+			})
+		})
+		.collect()
+}
+
+fn copy_into(src: &Path, dst_dir: &Path) -> Result<()> {
+	run_ok(Command::new("cp").arg("-a").arg(src).arg(dst_dir))
+}
+
+fn confirm_or_yes(args: &[String], json: bool, prompt: &str) -> Result<bool> {
+	if has_flag(args, "--yes") {
+		return Ok(true);
+	}
+	if json || !std::io::stdin().is_terminal() {
+		return Err(usage("非交互执行需要 --yes"));
+	}
+	Ok(Confirm::with_theme(&theme()).with_prompt(prompt).default(false).interact()?)
+}
+
+// magicbot backup [<file.tar.gz>] [--with-signal-data] [--force] [--json]
+// 带 signal-cli 数据时要求守护进程已停（它在写数据库），--force 跳过这个检查
+pub fn backup_cli(args: &[String]) -> Result<()> {
+	let json = json_mode(args);
+	let pos = positionals(args, 2);
+	let with_signal = has_flag(args, "--with-signal-data");
+	let gc = load_global()?;
+
+	let out = match pos.first() {
+		Some(p) => PathBuf::from(p),
+		None => PathBuf::from(format!("{APP}-backup-{}-{}.tar.gz", hostname(), Local::now().format("%Y%m%d-%H%M%S"))),
+	};
+	if with_signal && !has_flag(args, "--force") {
+		if let Some(pid) = lock::running_pid() {
+			return Err(cli::fail(
+				EXIT_NOT_READY,
+				format!("守护进程正在运行 (pid {pid})，signal-cli 数据可能不一致：先停止服务，或加 --force"),
+			));
+		}
+	}
+	let parent = out.parent().filter(|p| !p.as_os_str().is_empty()).unwrap_or(Path::new("."));
+	let stage = Scratch::new(parent, "backup")?;
+
+	// 先复制一份再算校验和、打包，避免守护进程边写边打包
+	let state = stage.0.join("state");
+	fs::create_dir_all(&state)?;
+	let sd = paths::state_dir();
+	lock::with_dir_lock(sd, || {
+		lock::with_dir_lock(&groups_dir(), || {
+			for item in STATE_ITEMS {
+				let src = sd.join(item);
+				if src.exists() {
+					copy_into(&src, &state)?;
+				}
+			}
+			Ok(())
+		})
+	})?;
+	let mut signal_data = false;
+	if with_signal {
+		let dir = signal_dir(gc.signal_cli_config_dir.as_deref());
+		if !dir.is_dir() {
+			return Err(anyhow!("signal-cli 数据目录 {} 不存在", dir.display()));
+		}
+		let dst = stage.0.join("signal-cli");
+		run_ok(Command::new("cp").arg("-a").arg(&dir).arg(&dst))?;
+		signal_data = true;
+	}
+
+	let files = entries(&stage.0)?;
+	let manifest = Manifest {
+		format: FORMAT.to_string(),
+		format_version: FORMAT_VERSION,
+		app_version: env!("CARGO_PKG_VERSION").to_string(),
+		created_at: Utc::now().timestamp(),
+		host: hostname(),
+		global_schema: schema::GLOBAL.current(),
+		group_schema: schema::GROUP.current(),
+		signal_data,
+		files,
+	};
+	fs::write(stage.0.join(MANIFEST), serde_json::to_vec_pretty(&manifest)?)?;
+
+	// 里面可能有账号密钥，只给属主读：tar 写之前就以 0600 建好文件
+	let tmp = out.with_extension("tmp");
+	let _ = fs::remove_file(&tmp);
+	OpenOptions::new()
+		.write(true)
+		.create_new(true)
+		.mode(0o600)
+		.open(&tmp)
+		.with_context(|| format!("create {}", tmp.display()))?;
+	let mut tar = Command::new("tar");
+	tar.arg("-czf").arg(&tmp).arg("-C").arg(&stage.0).arg(MANIFEST).arg("state");
+	if signal_data {
+		tar.arg("signal-cli");
+	}
+	if let Err(e) = run_ok(&mut tar) {
+		let _ = fs::remove_file(&tmp);
+		return Err(e);
+	}
+	fs::rename(&tmp, &out).with_context(|| format!("write {}", out.display()))?;
+
+	let bytes: u64 = manifest.files.iter().map(|f| f.size).sum();
+	report(
+		json,
+		json!({
+			"ok": true,
+			"archive": out,
+			"files": manifest.files.len(),
+			"bytes": bytes,
+			"signal_data": signal_data,
+			"format_version": FORMAT_VERSION,
+		}),
+		|| {
+			println!(
+				"[OK] 已备份 {} 个文件（{bytes} 字节{}）到 {}",
+				manifest.files.len(),
+				if signal_data { "，含 signal-cli 数据" } else { "" },
+				out.display()
+			)
+		},
+	);
+	Ok(())
+}
+
+// 归档里的路径只能是普通的相对路径
+fn safe_rel(p: &str) -> bool {
+	Path::new(p).components().all(|c| matches!(c, Component::Normal(_)))
+}
+
+fn verify(stage: &Path) -> Result<Manifest> {
+	let bad = |msg: String| cli::fail(EXIT_INVALID, msg);
+	let raw = fs::read(stage.join(MANIFEST)).map_err(|_| bad("归档里没有 manifest.json，不是 magicbot 备份".to_string()))?;
+	let m: Manifest = serde_json::from_slice(&raw).map_err(|e| bad(format!("manifest.json 无法解析: {e}")))?;
+	if m.format != FORMAT {
+		return Err(bad(format!("不是 magicbot 备份（format={}）", m.format)));
+	}
+	if m.format_version > FORMAT_VERSION {
+		return Err(bad(format!(
+			"归档格式 v{} 由更新版本的 magicbot ({}) 生成，本程序只支持到 v{FORMAT_VERSION}",
+			m.format_version, m.app_version
+		)));
+	}
+	// 旧 schema 加载时会走迁移链；新 schema 本程序读不懂
+	for (what, theirs, ours) in [
+		("global", m.global_schema, schema::GLOBAL.current()),
+		("group", m.group_schema, schema::GROUP.current()),
+	] {
+		if theirs > ours {
+			return Err(bad(format!(
+				"{what} 配置 schema v{theirs} 比本程序 (v{ours}) 新，请先升级 magicbot ({})",
+				m.app_version
+			)));
+		}
+	}
+
+	let mut problems = vec![];
+	let listed: BTreeSet<&str> = m.files.iter().map(|f| f.path.as_str()).collect();
+	for f in &m.files {
+		if !safe_rel(&f.path) {
+			problems.push(format!("{}: 路径不合法", f.path));
+			continue;
+		}
+		let p = stage.join(&f.path);
+		match fs::metadata(&p) {
+			Err(_) => problems.push(format!("{}: 缺失", f.path)),
+			Ok(md) if md.len() != f.size => problems.push(format!("{}: 大小 {} != {}", f.path, md.len(), f.size)),
+			Ok(_) if sha256_file(&p)? != f.sha256 => problems.push(format!("{}: sha256 不符", f.path)),
+			Ok(_) => {}
+		}
+	}
+	for f in entries(stage)? {
+		if !listed.contains(f.path.as_str()) {
+			problems.push(format!("{}: 不在 manifest 里", f.path));
+		}
+	}
+	if !problems.is_empty() {
+		for p in &problems {
+			eprintln!("[ERR] {p}");
+		}
+		return Err(bad(format!("完整性校验失败（{} 处）", problems.len())));
+	}
+	Ok(m)
+}
+
+// magicbot restore <file.tar.gz> [--with-signal-data] [--yes] [--json]
+// 现有数据不会删：状态目录里的挪到 pre-restore-<时间>/，signal-cli 目录改名为 <目录>.pre-restore-<时间>
+pub fn restore_cli(args: &[String]) -> Result<()> {
+	let json = json_mode(args);
+	let pos = positionals(args, 2);
+	let archive = PathBuf::from(*pos.first().ok_or_else(|| {
+		usage("magicbot restore <file.tar.gz> [--with-signal-data] [--yes]")
+	})?);
+	if !archive.is_file() {
+		return Err(cli::fail(cli::EXIT_NOT_FOUND, format!("{} 不存在", archive.display())));
+	}
+	if let Some(pid) = lock::running_pid() {
+		return Err(cli::fail(EXIT_NOT_READY, format!("守护进程正在运行 (pid {pid})，先停止服务再恢复")));
+	}
+
+	// 解到状态目录下，之后 rename 就能原子换进去
+	let sd = paths::state_dir();
+	let stage = Scratch::new(sd, "restore")?;
+	run_ok(
+		Command::new("tar")
+			.arg("-xzf")
+			.arg(&archive)
+			.arg("-C")
+			.arg(&stage.0)
+			.arg("--no-same-owner"),
+	)
+	.map_err(|e| cli::fail(EXIT_INVALID, format!("解包 {} 失败: {e:#}", archive.display())))?;
+	let m = verify(&stage.0)?;
+
+	let with_signal = has_flag(args, "--with-signal-data");
+	if with_signal && !m.signal_data {
+		println!("[WRN] 归档里没有 signal-cli 数据，只恢复 magicbot 配置");
+	}
+	if !with_signal && m.signal_data {
+		println!("[INF] 归档里有 signal-cli 数据，加 --with-signal-data 才会恢复");
+	}
+	let restore_signal = with_signal && m.signal_data;
+
+	let cur = load_global()?;
+	let has_data = cur.account.is_some() || groups_dir().read_dir().is_ok_and(|mut d| d.next().is_some());
+	let created = chrono::TimeZone::timestamp_opt(&Local, m.created_at, 0)
+		.single()
+		.map(|t| t.format("%Y-%m-%d %H:%M:%S").to_string())
+		.unwrap_or_default();
+	if !json {
+		println!("[INF] 备份来自 {}，{created}，magicbot {}，{} 个文件", m.host, m.app_version, m.files.len());
+	}
+	if has_data && !confirm_or_yes(args, json, "覆盖本机现有的 magicbot 配置？(原数据会保留在 pre-restore-* 下)")? {
+		println!("[INF] 已取消。");
+		return Ok(());
+	}
+
+	let ts = Local::now().format("%Y%m%d-%H%M%S").to_string();
+	let aside = sd.join(format!("pre-restore-{ts}"));
+	lock::with_dir_lock(sd, || {
+		for item in STATE_ITEMS {
+			let dst = sd.join(item);
+			if dst.exists() {
+				fs::create_dir_all(&aside)?;
+				fs::rename(&dst, aside.join(item)).with_context(|| format!("move {}", dst.display()))?;
+			}
+			let src = stage.0.join("state").join(item);
+			if src.exists() {
+				fs::rename(&src, &dst).with_context(|| format!("restore {}", dst.display()))?;
+			}
+		}
+		Ok(())
+	})?;
+
+	let mut signal_target = None;
+	if restore_signal {
+		// 用恢复后的 global.json 里的 signal_cli_config_dir
+		let gc = load_global()?;
+		let dir = signal_dir(gc.signal_cli_config_dir.as_deref());
+		if dir.exists() {
+			let old = PathBuf::from(format!("{}.pre-restore-{ts}", dir.display()));
+			fs::rename(&dir, &old).with_context(|| format!("move {}", dir.display()))?;
+			println!("[INF] 原 signal-cli 数据已移到 {}", old.display());
+		}
+		if let Some(parent) = dir.parent() {
+			fs::create_dir_all(parent)?;
+		}
+		run_ok(Command::new("cp").arg("-a").arg(stage.0.join("signal-cli")).arg(&dir))?;
+		signal_target = Some(dir);
+	}
+
+	// 触发一次加载，旧 schema 的备份在这里就迁移好
+	load_global()?;
+	report(
+		json,
+		json!({
+			"ok": true,
+			"archive": archive,
+			"files": m.files.len(),
+			"signal_data": signal_target,
+			"previous": aside.exists().then_some(&aside),
+			"app_version": m.app_version,
+		}),
+		|| {
+			println!("[OK] 已恢复 {} 个文件。", m.files.len());
+			if aside.exists() {
+				println!("[INF] 原有配置保留在 {}", aside.display());
+			}
+			if let Some(d) = &signal_target {
+				println!("[INF] signal-cli 数据已恢复到 {}", d.display());
+			}
+		},
+	);
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	// 临时目录里放一份带 manifest 的解包结果
+	fn stage(tag: &str) -> Scratch {
+		let s = Scratch::new(&env::temp_dir(), &format!("test-{tag}")).unwrap();
+		fs::create_dir_all(s.0.join("state/groups")).unwrap();
+		fs::write(s.0.join("state/global.json"), r#"{"schema_version":1}"#).unwrap();
+		fs::write(s.0.join("state/groups/g1.json"), r#"{"group_id":"g1"}"#).unwrap();
+		write_manifest(&s, |_| {});
+		s
+	}
+
+	fn write_manifest(s: &Scratch, edit: impl FnOnce(&mut Manifest)) {
+		let mut m = Manifest {
+			format: FORMAT.to_string(),
+			format_version: FORMAT_VERSION,
+			app_version: "test".to_string(),
+			created_at: 0,
+			host: "test".to_string(),
+			global_schema: schema::GLOBAL.current(),
+			group_schema: schema::GROUP.current(),
+			signal_data: false,
+			files: entries(&s.0).unwrap(),
+		};
+		edit(&mut m);
+		fs::write(s.0.join(MANIFEST), serde_json::to_vec(&m).unwrap()).unwrap();
+	}
+
+	fn rejected(s: &Scratch) -> bool {
+		verify(&s.0).is_err_and(|e| cli::exit_code(&e) == EXIT_INVALID)
+	}
+
+	#[test]
+	fn safe_rel_only_allows_plain_relative_paths() {
+		assert!(safe_rel("state/groups/g1.json"));
+		assert!(!safe_rel("/etc/passwd"));
+		assert!(!safe_rel("../outside"));
+		assert!(!safe_rel("state/../../outside"));
+		assert!(!safe_rel("./state"));
+	}
+
+	#[test]
+	fn intact_stage_verifies() {
+		let s = stage("intact");
+		assert_eq!(verify(&s.0).unwrap().files.len(), 2);
+	}
+
+	#[test]
+	fn tampered_file_is_rejected() {
+		let s = stage("tampered");
+		// 同样长度，只有 sha256 对不上
+		fs::write(s.0.join("state/groups/g1.json"), r#"{"group_id":"g2"}"#).unwrap();
+		assert!(rejected(&s));
+	}
+
+	#[test]
+	fn unlisted_file_is_rejected() {
+		let s = stage("unlisted");
+		fs::write(s.0.join("state/extra.json"), "{}").unwrap();
+		assert!(rejected(&s));
+	}
+
+	#[test]
+	fn unsafe_manifest_path_is_rejected() {
+		let s = stage("unsafe");
+		write_manifest(&s, |m| m.files[0].path = "../state/global.json".to_string());
+		assert!(rejected(&s));
+	}
+
+	#[test]
+	fn newer_format_or_schema_is_rejected() {
+		let s = stage("newer");
+		write_manifest(&s, |m| m.format_version = FORMAT_VERSION + 1);
+		assert!(rejected(&s));
+		write_manifest(&s, |m| m.group_schema = schema::GROUP.current() + 1);
+		assert!(rejected(&s));
+		write_manifest(&s, |m| m.global_schema = schema::GLOBAL.current() + 1);
+		assert!(rejected(&s));
+		write_manifest(&s, |_| {});
+		assert!(verify(&s.0).is_ok());
+	}
+
+	#[test]
+	fn scratch_dir_is_private() {
+		use std::os::unix::fs::PermissionsExt;
+		let s = Scratch::new(&env::temp_dir(), "test-mode").unwrap();
+		assert_eq!(fs::metadata(&s.0).unwrap().permissions().mode() & 0o777, 0o700);
+	}
+}
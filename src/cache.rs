@@ -0,0 +1,197 @@
+use crate::backend::{Backend, CacheScope, GroupFull, GroupUpdate, Identity};
+use crate::{crypto, paths};
+use anyhow::Result;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::Receiver;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// 联系人失效请求太密时（比如每个 UPDATE 都有陌生成员）至少隔这么久才真的重拉
+const MIN_REFETCH: Duration = Duration::from_secs(30);
+
+// global.json 里的 "cache" 段
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CacheConfig {
+	pub groups_ttl_secs: u64,
+	pub contacts_ttl_secs: u64,
+}
+
+impl Default for CacheConfig {
+	fn default() -> Self {
+		CacheConfig {
+			groups_ttl_secs: 60,
+			contacts_ttl_secs: 600,
+		}
+	}
+}
+
+pub fn dump_path() -> PathBuf {
+	paths::run_dir().join("cache.json")
+}
+
+struct Entry<T> {
+	at: Instant,
+	fetched: i64,
+	value: T,
+}
+
+impl<T: Clone> Entry<T> {
+	fn fresh(&self, ttl: u64) -> Option<T> {
+		(self.at.elapsed() < Duration::from_secs(ttl)).then(|| self.value.clone())
+	}
+}
+
+// 守护进程每次拉取/失效后都写一份到 RUN_DIR/cache.json，给 `magicbot cache dump` 看
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct CacheDump {
+	pub written: i64,
+	pub hits: u64,
+	pub misses: u64,
+	pub groups_fetched: Option<i64>,
+	pub groups: Vec<GroupFull>,
+	pub contacts_fetched: Option<i64>,
+	pub contacts: Vec<Identity>,
+}
+
+pub fn load_dump() -> Option<CacheDump> {
+	let s = crypto::read_to_string(&dump_path()).ok()?;
+	serde_json::from_str(&s).ok()
+}
+
+// listGroups / listContacts 的共享缓存：所有群的名字、号码、管理员查询都从这里取。
+// 同一时刻多个工作线程未命中时只拉一次（持锁拉取）
+pub struct Cached<B: Backend> {
+	inner: B,
+	cfg: CacheConfig,
+	groups: Mutex<Option<Entry<Vec<GroupFull>>>>,
+	contacts: Mutex<Option<Entry<Vec<Identity>>>>,
+	hits: AtomicU64,
+	misses: AtomicU64,
+}
+
+impl<B: Backend> Cached<B> {
+	pub fn new(inner: B, cfg: &CacheConfig) -> Self {
+		Cached {
+			inner,
+			cfg: cfg.clone(),
+			groups: Mutex::new(None),
+			contacts: Mutex::new(None),
+			hits: AtomicU64::new(0),
+			misses: AtomicU64::new(0),
+		}
+	}
+
+	fn get<T: Clone>(
+		&self,
+		slot: &Mutex<Option<Entry<T>>>,
+		ttl: u64,
+		fetch: impl FnOnce() -> Result<T>,
+	) -> Result<T> {
+		let mut guard = slot.lock().unwrap();
+		if let Some(v) = guard.as_ref().and_then(|e| e.fresh(ttl)) {
+			self.hits.fetch_add(1, Ordering::Relaxed);
+			return Ok(v);
+		}
+		self.misses.fetch_add(1, Ordering::Relaxed);
+		let value = fetch()?;
+		*guard = Some(Entry {
+			at: Instant::now(),
+			fetched: Utc::now().timestamp(),
+			value: value.clone(),
+		});
+		drop(guard);
+		self.write_dump();
+		Ok(value)
+	}
+
+	fn write_dump(&self) {
+		let (groups_fetched, groups) = match self.groups.lock().unwrap().as_ref() {
+			Some(e) => (Some(e.fetched), e.value.clone()),
+			None => (None, vec![]),
+		};
+		let (contacts_fetched, contacts) = match self.contacts.lock().unwrap().as_ref() {
+			Some(e) => (Some(e.fetched), e.value.clone()),
+			None => (None, vec![]),
+		};
+		let dump = CacheDump {
+			written: Utc::now().timestamp(),
+			hits: self.hits.load(Ordering::Relaxed),
+			misses: self.misses.load(Ordering::Relaxed),
+			groups_fetched,
+			groups,
+			contacts_fetched,
+			contacts,
+		};
+		let _ = fs::create_dir_all(paths::run_dir());
+		// 写临时文件再 rename，`magicbot cache dump` 不会读到写了一半的文件
+		if let Ok(b) = serde_json::to_vec_pretty(&dump) {
+			let p = dump_path();
+			let tmp = p.with_extension("json.tmp");
+			if crypto::write(&tmp, &b).is_ok() {
+				let _ = fs::rename(&tmp, &p);
+			}
+		}
+	}
+}
+
+impl<B: Backend> Backend for Cached<B> {
+	fn receive(&self) -> Result<Receiver<String>> {
+		self.inner.receive()
+	}
+
+	fn send_group_message(&self, gid: &str, msg: &str) -> Result<()> {
+		self.inner.send_group_message(gid, msg)
+	}
+
+	// 踢人/改权限之后群信息肯定变了
+	fn update_group(&self, gid: &str, upd: &GroupUpdate) -> Result<()> {
+		let r = self.inner.update_group(gid, upd);
+		*self.groups.lock().unwrap() = None;
+		r
+	}
+
+	fn list_groups(&self) -> Result<Vec<GroupFull>> {
+		self.get(&self.groups, self.cfg.groups_ttl_secs, || self.inner.list_groups())
+	}
+
+	fn list_contacts(&self) -> Result<Vec<Identity>> {
+		self.get(&self.contacts, self.cfg.contacts_ttl_secs, || self.inner.list_contacts())
+	}
+
+	fn submit_rate_limit_challenge(&self, challenge: &str, captcha: &str) -> Result<()> {
+		self.inner.submit_rate_limit_challenge(challenge, captcha)
+	}
+
+	fn receiver_exit_reason(&self) -> Option<String> {
+		self.inner.receiver_exit_reason()
+	}
+
+	fn outbound_paused(&self) -> bool {
+		self.inner.outbound_paused()
+	}
+
+	fn invalidate(&self, scope: CacheScope) {
+		match scope {
+			CacheScope::Groups => *self.groups.lock().unwrap() = None,
+			CacheScope::Contacts => {
+				let mut guard = self.contacts.lock().unwrap();
+				if guard.as_ref().is_some_and(|e| e.at.elapsed() >= MIN_REFETCH) {
+					*guard = None;
+				}
+			}
+		}
+	}
+
+	fn shutdown(&self) {
+		self.inner.shutdown();
+	}
+
+	fn receiver_healthy(&self) -> bool {
+		self.inner.receiver_healthy()
+	}
+}
@@ -0,0 +1,547 @@
+use crate::{
+	apply_group_selection, canonical_perm, flag_value, flag_values, group_cfg_path, has_flag, list_group_cfg_ids, list_groups,
+	load_global, load_group_cfg, logout, paths, ratelimit, save_group_cfg, systemctl, systemd_op, truncate,
+	GlobalConfig, GroupConfig, KeywordGroupBan, KeywordGroupReply, KeywordGroupWarn, SubprocessBackend,
+};
+use crate::declarative::template_problem;
+use anyhow::Result;
+use serde_json::{json, Value};
+use std::fmt;
+use std::fs::File;
+use std::io::Write;
+use std::mem::ManuallyDrop;
+use std::os::unix::io::FromRawFd;
+use std::sync::atomic::{AtomicI32, Ordering};
+
+// 非交互子命令（给 Ansible / ssh 脚本用）的退出码：0 成功，1 其它失败（signal-cli、systemctl 出错等）
+pub const EXIT_USAGE: i32 = 2;
+pub const EXIT_NOT_FOUND: i32 = 3;
+pub const EXIT_NOT_READY: i32 = 4;
+pub const EXIT_DENIED: i32 = 5;
+pub const EXIT_INACTIVE: i32 = 6;
+pub const EXIT_INVALID: i32 = 7;
+// 状态文件已加密但密钥缺失/不对
+pub const EXIT_KEY: i32 = 8;
+
+// 不跟值的开关，解析位置参数时不吞掉下一个参数
+const BOOL_FLAGS: [&str; 8] = [
+	"--json",
+	"--no-welcome",
+	"--yes",
+	"--delete-signal-data",
+	"--prune",
+	"--dry-run",
+	"--with-signal-data",
+	"--force",
+];
+
+#[derive(Debug)]
+pub struct CliError {
+	pub code: i32,
+	msg: String,
+}
+
+impl fmt::Display for CliError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str(&self.msg)
+	}
+}
+
+impl std::error::Error for CliError {}
+
+pub fn fail(code: i32, msg: impl Into<String>) -> anyhow::Error {
+	anyhow::Error::new(CliError { code, msg: msg.into() })
+}
+
+pub fn usage(msg: &str) -> anyhow::Error {
+	fail(EXIT_USAGE, format!("usage: {msg}"))
+}
+
+pub fn exit_code(e: &anyhow::Error) -> i32 {
+	e.chain().find_map(|c| c.downcast_ref::<CliError>()).map(|c| c.code).unwrap_or(1)
+}
+
+// --json 时过程日志（[INF]/[OK]，包括 signal-cli、systemctl 的输出）改走 stderr，stdout 只留最后一份 JSON
+static JSON_FD: AtomicI32 = AtomicI32::new(-1);
+
+pub fn json_mode(args: &[String]) -> bool {
+	if !has_flag(args, "--json") {
+		return false;
+	}
+	let _ = std::io::stdout().flush();
+	unsafe {
+		let fd = libc::dup(1);
+		if fd >= 0 && libc::dup2(2, 1) >= 0 {
+			JSON_FD.store(fd, Ordering::SeqCst);
+		}
+	}
+	true
+}
+
+pub fn print_json(v: &Value) {
+	let s = format!("{}\n", serde_json::to_string_pretty(v).unwrap_or_default());
+	let fd = JSON_FD.load(Ordering::SeqCst);
+	if fd < 0 {
+		print!("{s}");
+		return;
+	}
+	let _ = std::io::stdout().flush();
+	let mut f = ManuallyDrop::new(unsafe { File::from_raw_fd(fd) });
+	let _ = f.write_all(s.as_bytes());
+}
+
+pub fn report(json: bool, v: Value, text: impl FnOnce()) {
+	if json {
+		print_json(&v);
+	} else {
+		text();
+	}
+}
+
+// args[from..] 里去掉 --flag 及其值之后剩下的（magicbot <cmd> <sub> ... 时 from 为 3）
+pub fn positionals(args: &[String], from: usize) -> Vec<&str> {
+	let mut out = vec![];
+	let mut it = args.iter().skip(from);
+	while let Some(a) = it.next() {
+		if a.starts_with("--") {
+			if !BOOL_FLAGS.contains(&a.as_str()) {
+				it.next();
+			}
+			continue;
+		}
+		out.push(a.as_str());
+	}
+	out
+}
+
+fn account(gc: &GlobalConfig) -> Result<String> {
+	gc.account
+		.clone()
+		.ok_or_else(|| fail(EXIT_NOT_READY, "未登录：先运行 magicbot 登录/绑定设备"))
+}
+
+// 只改已经初始化过的群，避免手滑打错 id 凭空建出一份配置
+fn configured_group(gid: &str) -> Result<GroupConfig> {
+	if !group_cfg_path(gid).exists() {
+		return Err(fail(
+			EXIT_NOT_FOUND,
+			format!("群 {gid} 还没有配置，先执行 magicbot group select {gid}"),
+		));
+	}
+	load_group_cfg(gid)
+}
+
+fn parse_switch(flag: &str, v: &str) -> Result<bool> {
+	match v.to_ascii_lowercase().as_str() {
+		"on" | "true" | "yes" | "1" => Ok(true),
+		"off" | "false" | "no" | "0" => Ok(false),
+		_ => Err(usage(&format!("{flag} on|off"))),
+	}
+}
+
+fn parse_num<T: std::str::FromStr>(flag: &str, v: &str) -> Result<T> {
+	v.parse().map_err(|_| usage(&format!("{flag} <number>")))
+}
+
+fn parse_perm(flag: &str, v: &str) -> Result<String> {
+	canonical_perm(v).ok_or_else(|| usage(&format!("{flag} EVERY_MEMBER|ONLY_ADMINS")))
+}
+
+fn group_json(cfg: &GroupConfig) -> Value {
+	json!({
+		"id": cfg.group_id,
+		"name": cfg.group_name,
+		"enabled": cfg.enabled,
+	})
+}
+
+// magicbot group list [--json]
+// magicbot group select <gid>
+// magicbot group show <gid> [--json]
+// magicbot group enable|disable <gid>
+// magicbot group set <gid> [--welcome <模板> | --no-welcome] [--admin-only-ban on|off] [--require-bot-admin on|off]
+//     [--warn-window <分钟>] [--warn-max <次数>] [--warn-message <文案>]
+//     [--perm-add-member|--perm-send-message|--perm-edit-details EVERY_MEMBER|ONLY_ADMINS]
+//     [--per-minute <n>] [--burst <n>] [--coalesce <秒>]
+pub fn group_cli(args: &[String]) -> Result<()> {
+	const USAGE: &str = "magicbot group list | select <gid> | show <gid> | enable <gid> | disable <gid> | set <gid> [options]";
+	let json = json_mode(args);
+	let mut gc = load_global()?;
+	let pos = positionals(args, 3);
+	let sub = args.get(2).map(|s| s.as_str());
+	if sub == Some("list") {
+		let acc = account(&gc)?;
+		let groups = list_groups(&SubprocessBackend::new(&acc, gc.signal_cli_config_dir.as_deref()))?;
+		let configured = list_group_cfg_ids();
+		let rows: Vec<Value> = groups
+			.iter()
+			.map(|g| {
+				let cfg = configured.contains(&g.id).then(|| load_group_cfg(&g.id).ok()).flatten();
+				json!({
+					"id": g.id,
+					"name": g.name,
+					"configured": cfg.is_some(),
+					"enabled": cfg.is_some_and(|c| c.enabled),
+					"selected": gc.selected_group.as_deref() == Some(g.id.as_str()),
+				})
+			})
+			.collect();
+		report(json, Value::Array(rows.clone()), || {
+			if rows.is_empty() {
+				println!("该账号没有加入任何群");
+			}
+			for r in &rows {
+				let state = match (r["configured"].as_bool(), r["enabled"].as_bool()) {
+					(Some(true), Some(true)) => "启用",
+					(Some(true), _) => "关闭",
+					_ => "未配置",
+				};
+				let mark = if r["selected"].as_bool() == Some(true) { "*" } else { " " };
+				println!(
+					"{mark} {:<24} {:<6} {}",
+					truncate(r["name"].as_str().unwrap_or_default(), 24),
+					state,
+					r["id"].as_str().unwrap_or_default()
+				);
+			}
+		});
+		return Ok(());
+	}
+
+	let gid = *pos.first().ok_or_else(|| usage(USAGE))?;
+	match sub {
+		Some("select") => {
+			let acc = account(&gc)?;
+			let groups = list_groups(&SubprocessBackend::new(&acc, gc.signal_cli_config_dir.as_deref()))?;
+			let g = groups
+				.iter()
+				.find(|g| g.id == gid)
+				.ok_or_else(|| fail(EXIT_NOT_FOUND, format!("账号 {acc} 不在群 {gid} 里")))?;
+			let cfg = apply_group_selection(&mut gc, g)?;
+			report(json, json!({ "ok": true, "group": group_json(&cfg) }), || {
+				println!("[OK] 已选择群: {} ({})", cfg.group_name, cfg.group_id)
+			});
+		}
+		Some("show") => {
+			let cfg = configured_group(gid)?;
+			report(json, serde_json::to_value(&cfg)?, || {
+				println!("群组       : {} ({})", cfg.group_name, cfg.group_id);
+				println!("开关       : {}", if cfg.enabled { "启用" } else { "关闭" });
+				println!("需Bot管理员: {}", cfg.require_bot_admin_to_enforce);
+				println!("/ban仅管理员: {}", cfg.only_admin_can_ban);
+				println!("欢迎语     : {}", cfg.welcome_template.as_deref().unwrap_or("-"));
+				println!(
+					"警告策略   : {} 分钟内 {} 次，文案：{}",
+					cfg.warn_window_minutes, cfg.warn_max_count, cfg.warn_message
+				);
+				println!(
+					"接管权限   : addMember={} sendMessage={} editDetails={}",
+					cfg.desired_permission_add_member,
+					cfg.desired_permission_send_message,
+					cfg.desired_permission_edit_details
+				);
+				println!(
+					"外发限速   : 每分钟 {} 条, 突发 {}, 合并窗口 {} 秒",
+					cfg.throttle.per_minute, cfg.throttle.burst, cfg.throttle.coalesce_secs
+				);
+				println!(
+					"规则       : 自动回复 {} / 警告词 {} / 违规词 {}",
+					cfg.auto_replies.len(),
+					cfg.warn_rules.len(),
+					cfg.ban_rules.len()
+				);
+			});
+		}
+		Some(op @ ("enable" | "disable")) => {
+			let mut cfg = configured_group(gid)?;
+			cfg.enabled = op == "enable";
+			save_group_cfg(&cfg)?;
+			report(json, json!({ "ok": true, "group": group_json(&cfg) }), || {
+				println!("[OK] 群 {} 已{}", cfg.group_id, if cfg.enabled { "启用" } else { "关闭" })
+			});
+		}
+		Some("set") => {
+			let mut cfg = configured_group(gid)?;
+			let mut changed = 0;
+			let mut opt = |flag: &str| {
+				let v = flag_value(args, flag);
+				changed += v.is_some() as usize;
+				v
+			};
+			if let Some(v) = opt("--welcome") {
+				if let Some(p) = template_problem(&v) {
+					return Err(fail(EXIT_USAGE, format!("--welcome: {p}")));
+				}
+				cfg.welcome_template = Some(v);
+			}
+			if let Some(v) = opt("--admin-only-ban") {
+				cfg.only_admin_can_ban = parse_switch("--admin-only-ban", &v)?;
+			}
+			if let Some(v) = opt("--require-bot-admin") {
+				cfg.require_bot_admin_to_enforce = parse_switch("--require-bot-admin", &v)?;
+			}
+			if let Some(v) = opt("--warn-window") {
+				cfg.warn_window_minutes = parse_num("--warn-window", &v)?;
+			}
+			if let Some(v) = opt("--warn-max") {
+				cfg.warn_max_count = parse_num("--warn-max", &v)?;
+			}
+			if let Some(v) = opt("--warn-message") {
+				cfg.warn_message = v;
+			}
+			if let Some(v) = opt("--perm-add-member") {
+				cfg.desired_permission_add_member = parse_perm("--perm-add-member", &v)?;
+			}
+			if let Some(v) = opt("--perm-send-message") {
+				cfg.desired_permission_send_message = parse_perm("--perm-send-message", &v)?;
+			}
+			if let Some(v) = opt("--perm-edit-details") {
+				cfg.desired_permission_edit_details = parse_perm("--perm-edit-details", &v)?;
+			}
+			if let Some(v) = opt("--per-minute") {
+				cfg.throttle.per_minute = parse_num("--per-minute", &v)?;
+			}
+			if let Some(v) = opt("--burst") {
+				cfg.throttle.burst = parse_num("--burst", &v)?;
+			}
+			if let Some(v) = opt("--coalesce") {
+				cfg.throttle.coalesce_secs = parse_num("--coalesce", &v)?;
+			}
+			if has_flag(args, "--no-welcome") {
+				cfg.welcome_template = None;
+				changed += 1;
+			}
+			if changed == 0 {
+				return Err(usage("magicbot group set <gid> --welcome <模板> | --warn-max <n> | ... (至少一项)"));
+			}
+			save_group_cfg(&cfg)?;
+			report(json, json!({ "ok": true, "config": serde_json::to_value(&cfg)? }), || {
+				println!("[OK] 已更新群 {} 的 {changed} 项设置", cfg.group_id)
+			});
+		}
+		_ => return Err(usage(USAGE)),
+	}
+	Ok(())
+}
+
+fn rules_json(cfg: &GroupConfig) -> Value {
+	json!({
+		"reply": cfg.auto_replies,
+		"warn": cfg.warn_rules,
+		"ban": cfg.ban_rules,
+	})
+}
+
+fn rule_tag(i: usize) -> char {
+	(b'A' + (i as u8)) as char
+}
+
+// 序号和菜单里显示的一样是 A/B/C，也可以写 1/2/3
+fn rule_index(s: &str, len: usize) -> Result<usize> {
+	let i = match s.parse::<usize>() {
+		Ok(n) => n.checked_sub(1),
+		Err(_) if s.len() == 1 => s.to_ascii_uppercase().bytes().next().and_then(|b| b.checked_sub(b'A')).map(usize::from),
+		Err(_) => None,
+	};
+	i.filter(|&i| i < len)
+		.ok_or_else(|| fail(EXIT_NOT_FOUND, format!("没有第 {s} 条（共 {len} 条）")))
+}
+
+// magicbot rules list <gid> [--json]
+// magicbot rules add-reply <gid> --kw <关键词>... --reply <回复内容>
+// magicbot rules add-warn|add-ban <gid> --kw <关键词>...
+// magicbot rules remove <gid> reply|warn|ban <序号>
+// magicbot rules clear <gid> reply|warn|ban
+pub fn rules_cli(args: &[String]) -> Result<()> {
+	const USAGE: &str = "magicbot rules list <gid> | add-reply <gid> --kw <词>... --reply <text> | add-warn|add-ban <gid> --kw <词>... | remove <gid> reply|warn|ban <序号> | clear <gid> reply|warn|ban";
+	let json = json_mode(args);
+	let pos = positionals(args, 3);
+	let sub = args.get(2).map(|s| s.as_str());
+	let gid = *pos.first().ok_or_else(|| usage(USAGE))?;
+	let mut cfg = configured_group(gid)?;
+	let keywords = || -> Result<Vec<String>> {
+		let kws: Vec<String> = flag_values(args, "--kw")
+			.into_iter()
+			.map(|s| s.trim().to_string())
+			.filter(|s| !s.is_empty())
+			.collect();
+		if kws.is_empty() {
+			return Err(usage("--kw <关键词> required (可重复)"));
+		}
+		Ok(kws)
+	};
+	let kind = || -> Result<&str> {
+		pos.get(1)
+			.copied()
+			.filter(|k| ["reply", "warn", "ban"].contains(k))
+			.ok_or_else(|| usage(USAGE))
+	};
+
+	let msg = match sub {
+		Some("list") => {
+			report(json, rules_json(&cfg), || {
+				println!("自动回复:");
+				for (i, r) in cfg.auto_replies.iter().enumerate() {
+					println!("  {}. [{}] => {}", rule_tag(i), r.keywords.join(", "), truncate(&r.reply, 50));
+				}
+				println!("警告词:");
+				for (i, r) in cfg.warn_rules.iter().enumerate() {
+					println!("  {}. {}", rule_tag(i), r.keywords.join(", "));
+				}
+				println!("违规词:");
+				for (i, r) in cfg.ban_rules.iter().enumerate() {
+					println!("  {}. {}", rule_tag(i), r.keywords.join(", "));
+				}
+			});
+			return Ok(());
+		}
+		Some("add-reply") => {
+			let reply = flag_value(args, "--reply")
+				.filter(|r| !r.trim().is_empty())
+				.ok_or_else(|| usage("--reply <回复内容> required"))?;
+			cfg.auto_replies.push(KeywordGroupReply {
+				keywords: keywords()?,
+				reply,
+			});
+			"已增加自动回复".to_string()
+		}
+		Some("add-warn") => {
+			cfg.warn_rules.push(KeywordGroupWarn { keywords: keywords()? });
+			"已增加警告词".to_string()
+		}
+		Some("add-ban") => {
+			cfg.ban_rules.push(KeywordGroupBan { keywords: keywords()? });
+			"已增加违规词".to_string()
+		}
+		Some("remove") => {
+			let kind = kind()?;
+			let idx = pos.get(2).ok_or_else(|| usage(USAGE))?;
+			match kind {
+				"reply" => {
+					cfg.auto_replies.remove(rule_index(idx, cfg.auto_replies.len())?);
+				}
+				"warn" => {
+					cfg.warn_rules.remove(rule_index(idx, cfg.warn_rules.len())?);
+				}
+				_ => {
+					cfg.ban_rules.remove(rule_index(idx, cfg.ban_rules.len())?);
+				}
+			}
+			format!("已删除 {kind} 第 {idx} 条")
+		}
+		Some("clear") => {
+			let kind = kind()?;
+			match kind {
+				"reply" => cfg.auto_replies.clear(),
+				"warn" => cfg.warn_rules.clear(),
+				_ => cfg.ban_rules.clear(),
+			}
+			format!("已清空 {kind}")
+		}
+		_ => return Err(usage(USAGE)),
+	};
+	save_group_cfg(&cfg)?;
+	report(json, json!({ "ok": true, "group": cfg.group_id, "rules": rules_json(&cfg) }), || {
+		println!("[OK] {msg}")
+	});
+	Ok(())
+}
+
+// magicbot systemd install|enable|disable|start|stop|uninstall [--json]
+// magicbot systemd status [--json]   服务没在运行时退出码 6
+pub fn systemd_cli(args: &[String]) -> Result<()> {
+	const USAGE: &str = "magicbot systemd install|enable|disable|start|stop|status|uninstall";
+	let json = json_mode(args);
+	let unit = paths::unit_name();
+	match args.get(2).map(|s| s.as_str()) {
+		Some("status") => {
+			let out = systemctl(&["show", &unit, "-p", "LoadState,ActiveState,SubState,UnitFileState,MainPID"])
+				.output()
+				.map_err(|e| anyhow::anyhow!("systemctl show: {e}"))?;
+			let mut props = serde_json::Map::new();
+			for line in String::from_utf8_lossy(&out.stdout).lines() {
+				if let Some((k, v)) = line.split_once('=') {
+					props.insert(k.to_string(), Value::String(v.to_string()));
+				}
+			}
+			let active = props.get("ActiveState").and_then(|v| v.as_str()) == Some("active");
+			props.insert("unit".to_string(), Value::String(unit.clone()));
+			props.insert("active".to_string(), Value::Bool(active));
+			report(json, Value::Object(props), || {
+				let _ = systemctl(&["status", &unit, "-l", "--no-pager"]).status();
+			});
+			if !active {
+				// 已经输出过了，只要退出码
+				return Err(fail(EXIT_INACTIVE, ""));
+			}
+		}
+		Some(op @ ("install" | "enable" | "disable" | "start" | "stop" | "uninstall")) => {
+			let mut gc = load_global()?;
+			systemd_op(&mut gc, op)?;
+			report(json, json!({ "ok": true, "unit": unit, "op": op }), || {
+				println!("[OK] systemd {op}: {unit}")
+			});
+		}
+		_ => return Err(usage(USAGE)),
+	}
+	Ok(())
+}
+
+// magicbot captcha submit --captcha <signalcaptcha://...> [--challenge <token>] [--json]
+// magicbot captcha status [--json]
+pub fn captcha_cli(args: &[String]) -> Result<()> {
+	let json = json_mode(args);
+	let gc = load_global()?;
+	match args.get(2).map(|s| s.as_str()) {
+		Some("submit") => {
+			let acc = account(&gc)?;
+			let cap = flag_value(args, "--captcha").ok_or_else(|| usage("--captcha <signalcaptcha://...> required"))?;
+			let chal = flag_value(args, "--challenge");
+			ratelimit::submit(&acc, gc.signal_cli_config_dir.as_deref(), chal, &cap)?;
+			report(json, json!({ "ok": true }), || println!("[OK] 已提交，外发已恢复。"));
+			Ok(())
+		}
+		Some("status") => {
+			let st = ratelimit::load_pause();
+			let v = match &st {
+				Some(st) => json!({ "paused": true, "state": st }),
+				None => json!({ "paused": false }),
+			};
+			report(json, v, || match st {
+				Some(st) => {
+					println!("paused since: {}", st.since);
+					println!("challenge  : {}", st.challenge.as_deref().unwrap_or("-"));
+					println!("resume at  : {}", st.resume_at.map(|t| t.to_string()).unwrap_or_else(|| "-".to_string()));
+					println!("detail     : {}", st.detail);
+				}
+				None => println!("not paused"),
+			});
+			Ok(())
+		}
+		_ => Err(usage(
+			"magicbot captcha submit --captcha <signalcaptcha://...> [--challenge <token>] | magicbot captcha status",
+		)),
+	}
+}
+
+// magicbot logout --yes [--delete-signal-data]
+// 没登录时什么都不做，照样返回 0（重复执行无害）
+pub fn logout_cli(args: &[String]) -> Result<()> {
+	let json = json_mode(args);
+	if !has_flag(args, "--yes") {
+		return Err(usage("magicbot logout --yes [--delete-signal-data]  (会清理本机 magicbot 配置)"));
+	}
+	let mut gc = load_global()?;
+	let Some(acc) = gc.account.clone() else {
+		report(json, json!({ "ok": true, "changed": false }), || println!("[WRN] 未登录。"));
+		return Ok(());
+	};
+	let delete_signal = has_flag(args, "--delete-signal-data");
+	logout(&mut gc, &acc, delete_signal)?;
+	report(
+		json,
+		json!({ "ok": true, "changed": true, "account": acc, "signal_data_deleted": delete_signal }),
+		|| println!("[OK] 已退出并清理。"),
+	);
+	Ok(())
+}
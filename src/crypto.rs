@@ -0,0 +1,578 @@
+use crate::cli::{self, json_mode, report, usage, EXIT_KEY, EXIT_NOT_READY};
+use crate::{alerts, cache, flag_value, lock, paths, recorder};
+use anyhow::{anyhow, Context, Result};
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Nonce};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::env;
+use std::fs;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+// 状态文件的静态加密（可选）：配置了密钥就用 ChaCha20-Poly1305 写，读的时候按文件头自动识别，
+// 没加密的旧文件照常能读。覆盖 global.json(*.bak)、ratelimit.json、groups/、state/、marks/、outbox/
+// 和运行目录的缓存快照；marks 的文件名本来就是成员 id，加密时改成带密钥的散列。
+// 不覆盖（见 unprotected()，crypt status 也会列出）：signal-cli 自己的数据目录（signal-cli 要直接读）、
+// 录制的原始消息（LOG_DIR/raw，追加写、要能直接喂给 --replay）和告警日志 alerts.jsonl，
+// 这些靠磁盘/文件系统加密；在意的话别开 recorder。
+//
+// 密钥是 32 字节、写成 64 个十六进制字符（openssl rand -hex 32 或 magicbot crypt keygen），依次取：
+//   $CREDENTIALS_DIRECTORY/magicbot.key   systemd LoadCredential=magicbot.key:<文件>
+//   MAGICBOT_KEY_FILE=<文件>
+//   MAGICBOT_KEY=<hex>
+//   /etc/magicbot/magicbot.key（系统模式，存在时）
+pub const CREDENTIAL: &str = "magicbot.key";
+// 系统模式的默认密钥文件：root 所有、0600；装 unit 时存在就自动加上 LoadCredential
+pub const DEFAULT_KEY_FILE: &str = "/etc/magicbot/magicbot.key";
+
+// 文件头：MAGIC | 密钥 id(8) | nonce(12) | 密文+tag；MAGIC 和密钥 id 作为附加数据参与认证
+const MAGIC: &[u8; 4] = b"MBE1";
+const ID_LEN: usize = 8;
+const NONCE_LEN: usize = 12;
+const HEADER_LEN: usize = MAGIC.len() + ID_LEN + NONCE_LEN;
+
+pub struct Key {
+	bytes: [u8; 32],
+	id: [u8; ID_LEN],
+	source: String,
+}
+
+impl Key {
+	fn new(bytes: [u8; 32], source: String) -> Self {
+		let digest = Sha256::new().chain_update(b"magicbot-key-id\0").chain_update(bytes).finalize();
+		let mut id = [0u8; ID_LEN];
+		id.copy_from_slice(&digest[..ID_LEN]);
+		Key { bytes, id, source }
+	}
+
+	pub fn id_hex(&self) -> String {
+		hex(&self.id)
+	}
+
+	fn cipher(&self) -> ChaCha20Poly1305 {
+		ChaCha20Poly1305::new((&self.bytes).into())
+	}
+}
+
+fn hex(b: &[u8]) -> String {
+	b.iter().map(|x| format!("{x:02x}")).collect()
+}
+
+fn parse_key(text: &str, source: &str) -> Result<Key> {
+	let t = text.trim();
+	let bad = || {
+		cli::fail(
+			EXIT_KEY,
+			format!("{source}: 密钥应为 64 个十六进制字符（openssl rand -hex 32 或 magicbot crypt keygen）"),
+		)
+	};
+	if t.len() != 64 || !t.is_ascii() {
+		return Err(bad());
+	}
+	let mut bytes = [0u8; 32];
+	for (i, b) in bytes.iter_mut().enumerate() {
+		*b = u8::from_str_radix(&t[i * 2..i * 2 + 2], 16).map_err(|_| bad())?;
+	}
+	Ok(Key::new(bytes, source.to_string()))
+}
+
+pub fn load_key_file(p: &Path) -> Result<Key> {
+	let text = fs::read_to_string(p)
+		.map_err(|e| cli::fail(EXIT_KEY, format!("读取密钥文件 {} 失败: {e}", p.display())))?;
+	if fs::metadata(p).is_ok_and(|m| m.permissions().mode() & 0o077 != 0) {
+		eprintln!("[WRN] 密钥文件 {} 对其他用户可读，建议 chmod 600", p.display());
+	}
+	parse_key(&text, &p.display().to_string())
+}
+
+fn load_key() -> Result<Option<Key>> {
+	if let Some(dir) = env::var_os("CREDENTIALS_DIRECTORY") {
+		let p = PathBuf::from(dir).join(CREDENTIAL);
+		if p.exists() {
+			let mut k = load_key_file(&p)?;
+			k.source = format!("systemd credential {CREDENTIAL}");
+			return Ok(Some(k));
+		}
+	}
+	if let Some(p) = env::var_os("MAGICBOT_KEY_FILE").filter(|p| !p.is_empty()) {
+		return load_key_file(Path::new(&p)).map(Some);
+	}
+	if let Ok(k) = env::var("MAGICBOT_KEY") {
+		if !k.is_empty() {
+			return parse_key(&k, "MAGICBOT_KEY").map(Some);
+		}
+	}
+	if !paths::user_mode() && Path::new(DEFAULT_KEY_FILE).exists() {
+		return load_key_file(Path::new(DEFAULT_KEY_FILE)).map(Some);
+	}
+	Ok(None)
+}
+
+// 降权前调用：密钥文件通常只有 root 能读
+pub fn preload() {
+	let _ = current();
+}
+
+// 写进 unit 的 LoadCredential 行（没有密钥文件就是空）
+pub fn unit_credential() -> String {
+	let file = match env::var_os("MAGICBOT_KEY_FILE").filter(|p| !p.is_empty()) {
+		Some(p) => std::path::absolute(PathBuf::from(p)).ok(),
+		None => (!paths::user_mode() && Path::new(DEFAULT_KEY_FILE).exists()).then(|| PathBuf::from(DEFAULT_KEY_FILE)),
+	};
+	match file {
+		Some(f) => format!("LoadCredential={CREDENTIAL}:{}\n", f.display()),
+		None => {
+			if env::var("MAGICBOT_KEY").is_ok_and(|k| !k.is_empty()) {
+				println!("[WRN] MAGICBOT_KEY 不会写进 unit：把密钥存成文件并设置 MAGICBOT_KEY_FILE 后重装 unit");
+			}
+			String::new()
+		}
+	}
+}
+
+// 守护进程启动时报一下加密状态
+pub fn log_status(recording: bool) -> Result<()> {
+	let Some(key) = current()? else {
+		return Ok(());
+	};
+	println!("[INF] state encryption on (key id {}, {})", key.id_hex(), key.source);
+	let plain = protected_files()
+		.iter()
+		.filter(|p| fs::read(p).is_ok_and(|raw| key_id_of(&raw).is_none()))
+		.count();
+	if plain > 0 {
+		println!("[WRN] {plain} state file(s) still plaintext, run: magicbot crypt encrypt");
+	}
+	if recording {
+		println!(
+			"[WRN] recorder is on: raw envelopes in {} are NOT encrypted",
+			recorder::raw_dir().display()
+		);
+	}
+	Ok(())
+}
+
+// 加密覆盖不到的数据：(说明, 路径)
+fn unprotected() -> Vec<(&'static str, PathBuf)> {
+	vec![
+		("recorder raw envelopes", recorder::raw_dir()),
+		("alert log", alerts::alerts_path()),
+	]
+}
+
+static KEY: OnceLock<std::result::Result<Option<Key>, String>> = OnceLock::new();
+
+// 密钥来源配置错了（文件读不到、格式不对）每次用到都报错，不会悄悄退回明文
+pub fn current() -> Result<Option<&'static Key>> {
+	match KEY.get_or_init(|| load_key().map_err(|e| format!("{e:#}"))) {
+		Ok(k) => Ok(k.as_ref()),
+		Err(e) => Err(cli::fail(EXIT_KEY, e.clone())),
+	}
+}
+
+fn random(buf: &mut [u8]) -> Result<()> {
+	let mut filled = 0;
+	while filled < buf.len() {
+		let n = unsafe { libc::getrandom(buf[filled..].as_mut_ptr().cast(), buf.len() - filled, 0) };
+		if n < 0 {
+			let e = std::io::Error::last_os_error();
+			if e.kind() == std::io::ErrorKind::Interrupted {
+				continue;
+			}
+			return Err(anyhow!("getrandom: {e}"));
+		}
+		filled += n as usize;
+	}
+	Ok(())
+}
+
+fn seal_with(key: Option<&Key>, plain: &[u8]) -> Result<Vec<u8>> {
+	let Some(key) = key else {
+		return Ok(plain.to_vec());
+	};
+	let mut nonce = [0u8; NONCE_LEN];
+	random(&mut nonce)?;
+	let mut aad = MAGIC.to_vec();
+	aad.extend_from_slice(&key.id);
+	let ct = key
+		.cipher()
+		.encrypt(Nonce::from_slice(&nonce), Payload { msg: plain, aad: &aad })
+		.map_err(|_| anyhow!("encrypt failed"))?;
+	let mut out = aad;
+	out.extend_from_slice(&nonce);
+	out.extend_from_slice(&ct);
+	Ok(out)
+}
+
+fn key_id_of(raw: &[u8]) -> Option<&[u8]> {
+	raw.starts_with(MAGIC).then(|| raw.get(MAGIC.len()..MAGIC.len() + ID_LEN)).flatten()
+}
+
+// keys 里找文件头对应的那把；明文原样返回
+fn open_with(keys: &[&Key], raw: &[u8], p: &Path) -> Result<Vec<u8>> {
+	if !raw.starts_with(MAGIC) {
+		return Ok(raw.to_vec());
+	}
+	if raw.len() < HEADER_LEN {
+		return Err(cli::fail(EXIT_KEY, format!("{}: 加密文件被截断", p.display())));
+	}
+	let id = &raw[MAGIC.len()..MAGIC.len() + ID_LEN];
+	let Some(key) = keys.iter().find(|k| k.id == id) else {
+		let msg = match keys.first() {
+			None => format!(
+				"{} 已加密，但没有配置密钥：设置 MAGICBOT_KEY_FILE / MAGICBOT_KEY，或在 unit 里 LoadCredential={CREDENTIAL}:<密钥文件>",
+				p.display()
+			),
+			Some(k) => format!(
+				"{} 是用另一把密钥 (id {}) 加密的，当前密钥 id {}（{}）",
+				p.display(),
+				hex(id),
+				k.id_hex(),
+				k.source
+			),
+		};
+		return Err(cli::fail(EXIT_KEY, msg));
+	};
+	let (aad, rest) = raw.split_at(MAGIC.len() + ID_LEN);
+	let (nonce, ct) = rest.split_at(NONCE_LEN);
+	key.cipher()
+		.decrypt(Nonce::from_slice(nonce), Payload { msg: ct, aad })
+		.map_err(|_| cli::fail(EXIT_KEY, format!("{}: 解密失败（文件损坏或被改动过）", p.display())))
+}
+
+pub fn read(p: &Path) -> Result<Vec<u8>> {
+	let raw = fs::read(p).with_context(|| format!("read {}", p.display()))?;
+	let keys: Vec<&Key> = current()?.into_iter().collect();
+	open_with(&keys, &raw, p)
+}
+
+pub fn read_to_string(p: &Path) -> Result<String> {
+	String::from_utf8(read(p)?).with_context(|| format!("read {}", p.display()))
+}
+
+pub fn write(p: &Path, data: &[u8]) -> Result<()> {
+	let out = seal_with(current()?, data)?;
+	fs::write(p, out).with_context(|| format!("write {}", p.display()))
+}
+
+fn blind_with(key: Option<&Key>, user: &str) -> String {
+	match key {
+		Some(k) => {
+			let d = Sha256::new()
+				.chain_update(b"magicbot-mark\0")
+				.chain_update(k.bytes)
+				.chain_update(user.as_bytes())
+				.finalize();
+			hex(&d[..16])
+		}
+		None => user.to_string(),
+	}
+}
+
+// marks/<gid>/ 下的文件名：不加密时就是成员 id
+pub fn mark_name(user: &str) -> Result<String> {
+	Ok(blind_with(current()?, user))
+}
+
+fn collect(dir: &Path, out: &mut Vec<PathBuf>) {
+	let Ok(rd) = fs::read_dir(dir) else {
+		return;
+	};
+	for e in rd.flatten() {
+		let p = e.path();
+		match e.file_type() {
+			Ok(t) if t.is_dir() => collect(&p, out),
+			Ok(t) if t.is_file() && p.extension().is_none_or(|x| x != "tmp") => out.push(p),
+			_ => {}
+		}
+	}
+}
+
+fn protected_files() -> Vec<PathBuf> {
+	let sd = paths::state_dir();
+	let mut out = vec![];
+	if let Ok(rd) = fs::read_dir(sd) {
+		for e in rd.flatten() {
+			let name = e.file_name().to_string_lossy().into_owned();
+			let state_file = name.starts_with("global.json") || name == "ratelimit.json";
+			if state_file && !name.ends_with(".tmp") && e.path().is_file() {
+				out.push(e.path());
+			}
+		}
+	}
+	for d in ["groups", "state", "outbox", "marks"] {
+		collect(&sd.join(d), &mut out);
+	}
+	let dump = cache::dump_path();
+	if dump.is_file() {
+		out.push(dump);
+	}
+	out.sort();
+	out
+}
+
+fn is_mark(p: &Path) -> bool {
+	p.parent()
+		.and_then(|d| d.parent())
+		.is_some_and(|d| d == paths::state_dir().join("marks"))
+}
+
+// 用 to（None = 明文）重写一个文件；marks 的文件名跟着密钥变，成员 id 记在内容里。返回是否有改动
+fn rewrite(p: &Path, keys: &[&Key], to: Option<&Key>) -> Result<bool> {
+	let raw = fs::read(p).with_context(|| format!("read {}", p.display()))?;
+	let mut plain = open_with(keys, &raw, p)?;
+	let mut dest = p.to_path_buf();
+	if is_mark(p) {
+		let mut v: Value = serde_json::from_slice(&plain).with_context(|| format!("parse {}", p.display()))?;
+		let user = match v.get("user").and_then(|u| u.as_str()).filter(|u| !u.is_empty()) {
+			Some(u) => u.to_string(),
+			// 老的标记没存 id，文件名就是
+			None => p.file_stem().map(|s| s.to_string_lossy().into_owned()).unwrap_or_default(),
+		};
+		v["user"] = Value::String(user.clone());
+		plain = serde_json::to_vec_pretty(&v)?;
+		dest = p.with_file_name(format!("{}.json", blind_with(to, &user)));
+	}
+	if dest == p && key_id_of(&raw) == to.map(|k| &k.id[..]) {
+		return Ok(false);
+	}
+	let tmp = dest.with_extension("json.tmp");
+	fs::write(&tmp, seal_with(to, &plain)?).with_context(|| format!("write {}", tmp.display()))?;
+	fs::rename(&tmp, &dest).with_context(|| format!("write {}", dest.display()))?;
+	if dest != p {
+		fs::remove_file(p)?;
+	}
+	Ok(true)
+}
+
+fn rewrite_all(keys: &[&Key], to: Option<&Key>) -> Result<(usize, usize)> {
+	let files = protected_files();
+	let mut changed = 0;
+	for p in &files {
+		if rewrite(p, keys, to)? {
+			changed += 1;
+		}
+	}
+	Ok((changed, files.len()))
+}
+
+fn require_stopped() -> Result<()> {
+	if let Some(pid) = lock::running_pid() {
+		return Err(cli::fail(EXIT_NOT_READY, format!("守护进程正在运行 (pid {pid})，先停止服务")));
+	}
+	Ok(())
+}
+
+fn require_key() -> Result<&'static Key> {
+	current()?.ok_or_else(|| {
+		cli::fail(
+			EXIT_KEY,
+			format!("没有配置密钥：设置 MAGICBOT_KEY_FILE / MAGICBOT_KEY，或在 unit 里 LoadCredential={CREDENTIAL}:<密钥文件>"),
+		)
+	})
+}
+
+// magicbot crypt status [--json]
+// magicbot crypt keygen [<file>]                    生成密钥（写文件时权限 0600，不覆盖已有文件）
+// magicbot crypt encrypt                            用当前密钥加密现有文件
+// magicbot crypt decrypt                            全部改回明文（关闭加密前执行）
+// magicbot crypt rotate --new-key-file <file>       换成新密钥；中途失败可以重跑，之后把密钥来源换成新文件
+pub fn crypt_cli(args: &[String]) -> Result<()> {
+	const USAGE: &str = "magicbot crypt status | keygen [<file>] | encrypt | decrypt | rotate --new-key-file <file>";
+	let json = json_mode(args);
+	match args.get(2).map(|s| s.as_str()) {
+		Some("status") => {
+			let key = current()?;
+			let (mut enc, mut plain, mut other) = (0, 0, 0);
+			for p in protected_files() {
+				let raw = fs::read(&p).unwrap_or_default();
+				match key_id_of(&raw) {
+					None => plain += 1,
+					Some(id) if key.is_some_and(|k| k.id == id) => enc += 1,
+					Some(_) => other += 1,
+				}
+			}
+			let unprotected = unprotected();
+			let v = json!({
+				"key": key.map(|k| json!({ "id": k.id_hex(), "source": k.source })),
+				"encrypted": enc,
+				"plaintext": plain,
+				"other_key": other,
+				"unprotected": unprotected
+					.iter()
+					.map(|(what, p)| json!({ "what": what, "path": p, "exists": p.exists() }))
+					.chain([json!({ "what": "signal-cli data", "path": null, "exists": null })])
+					.collect::<Vec<_>>(),
+			});
+			report(json, v, || {
+				match key {
+					Some(k) => println!("密钥      : id {}（{}）", k.id_hex(), k.source),
+					None => println!("密钥      : 未配置（新写的文件为明文）"),
+				}
+				println!("已加密    : {enc}");
+				println!("明文      : {plain}");
+				if other > 0 {
+					println!("其它密钥  : {other}（当前密钥解不开）");
+				}
+				if key.is_some() && plain > 0 {
+					println!("[INF] 运行 magicbot crypt encrypt 把现有明文文件加密");
+				}
+				println!("[INF] 以下不加密，请用磁盘/文件系统加密保护：");
+				println!("      signal-cli 数据目录");
+				for (what, p) in &unprotected {
+					let mark = if p.exists() { "" } else { "（当前不存在）" };
+					println!("      {what}: {}{mark}", p.display());
+				}
+			});
+			Ok(())
+		}
+		Some("keygen") => {
+			let mut bytes = [0u8; 32];
+			random(&mut bytes)?;
+			let text = format!("{}\n", hex(&bytes));
+			match args.get(3).filter(|a| !a.starts_with("--")) {
+				Some(p) => {
+					let p = Path::new(p);
+					if let Some(parent) = p.parent().filter(|d| !d.as_os_str().is_empty()) {
+						fs::create_dir_all(parent)?;
+					}
+					let mut f = fs::OpenOptions::new()
+						.write(true)
+						.create_new(true)
+						.mode(0o600)
+						.open(p)
+						.with_context(|| format!("create {}（不会覆盖已有文件）", p.display()))?;
+					std::io::Write::write_all(&mut f, text.as_bytes())?;
+					let id = Key::new(bytes, String::new()).id_hex();
+					report(json, json!({ "ok": true, "file": p, "id": id }), || {
+						println!("[OK] 已生成密钥 {}（id {id}）", p.display())
+					});
+				}
+				None => print!("{text}"),
+			}
+			Ok(())
+		}
+		Some("encrypt") => {
+			require_stopped()?;
+			let key = require_key()?;
+			let (n, total) = rewrite_all(&[key], Some(key))?;
+			report(json, json!({ "ok": true, "changed": n, "files": total, "key_id": key.id_hex() }), || {
+				println!("[OK] 已加密 {n} 个文件（共 {total} 个，密钥 id {}）", key.id_hex())
+			});
+			Ok(())
+		}
+		Some("decrypt") => {
+			require_stopped()?;
+			let keys: Vec<&Key> = current()?.into_iter().collect();
+			let (n, total) = rewrite_all(&keys, None)?;
+			report(json, json!({ "ok": true, "changed": n, "files": total }), || {
+				println!("[OK] 已解密 {n} 个文件（共 {total} 个）。去掉密钥配置后就是明文存储。")
+			});
+			Ok(())
+		}
+		Some("rotate") => {
+			require_stopped()?;
+			let new = flag_value(args, "--new-key-file").ok_or_else(|| usage("magicbot crypt rotate --new-key-file <file>"))?;
+			let new = load_key_file(Path::new(&new))?;
+			// 当前没有密钥时等于 encrypt；已经换过的文件（上次中断）按新密钥识别
+			let mut keys: Vec<&Key> = current()?.into_iter().collect();
+			keys.push(&new);
+			let (n, total) = rewrite_all(&keys, Some(&new))?;
+			report(json, json!({ "ok": true, "changed": n, "files": total, "key_id": new.id_hex() }), || {
+				println!("[OK] 已用新密钥 (id {}) 重写 {n} 个文件（共 {total} 个）", new.id_hex());
+				println!("[INF] 现在把密钥来源（LoadCredential / MAGICBOT_KEY_FILE）换成新文件，再启动服务");
+			});
+			Ok(())
+		}
+		_ => Err(usage(USAGE)),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn key(b: u8) -> Key {
+		Key::new([b; 32], format!("test-{b}"))
+	}
+
+	fn p() -> &'static Path {
+		Path::new("/test/file.json")
+	}
+
+	#[test]
+	fn round_trip() {
+		let k = key(1);
+		let plain = br#"{"account":"+15550001"}"#;
+		let sealed = seal_with(Some(&k), plain).unwrap();
+		assert!(sealed.starts_with(MAGIC));
+		assert_eq!(key_id_of(&sealed), Some(&k.id[..]));
+		assert!(!sealed.windows(plain.len()).any(|w| w == plain));
+		assert_eq!(open_with(&[&k], &sealed, p()).unwrap(), plain);
+		// 每次 nonce 不同
+		assert_ne!(seal_with(Some(&k), plain).unwrap(), sealed);
+	}
+
+	#[test]
+	fn plaintext_passes_through() {
+		let plain = br#"{"group_id":"G1"}"#;
+		assert_eq!(seal_with(None, plain).unwrap(), plain);
+		assert_eq!(open_with(&[], plain, p()).unwrap(), plain);
+		assert_eq!(open_with(&[&key(1)], plain, p()).unwrap(), plain);
+		assert_eq!(open_with(&[], b"", p()).unwrap(), b"");
+	}
+
+	#[test]
+	fn wrong_key_is_rejected() {
+		let sealed = seal_with(Some(&key(1)), b"secret").unwrap();
+		let e = open_with(&[&key(2)], &sealed, p()).unwrap_err();
+		assert_eq!(cli::exit_code(&e), EXIT_KEY);
+		assert!(e.to_string().contains(&key(1).id_hex()));
+		let e = open_with(&[], &sealed, p()).unwrap_err();
+		assert_eq!(cli::exit_code(&e), EXIT_KEY);
+		assert!(e.to_string().contains("MAGICBOT_KEY_FILE"));
+		// 轮换时新旧两把都给
+		assert_eq!(open_with(&[&key(2), &key(1)], &sealed, p()).unwrap(), b"secret");
+	}
+
+	#[test]
+	fn truncated_header_is_rejected() {
+		let sealed = seal_with(Some(&key(1)), b"secret").unwrap();
+		for n in [MAGIC.len(), MAGIC.len() + 3, HEADER_LEN - 1] {
+			let e = open_with(&[&key(1)], &sealed[..n], p()).unwrap_err();
+			assert_eq!(cli::exit_code(&e), EXIT_KEY);
+			assert!(e.to_string().contains("截断"));
+		}
+		// 头完整但密文被截
+		let e = open_with(&[&key(1)], &sealed[..sealed.len() - 1], p()).unwrap_err();
+		assert!(e.to_string().contains("解密失败"));
+	}
+
+	#[test]
+	fn tampering_is_detected() {
+		let k = key(1);
+		let mut sealed = seal_with(Some(&k), b"secret").unwrap();
+		let last = sealed.len() - 1;
+		sealed[last] ^= 1;
+		assert!(open_with(&[&k], &sealed, p()).is_err());
+	}
+
+	#[test]
+	fn parse_key_accepts_hex_only() {
+		let hex64 = "00".repeat(31) + "ff\n";
+		assert_eq!(parse_key(&hex64, "t").unwrap().bytes[31], 0xff);
+		assert!(parse_key("abc", "t").is_err());
+		assert!(parse_key(&"zz".repeat(32), "t").is_err());
+	}
+
+	#[test]
+	fn mark_names_are_blinded_per_key() {
+		assert_eq!(blind_with(None, "+15550001"), "+15550001");
+		let a = blind_with(Some(&key(1)), "+15550001");
+		assert_eq!(a.len(), 32);
+		assert_ne!(a, blind_with(Some(&key(2)), "+15550001"));
+		assert_eq!(a, blind_with(Some(&key(1)), "+15550001"));
+	}
+}
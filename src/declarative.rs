@@ -0,0 +1,704 @@
+use crate::cli::{self, json_mode, positionals, print_json, report, usage, EXIT_INVALID};
+use crate::{
+	canonical_perm, group_cfg_path, has_flag, list_group_cfg_ids, load_global, load_group_cfg, save_global,
+	save_group_cfg, theme, GlobalConfig, GroupConfig, KeywordGroupBan, KeywordGroupReply, KeywordGroupWarn,
+};
+use anyhow::{anyhow, Context, Result};
+use dialoguer::Confirm;
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io::IsTerminal;
+use std::path::Path;
+
+// 一份文件管整个机器上的群：
+//
+//   [global]                 # GlobalConfig 里的各段（recorder / outbox / cache ...），没写的取默认值
+//   [rule_sets.<名字>]       # auto_replies / warn_rules / ban_rules，可被多个群引用
+//   [groups."<group id>"]    # GroupConfig 的字段 + rule_sets = ["<名字>", ...]
+//
+// 按扩展名识别 .toml / .yaml / .yml / .json
+const TOP_KEYS: [&str; 3] = ["global", "rule_sets", "groups"];
+
+// 登录、选群、安装时由程序维护的字段，不能写进配置文件
+const GLOBAL_RUNTIME: [&str; 5] = ["schema_version", "installed_at", "account", "selected_group", "daemon_enabled"];
+const GROUP_RESERVED: [&str; 2] = ["schema_version", "group_id"];
+
+const PLACEHOLDER: &str = "##{@user}##";
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RuleSet {
+	auto_replies: Vec<KeywordGroupReply>,
+	warn_rules: Vec<KeywordGroupWarn>,
+	ban_rules: Vec<KeywordGroupBan>,
+}
+
+#[derive(Default)]
+struct Findings {
+	errors: Vec<String>,
+	warnings: Vec<String>,
+}
+
+impl Findings {
+	fn error(&mut self, at: &str, msg: impl AsRef<str>) {
+		self.errors.push(format!("{at}: {}", msg.as_ref()));
+	}
+
+	fn warn(&mut self, at: &str, msg: impl AsRef<str>) {
+		self.warnings.push(format!("{at}: {}", msg.as_ref()));
+	}
+}
+
+// 校验通过后要落盘的样子
+struct Plan {
+	global: GlobalConfig,
+	groups: BTreeMap<String, GroupConfig>,
+}
+
+fn parse_file(path: &Path) -> Result<Value> {
+	let s = fs::read_to_string(path).with_context(|| format!("read {}", path.display()))?;
+	let ext = path.extension().and_then(|e| e.to_str()).unwrap_or_default();
+	let v = match ext {
+		"toml" => toml::from_str::<Value>(&s).map_err(|e| anyhow!("{e}"))?,
+		"yaml" | "yml" => serde_yaml::from_str::<Value>(&s).map_err(|e| anyhow!("{e}"))?,
+		"json" => serde_json::from_str::<Value>(&s)?,
+		_ => return Err(usage("配置文件扩展名需为 .toml / .yaml / .yml / .json")),
+	};
+	Ok(v)
+}
+
+// 欢迎语只认 ##{@user}##，其它像占位符的写法（{@user}、##{@name}## ...）多半是手误
+pub fn template_problem(tpl: &str) -> Option<String> {
+	if tpl.trim().is_empty() {
+		return Some("欢迎语为空；不需要欢迎语就不要写这一项".to_string());
+	}
+	let rest = tpl.replace(PLACEHOLDER, "");
+	if rest.contains("{@") || rest.contains("##") {
+		return Some(format!("无法识别的占位符，只支持 {PLACEHOLDER}"));
+	}
+	None
+}
+
+fn check_keywords(f: &mut Findings, at: &str, keywords: &[String]) {
+	if keywords.is_empty() {
+		f.error(at, "keywords 为空");
+	}
+	for (i, k) in keywords.iter().enumerate() {
+		if k.trim().is_empty() {
+			f.error(&format!("{at}.keywords[{i}]"), "关键词为空");
+		}
+	}
+}
+
+fn check_rules(f: &mut Findings, at: &str, replies: &[KeywordGroupReply], warns: &[KeywordGroupWarn], bans: &[KeywordGroupBan]) {
+	for (i, r) in replies.iter().enumerate() {
+		let at = format!("{at}.auto_replies[{i}]");
+		check_keywords(f, &at, &r.keywords);
+		if r.reply.trim().is_empty() {
+			f.error(&at, "reply 为空");
+		}
+	}
+	for (i, r) in warns.iter().enumerate() {
+		check_keywords(f, &format!("{at}.warn_rules[{i}]"), &r.keywords);
+	}
+	for (i, r) in bans.iter().enumerate() {
+		check_keywords(f, &format!("{at}.ban_rules[{i}]"), &r.keywords);
+	}
+}
+
+// 文件里出现了 template（默认配置序列化出来的样子）没有的键
+fn unknown_keys(f: &mut Findings, at: &str, template: &Value, v: &Value, allow: &[&str]) {
+	let (Some(t), Some(o)) = (template.as_object(), v.as_object()) else {
+		return;
+	};
+	for (k, sub) in o {
+		let path = format!("{at}.{k}");
+		match t.get(k) {
+			Some(ts) => unknown_keys(f, &path, ts, sub, &[]),
+			None if allow.contains(&k.as_str()) => {}
+			None => f.error(&path, "未知字段"),
+		}
+	}
+}
+
+fn merge(base: &mut Value, over: &Value) {
+	match (base, over) {
+		(Value::Object(b), Value::Object(o)) => {
+			for (k, v) in o {
+				match b.get_mut(k) {
+					Some(slot) if slot.is_object() && v.is_object() => merge(slot, v),
+					_ => {
+						b.insert(k.clone(), v.clone());
+					}
+				}
+			}
+		}
+		(b, o) => *b = o.clone(),
+	}
+}
+
+fn build_global(f: &mut Findings, declared: Option<&Value>) -> Result<GlobalConfig> {
+	let disk = serde_json::to_value(load_global()?)?;
+	let mut base = serde_json::to_value(GlobalConfig::default())?;
+	let template = base.clone();
+	// 运行时字段和新版本写进来的未知字段沿用磁盘上的
+	if let (Some(b), Some(d)) = (base.as_object_mut(), disk.as_object()) {
+		for (k, v) in d {
+			if GLOBAL_RUNTIME.contains(&k.as_str()) || !template.as_object().is_some_and(|t| t.contains_key(k)) {
+				b.insert(k.clone(), v.clone());
+			}
+		}
+	}
+	if let Some(g) = declared {
+		if !g.is_object() {
+			f.error("global", "应为表/映射");
+		} else {
+			unknown_keys(f, "global", &template, g, &[]);
+			for k in GLOBAL_RUNTIME {
+				if g.get(k).is_some() {
+					f.error(&format!("global.{k}"), "由程序维护，不能写在配置文件里");
+				}
+			}
+			let mut over = g.clone();
+			if let Some(o) = over.as_object_mut() {
+				o.retain(|k, _| !GLOBAL_RUNTIME.contains(&k.as_str()));
+			}
+			merge(&mut base, &over);
+		}
+	}
+	match serde_json::from_value::<GlobalConfig>(base) {
+		Ok(gc) => Ok(gc),
+		Err(e) => {
+			f.error("global", e.to_string());
+			load_global()
+		}
+	}
+}
+
+fn build_group(f: &mut Findings, gid: &str, v: &Value, sets: &BTreeMap<String, RuleSet>, used: &mut BTreeSet<String>) -> Result<Option<GroupConfig>> {
+	let at = format!("groups.{gid}");
+	if gid.trim().is_empty() || gid.contains('/') {
+		f.error(&at, "group id 不合法");
+		return Ok(None);
+	}
+	let Some(obj) = v.as_object() else {
+		f.error(&at, "应为表/映射");
+		return Ok(None);
+	};
+	let template = serde_json::to_value(GroupConfig::default())?;
+	unknown_keys(f, &at, &template, v, &["rule_sets"]);
+	for k in GROUP_RESERVED {
+		if obj.contains_key(k) {
+			f.error(&format!("{at}.{k}"), "由程序维护，不能写在配置文件里");
+		}
+	}
+
+	let mut base = template.clone();
+	base["group_id"] = Value::String(gid.to_string());
+	// 群名默认沿用选群时从 Signal 取到的；未知字段原样保留
+	if group_cfg_path(gid).exists() {
+		let disk = serde_json::to_value(load_group_cfg(gid)?)?;
+		base["group_name"] = disk["group_name"].clone();
+		if let (Some(b), Some(d)) = (base.as_object_mut(), disk.as_object()) {
+			for (k, v) in d {
+				if !template.as_object().is_some_and(|t| t.contains_key(k)) {
+					b.insert(k.clone(), v.clone());
+				}
+			}
+		}
+	}
+	let mut over = v.clone();
+	if let Some(o) = over.as_object_mut() {
+		o.retain(|k, _| k != "rule_sets" && !GROUP_RESERVED.contains(&k.as_str()));
+	}
+	merge(&mut base, &over);
+	let mut cfg = match serde_json::from_value::<GroupConfig>(base) {
+		Ok(c) => c,
+		Err(e) => {
+			f.error(&at, e.to_string());
+			return Ok(None);
+		}
+	};
+	check_rules(f, &at, &cfg.auto_replies, &cfg.warn_rules, &cfg.ban_rules);
+
+	// 引用的规则集追加在群自己的规则后面
+	let refs = match obj.get("rule_sets") {
+		None => vec![],
+		Some(Value::Array(a)) if a.iter().all(|x| x.is_string()) => {
+			a.iter().filter_map(|x| x.as_str()).map(str::to_string).collect()
+		}
+		Some(_) => {
+			f.error(&format!("{at}.rule_sets"), "应为字符串数组");
+			vec![]
+		}
+	};
+	for name in refs {
+		let Some(set) = sets.get(&name) else {
+			f.error(&format!("{at}.rule_sets"), format!("规则集 {name:?} 未定义"));
+			continue;
+		};
+		used.insert(name);
+		cfg.auto_replies.extend(set.auto_replies.iter().cloned());
+		cfg.warn_rules.extend(set.warn_rules.iter().cloned());
+		cfg.ban_rules.extend(set.ban_rules.iter().cloned());
+	}
+
+	for (key, slot) in [
+		("desired_permission_add_member", &mut cfg.desired_permission_add_member),
+		("desired_permission_send_message", &mut cfg.desired_permission_send_message),
+		("desired_permission_edit_details", &mut cfg.desired_permission_edit_details),
+	] {
+		match canonical_perm(slot) {
+			Some(p) => *slot = p,
+			None => f.error(&format!("{at}.{key}"), format!("{slot:?} 不是 EVERY_MEMBER / ONLY_ADMINS")),
+		}
+	}
+	if let Some(tpl) = &cfg.welcome_template {
+		match template_problem(tpl) {
+			Some(p) => f.error(&format!("{at}.welcome_template"), p),
+			None if !tpl.contains(PLACEHOLDER) => {
+				f.warn(&format!("{at}.welcome_template"), format!("没有 {PLACEHOLDER}，欢迎语里不会出现新成员的名字"))
+			}
+			None => {}
+		}
+	}
+	if cfg.warn_message.trim().is_empty() {
+		f.error(&format!("{at}.warn_message"), "警告文案为空");
+	}
+	if cfg.warn_max_count == 0 {
+		f.error(&format!("{at}.warn_max_count"), "至少为 1");
+	}
+	Ok(Some(cfg))
+}
+
+fn build(doc: &Value) -> Result<(Findings, Plan)> {
+	let mut f = Findings::default();
+	let empty = Map::new();
+	let top = match doc.as_object() {
+		Some(o) => o,
+		None => {
+			f.error("(root)", "应为表/映射");
+			&empty
+		}
+	};
+	for k in top.keys() {
+		if !TOP_KEYS.contains(&k.as_str()) {
+			f.error(k, "未知字段（只支持 global / rule_sets / groups）");
+		}
+	}
+
+	let global = build_global(&mut f, top.get("global"))?;
+
+	let mut sets = BTreeMap::new();
+	for (name, v) in top.get("rule_sets").and_then(|v| v.as_object()).unwrap_or(&empty) {
+		let at = format!("rule_sets.{name}");
+		match serde_json::from_value::<RuleSet>(v.clone()) {
+			Ok(set) => {
+				check_rules(&mut f, &at, &set.auto_replies, &set.warn_rules, &set.ban_rules);
+				sets.insert(name.clone(), set);
+			}
+			Err(e) => f.error(&at, e.to_string()),
+		}
+	}
+
+	let mut groups = BTreeMap::new();
+	let mut used = BTreeSet::new();
+	let declared = top.get("groups").and_then(|v| v.as_object()).unwrap_or(&empty);
+	if declared.is_empty() {
+		f.warn("groups", "没有声明任何群");
+	}
+	for (gid, v) in declared {
+		if let Some(cfg) = build_group(&mut f, gid, v, &sets, &mut used)? {
+			groups.insert(gid.clone(), cfg);
+		}
+	}
+	for name in sets.keys().filter(|n| !used.contains(*n)) {
+		f.warn(&format!("rule_sets.{name}"), "没有群引用");
+	}
+	Ok((f, Plan { global, groups }))
+}
+
+// 把 JSON 展开成 路径 -> 叶子值，用来逐项对比
+fn flatten(prefix: &str, v: &Value, out: &mut BTreeMap<String, Value>) {
+	match v {
+		Value::Object(o) if !o.is_empty() => {
+			for (k, sub) in o {
+				let p = if prefix.is_empty() { k.clone() } else { format!("{prefix}.{k}") };
+				flatten(&p, sub, out);
+			}
+		}
+		Value::Array(a) if !a.is_empty() => {
+			for (i, sub) in a.iter().enumerate() {
+				flatten(&format!("{prefix}[{i}]"), sub, out);
+			}
+		}
+		_ => {
+			out.insert(prefix.to_string(), v.clone());
+		}
+	}
+}
+
+fn diff(target: &str, old: &Value, new: &Value, out: &mut Vec<Value>) {
+	let (mut a, mut b) = (BTreeMap::new(), BTreeMap::new());
+	flatten("", old, &mut a);
+	flatten("", new, &mut b);
+	// 空数组/空表变成有内容（或反过来）时只列出里面的项
+	let has_children = |m: &BTreeMap<String, Value>, k: &str| {
+		m.keys().any(|x| x.len() > k.len() && x.starts_with(k) && matches!(x.as_bytes()[k.len()], b'.' | b'['))
+	};
+	let keys: BTreeSet<&String> = a.keys().chain(b.keys()).collect();
+	for k in keys {
+		if (!a.contains_key(k) && has_children(&a, k)) || (!b.contains_key(k) && has_children(&b, k)) {
+			continue;
+		}
+		let op = match (a.get(k), b.get(k)) {
+			(Some(x), Some(y)) if x == y => continue,
+			(Some(_), Some(_)) => "~",
+			(None, Some(_)) => "+",
+			(Some(_), None) => "-",
+			(None, None) => continue,
+		};
+		out.push(json!({ "target": target, "op": op, "path": k, "old": a.get(k), "new": b.get(k) }));
+	}
+}
+
+fn print_changes(changes: &[Value]) {
+	let mut last = "";
+	for c in changes {
+		let target = c["target"].as_str().unwrap_or_default();
+		if target != last {
+			println!("{target}:");
+			last = target;
+		}
+		let show = |v: &Value| v.to_string();
+		match c["op"].as_str() {
+			Some("~") => println!("  ~ {}: {} -> {}", c["path"].as_str().unwrap_or_default(), show(&c["old"]), show(&c["new"])),
+			Some("+") => println!("  + {}: {}", c["path"].as_str().unwrap_or_default(), show(&c["new"])),
+			_ => println!("  - {}: {}", c["path"].as_str().unwrap_or_default(), show(&c["old"])),
+		}
+	}
+}
+
+fn print_findings(f: &Findings) {
+	for w in &f.warnings {
+		println!("[WRN] {w}");
+	}
+	for e in &f.errors {
+		println!("[ERR] {e}");
+	}
+}
+
+fn invalid(json: bool, f: &Findings) -> anyhow::Error {
+	if json {
+		// 结果已经以 JSON 输出，只要退出码
+		return cli::fail(EXIT_INVALID, "");
+	}
+	cli::fail(EXIT_INVALID, format!("配置文件有 {} 处错误", f.errors.len()))
+}
+
+// 去掉 null：TOML 没有 null，Option 为空就不写
+fn strip_nulls(v: &mut Value) {
+	match v {
+		Value::Object(o) => {
+			o.retain(|_, x| !x.is_null());
+			o.values_mut().for_each(strip_nulls);
+		}
+		Value::Array(a) => a.iter_mut().for_each(strip_nulls),
+		_ => {}
+	}
+}
+
+// 现有的 global.json + groups/*.json 导出成声明式文件，作为纳管的起点
+fn export(format: &str) -> Result<String> {
+	let mut global = serde_json::to_value(load_global()?)?;
+	if let Some(o) = global.as_object_mut() {
+		o.retain(|k, _| !GLOBAL_RUNTIME.contains(&k.as_str()));
+	}
+	let mut groups = Map::new();
+	let mut ids = list_group_cfg_ids();
+	ids.sort();
+	for gid in ids {
+		let mut v = serde_json::to_value(load_group_cfg(&gid)?)?;
+		if let Some(o) = v.as_object_mut() {
+			o.retain(|k, _| !GROUP_RESERVED.contains(&k.as_str()));
+		}
+		groups.insert(gid, v);
+	}
+	let mut doc = json!({ "global": global, "groups": groups });
+	strip_nulls(&mut doc);
+	match format {
+		"toml" => toml::to_string_pretty(&doc).map_err(|e| anyhow!("{e}")),
+		"yaml" | "yml" => serde_yaml::to_string(&doc).map_err(|e| anyhow!("{e}")),
+		"json" => Ok(serde_json::to_string_pretty(&doc)? + "\n"),
+		_ => Err(usage("--format toml|yaml|json")),
+	}
+}
+
+// magicbot config validate <file> [--json]
+// magicbot config apply <file> [--yes|--dry-run] [--prune] [--json]   先列出差异，确认后写入；--prune 关掉文件里没有的群。
+//   --dry-run 只输出差异（applied=false，退出码 0）；非交互又没给 --yes 时同样输出差异，但以退出码 2 结束
+// magicbot config export [<file>] [--format toml|yaml|json] [--json]   不给文件就打到 stdout，格式默认按扩展名
+pub fn config_cli(args: &[String]) -> Result<()> {
+	const USAGE: &str = "magicbot config validate <file> | apply <file> [--yes|--dry-run] [--prune] | export [<file>] [--format toml|yaml|json]";
+	let json = json_mode(args);
+	let pos = positionals(args, 3);
+	let sub = args.get(2).map(|s| s.as_str());
+	if sub == Some("export") {
+		let out = pos.first().map(Path::new);
+		let format = crate::flag_value(args, "--format")
+			.or_else(|| out.and_then(|p| p.extension()).map(|e| e.to_string_lossy().into_owned()))
+			.unwrap_or_else(|| "toml".to_string());
+		let text = export(&format)?;
+		match out {
+			Some(p) => {
+				fs::write(p, text).with_context(|| format!("write {}", p.display()))?;
+				report(json, json!({ "ok": true, "file": p, "format": format }), || {
+					println!("[OK] 已导出到 {}", p.display())
+				});
+			}
+			None if json => print_json(&json!({ "ok": true, "format": format, "content": text })),
+			None => print!("{text}"),
+		}
+		return Ok(());
+	}
+
+	let file = Path::new(*pos.first().ok_or_else(|| usage(USAGE))?);
+	let doc = parse_file(file).map_err(|e| match cli::exit_code(&e) {
+		1 => cli::fail(EXIT_INVALID, format!("{}: {e:#}", file.display())),
+		_ => e,
+	})?;
+	let (f, plan) = build(&doc)?;
+
+	match sub {
+		Some("validate") => {
+			let v = json!({
+				"ok": f.errors.is_empty(),
+				"errors": f.errors,
+				"warnings": f.warnings,
+				"groups": plan.groups.len(),
+			});
+			report(json, v, || {
+				print_findings(&f);
+				if f.errors.is_empty() {
+					println!("[OK] {} 校验通过（{} 个群）", file.display(), plan.groups.len());
+				}
+			});
+			if !f.errors.is_empty() {
+				return Err(invalid(json, &f));
+			}
+			Ok(())
+		}
+		Some("apply") => {
+			if !f.errors.is_empty() {
+				report(json, json!({ "ok": false, "errors": f.errors, "warnings": f.warnings }), || {
+					print_findings(&f)
+				});
+				return Err(invalid(json, &f));
+			}
+			let mut changes = vec![];
+			let disk_global = serde_json::to_value(load_global()?)?;
+			diff("global", &disk_global, &serde_json::to_value(&plan.global)?, &mut changes);
+			for (gid, cfg) in &plan.groups {
+				let old = if group_cfg_path(gid).exists() {
+					serde_json::to_value(load_group_cfg(gid)?)?
+				} else {
+					serde_json::to_value(GroupConfig {
+						group_id: gid.clone(),
+						..GroupConfig::default()
+					})?
+				};
+				diff(&format!("group {gid}"), &old, &serde_json::to_value(cfg)?, &mut changes);
+			}
+			let unmanaged: Vec<String> = list_group_cfg_ids()
+				.into_iter()
+				.filter(|g| !plan.groups.contains_key(g))
+				.collect();
+			let mut pruned = vec![];
+			if has_flag(args, "--prune") {
+				for gid in &unmanaged {
+					let mut cfg = load_group_cfg(gid)?;
+					if cfg.enabled {
+						let old = serde_json::to_value(&cfg)?;
+						cfg.enabled = false;
+						diff(&format!("group {gid}"), &old, &serde_json::to_value(&cfg)?, &mut changes);
+						pruned.push(cfg);
+					}
+				}
+			}
+
+			if !json {
+				for w in &f.warnings {
+					println!("[WRN] {w}");
+				}
+				print_changes(&changes);
+				if !unmanaged.is_empty() && !has_flag(args, "--prune") {
+					println!("[INF] 不在配置文件里的群保持不变（--prune 会关掉它们）: {}", unmanaged.join(", "));
+				}
+			}
+			if changes.is_empty() {
+				report(json, json!({ "ok": true, "changed": false, "applied": false, "changes": [] }), || {
+					println!("[OK] 没有变化。")
+				});
+				return Ok(());
+			}
+			let dry_run = has_flag(args, "--dry-run");
+			if dry_run || (!has_flag(args, "--yes") && (json || !std::io::stdin().is_terminal())) {
+				let v = json!({ "ok": true, "changed": true, "applied": false, "warnings": f.warnings, "changes": changes });
+				report(json, v, || {
+					if dry_run {
+						println!("[INF] --dry-run：以上 {} 处修改没有写入。", changes.len())
+					}
+				});
+				if dry_run {
+					return Ok(());
+				}
+				// 差异已经输出；退出码区分"没写入"
+				let msg = if json { "" } else { "非交互执行 config apply 需要 --yes（只看差异用 --dry-run）" };
+				return Err(cli::fail(cli::EXIT_USAGE, msg));
+			}
+			if !has_flag(args, "--yes") {
+				let ok = Confirm::with_theme(&theme())
+					.with_prompt(format!("写入以上 {} 处修改？", changes.len()))
+					.default(false)
+					.interact()?;
+				if !ok {
+					println!("[INF] 已取消，没有写入。");
+					return Ok(());
+				}
+			}
+
+			let global_changed = changes.iter().any(|c| c["target"] == "global");
+			if global_changed {
+				save_global(&plan.global)?;
+			}
+			for cfg in plan.groups.values().chain(pruned.iter()) {
+				let target = format!("group {}", cfg.group_id);
+				if changes.iter().any(|c| c["target"] == target.as_str()) {
+					save_group_cfg(cfg)?;
+				}
+			}
+			report(
+				json,
+				json!({ "ok": true, "changed": true, "applied": true, "warnings": f.warnings, "changes": changes }),
+				|| {
+					println!("[OK] 已写入 {} 处修改。群配置会被运行中的守护进程自动加载。", changes.len());
+					if global_changed {
+						println!("[INF] 全局设置需要重启守护进程才生效。");
+					}
+				},
+			);
+			Ok(())
+		}
+		_ => Err(usage(USAGE)),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const GID: &str = "declarative-test-group";
+
+	fn group(v: Value, sets: &BTreeMap<String, RuleSet>) -> (Findings, Option<GroupConfig>) {
+		let mut f = Findings::default();
+		let cfg = build_group(&mut f, GID, &v, sets, &mut BTreeSet::new()).unwrap();
+		(f, cfg)
+	}
+
+	fn has_error(f: &Findings, at: &str) -> bool {
+		f.errors.iter().any(|e| e.starts_with(&format!("groups.{GID}.{at}")))
+	}
+
+	#[test]
+	fn valid_group_has_no_findings() {
+		let (f, cfg) = group(
+			json!({
+				"enabled": true,
+				"welcome_template": "欢迎 ##{@user}##",
+				"desired_permission_add_member": "only_admins",
+				"auto_replies": [{ "keywords": ["ping"], "reply": "pong" }],
+			}),
+			&BTreeMap::new(),
+		);
+		assert!(f.errors.is_empty(), "{:?}", f.errors);
+		assert!(f.warnings.is_empty(), "{:?}", f.warnings);
+		assert_eq!(cfg.unwrap().desired_permission_add_member, "ONLY_ADMINS");
+	}
+
+	#[test]
+	fn typoed_permission_is_rejected() {
+		let (f, _) = group(json!({ "desired_permission_send_message": "EVERY_MEMBR" }), &BTreeMap::new());
+		assert!(has_error(&f, "desired_permission_send_message"), "{:?}", f.errors);
+
+		let (f, _) = group(json!({ "desired_permision_add_member": "EVERY_MEMBER" }), &BTreeMap::new());
+		assert!(has_error(&f, "desired_permision_add_member"), "{:?}", f.errors);
+	}
+
+	#[test]
+	fn empty_keywords_are_rejected() {
+		let (f, _) = group(
+			json!({
+				"warn_rules": [{ "keywords": [] }],
+				"ban_rules": [{ "keywords": ["ok", " "] }],
+			}),
+			&BTreeMap::new(),
+		);
+		assert!(has_error(&f, "warn_rules[0]"), "{:?}", f.errors);
+		assert!(has_error(&f, "ban_rules[0].keywords[1]"), "{:?}", f.errors);
+	}
+
+	#[test]
+	fn bad_placeholder_is_rejected() {
+		for tpl in ["欢迎 {@user}", "欢迎 ##{@name}##", "  "] {
+			let (f, _) = group(json!({ "welcome_template": tpl }), &BTreeMap::new());
+			assert!(has_error(&f, "welcome_template"), "{tpl:?}: {:?}", f.errors);
+		}
+	}
+
+	#[test]
+	fn missing_rule_set_reference_is_rejected() {
+		let sets = BTreeMap::from([(
+			"common".to_string(),
+			RuleSet {
+				ban_rules: vec![serde_json::from_value(json!({ "keywords": ["spam"] })).unwrap()],
+				..RuleSet::default()
+			},
+		)]);
+		let (f, cfg) = group(json!({ "rule_sets": ["common", "comon"] }), &sets);
+		assert_eq!(f.errors.len(), 1, "{:?}", f.errors);
+		assert!(has_error(&f, "rule_sets"));
+		assert!(f.errors[0].contains("comon"));
+		assert_eq!(cfg.unwrap().ban_rules.len(), 1);
+	}
+
+	#[test]
+	fn diff_lists_items_added_to_empty_arrays() {
+		let old = json!({ "enabled": false, "auto_replies": [], "warn_rules": [] });
+		let new = json!({
+			"enabled": true,
+			"auto_replies": [{ "keywords": ["ping"], "reply": "pong" }],
+			"warn_rules": [],
+		});
+		let mut out = vec![];
+		diff("group G", &old, &new, &mut out);
+		let got: Vec<(String, String)> = out
+			.iter()
+			.map(|c| (c["op"].as_str().unwrap().to_string(), c["path"].as_str().unwrap().to_string()))
+			.collect();
+		assert_eq!(
+			got,
+			vec![
+				("+".to_string(), "auto_replies[0].keywords[0]".to_string()),
+				("+".to_string(), "auto_replies[0].reply".to_string()),
+				("~".to_string(), "enabled".to_string()),
+			]
+		);
+
+		let mut back = vec![];
+		diff("group G", &new, &old, &mut back);
+		assert!(back.iter().filter(|c| c["path"] != "enabled").all(|c| c["op"] == "-"));
+		assert_eq!(back.len(), 3);
+	}
+}
@@ -0,0 +1,78 @@
+use crate::paths;
+use anyhow::{anyhow, Context, Result};
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+pub fn pid_path() -> PathBuf {
+	paths::run_dir().join("magicbot.pid")
+}
+
+fn try_flock(f: &File, op: libc::c_int) -> bool {
+	unsafe { libc::flock(f.as_raw_fd(), op | libc::LOCK_NB) == 0 }
+}
+
+fn read_pid(f: &mut File) -> Option<i32> {
+	let mut s = String::new();
+	f.seek(SeekFrom::Start(0)).ok()?;
+	f.read_to_string(&mut s).ok()?;
+	s.trim().parse().ok()
+}
+
+// 守护进程单实例锁：RUN_DIR/magicbot.pid 上持有 flock，进程退出（包括被 kill）时内核自动释放
+pub struct PidLock {
+	file: File,
+}
+
+impl PidLock {
+	pub fn acquire() -> Result<Self> {
+		fs::create_dir_all(paths::run_dir()).with_context(|| format!("create dir {}", paths::run_dir().display()))?;
+		let p = pid_path();
+		let mut file = OpenOptions::new()
+			.read(true)
+			.write(true)
+			.create(true)
+			.truncate(false)
+			.open(&p)
+			.with_context(|| format!("open {}", p.display()))?;
+		if !try_flock(&file, libc::LOCK_EX) {
+			let pid = read_pid(&mut file).map(|p| p.to_string()).unwrap_or_else(|| "?".to_string());
+			return Err(anyhow!("另一个守护进程正在运行 (pid {pid}, {})", p.display()));
+		}
+		file.set_len(0)?;
+		file.seek(SeekFrom::Start(0))?;
+		writeln!(file, "{}", std::process::id())?;
+		file.flush()?;
+		Ok(PidLock { file })
+	}
+}
+
+// 只清空内容、不删文件：删掉的话新守护进程会在新 inode 上加锁，
+// 而别的进程可能还拿着旧文件，两边都以为自己独占
+impl Drop for PidLock {
+	fn drop(&mut self) {
+		let _ = self.file.set_len(0);
+	}
+}
+
+// 有守护进程在跑就返回它的 pid（能拿到锁说明没人持有）
+pub fn running_pid() -> Option<i32> {
+	let mut f = File::open(pid_path()).ok()?;
+	if try_flock(&f, libc::LOCK_SH) {
+		return None;
+	}
+	Some(read_pid(&mut f).unwrap_or(0))
+}
+
+// TUI 和守护进程共享的文件（global.json、groups/、state/、marks/）写之前先锁所在目录：
+// 文件本身是写临时文件再 rename 的，锁文件会跟着换掉，所以锁目录
+pub fn with_dir_lock<T>(dir: &Path, f: impl FnOnce() -> Result<T>) -> Result<T> {
+	fs::create_dir_all(dir).with_context(|| format!("create dir {}", dir.display()))?;
+	let d = File::open(dir).with_context(|| format!("open {}", dir.display()))?;
+	if unsafe { libc::flock(d.as_raw_fd(), libc::LOCK_EX) } != 0 {
+		return Err(anyhow!("flock {}: {}", dir.display(), std::io::Error::last_os_error()));
+	}
+	// d 关掉时锁自动释放
+	f()
+}
@@ -1,35 +1,117 @@
 use anyhow::{anyhow, Context, Result};
-use chrono::{DateTime, TimeZone, Utc};
+use chrono::{Local, TimeZone, Utc};
 use dialoguer::{theme::ColorfulTheme, Confirm, Input, Select};
 use regex::Regex;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::collections::{BTreeSet, HashMap};
 use std::env;
-use std::fs::{self, File};
+use std::fs;
 use std::io::{BufRead, BufReader, Read, Write};
 use std::path::{Path, PathBuf};
-use std::process::{Child, Command, Stdio};
-use std::time::{Duration, SystemTime};
+use std::process::{Command, Stdio};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+mod alerts;
+mod backend;
+mod backup;
+mod cache;
+mod cli;
+mod crypto;
+mod declarative;
+mod lock;
+mod outbox;
+mod paths;
+mod privs;
+mod proc;
+mod ratelimit;
+mod reconcile;
+mod recorder;
+mod replay;
+mod rpc;
+mod schema;
+mod sdnotify;
+mod signals;
+mod state;
+mod supervisor;
+mod throttle;
+mod watch;
+mod workers;
+
+use alerts::AlertConfig;
+use backend::{Backend, CacheScope, GroupFull, GroupUpdate, JsonRpcBackend, SubprocessBackend};
+use cache::{CacheConfig, Cached};
+use lock::PidLock;
+use outbox::{Outbox, OutboxConfig};
+use ratelimit::RateLimitGuard;
+use reconcile::ReconcileConfig;
+use recorder::{Recorder, RecorderConfig};
+use sdnotify::Watchdog;
+use state::{load_group_state, save_group_state, GroupState};
+use supervisor::{Supervisor, SupervisorConfig};
+use throttle::{AccountThrottleConfig, Outgoing, SharedAccountBucket, ThrottleConfig};
+use watch::ConfigWatch;
+use workers::{WorkerConfig, Workers};
 
 const APP: &str = "magicbot";
 const BOT_NAME: &str = "magicbot";
-const STATE_DIR: &str = "/var/lib/magicbot";
-const RUN_DIR: &str = "/run/magicbot";
-const LOG_DIR: &str = "/var/log/magicbot";
-const SYSTEMD_UNIT: &str = "/etc/systemd/system/magicbot.service";
+const DAEMON_TICK: Duration = Duration::from_secs(2);
+// 生成二维码到手机扫完至少要这么久
+const MIN_LINK_TIMEOUT_SECS: u64 = 30;
 
+// 缺的字段取默认值；不认识的字段（新版本写的）放进 extra，写回时原样保留
 #[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
 struct GlobalConfig {
+	schema_version: u32,
 	installed_at: i64,
 	account: Option<String>,
 	signal_cli_config_dir: Option<String>,
 	selected_group: Option<String>,
 	daemon_enabled: bool,
+	recorder: RecorderConfig,
+	supervisor: SupervisorConfig,
+	alerts: AlertConfig,
+	outbox: OutboxConfig,
+	throttle: AccountThrottleConfig,
+	workers: WorkerConfig,
+	reconcile: ReconcileConfig,
+	cache: CacheConfig,
+	// 绑定设备时等手机扫码的时长（秒），最少 MIN_LINK_TIMEOUT_SECS，更小的值按它算
+	link_timeout_secs: u64,
+	#[serde(flatten)]
+	extra: serde_json::Map<String, Value>,
+}
+
+impl Default for GlobalConfig {
+	fn default() -> Self {
+		GlobalConfig {
+			schema_version: schema::GLOBAL.current(),
+			installed_at: Utc::now().timestamp(),
+			account: None,
+			signal_cli_config_dir: None,
+			selected_group: None,
+			daemon_enabled: false,
+			recorder: RecorderConfig::default(),
+			supervisor: SupervisorConfig::default(),
+			alerts: AlertConfig::default(),
+			outbox: OutboxConfig::default(),
+			throttle: AccountThrottleConfig::default(),
+			workers: WorkerConfig::default(),
+			reconcile: ReconcileConfig::default(),
+			cache: CacheConfig::default(),
+			link_timeout_secs: 300,
+			extra: Default::default(),
+		}
+	}
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize, Default)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
 struct GroupConfig {
+	schema_version: u32,
 	group_id: String,
 	group_name: String,
 	enabled: bool,
@@ -50,22 +132,52 @@ struct GroupConfig {
 	desired_permission_send_message: String,
 	desired_permission_edit_details: String,
 
-	last_members_snapshot: BTreeSet<String>,
-	bot_has_admin: bool,
+	throttle: ThrottleConfig,
+
+	#[serde(flatten)]
+	extra: serde_json::Map<String, Value>,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+impl Default for GroupConfig {
+	fn default() -> Self {
+		GroupConfig {
+			schema_version: schema::GROUP.current(),
+			group_id: String::new(),
+			group_name: String::new(),
+			enabled: false,
+			only_admin_can_ban: true,
+			require_bot_admin_to_enforce: true,
+			welcome_template: None,
+			auto_replies: vec![],
+			warn_rules: vec![],
+			ban_rules: vec![],
+			warn_window_minutes: 10,
+			warn_max_count: 3,
+			warn_message: "警告：请停止违规内容，否则将被移出群组。".to_string(),
+			desired_permission_add_member: "EVERY_MEMBER".to_string(),
+			desired_permission_send_message: "EVERY_MEMBER".to_string(),
+			desired_permission_edit_details: "ONLY_ADMINS".to_string(),
+			throttle: ThrottleConfig::default(),
+			extra: Default::default(),
+		}
+	}
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
 struct KeywordGroupReply {
 	keywords: Vec<String>,
 	reply: String,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
 struct KeywordGroupWarn {
 	keywords: Vec<String>,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
 struct KeywordGroupBan {
 	keywords: Vec<String>,
 }
@@ -73,23 +185,69 @@ struct KeywordGroupBan {
 #[derive(Clone, Debug)]
 struct GroupRuntime {
 	cfg: GroupConfig,
+	state: GroupState,
 	admins: BTreeSet<String>,
 	members: BTreeSet<String>,
 	member_names: HashMap<String, String>,
 	self_id: String,
+	out: Outgoing,
+	sim: Option<Simulation>,
 }
 
-#[derive(Clone, Debug)]
-struct Identity {
-	id: String,
-	number: Option<String>,
-	name: Option<String>,
+// --replay 时挂在 GroupRuntime 上：不落盘，警告计数留在内存，时间取消息自身的时间戳
+#[derive(Clone, Debug, Default)]
+struct Simulation {
+	now: i64,
+	marks: HashMap<String, WarnMark>,
+	warns: Vec<String>,
+}
+
+impl GroupRuntime {
+	fn now(&self) -> i64 {
+		match &self.sim {
+			Some(sim) => sim.now,
+			None => Utc::now().timestamp(),
+		}
+	}
+
+	fn save_state(&self) -> Result<()> {
+		if self.sim.is_some() {
+			return Ok(());
+		}
+		save_group_state(&self.cfg.group_id, &self.state)
+	}
+
+	fn say(&mut self, msg: &str) {
+		let now = self.now();
+		self.out.text(now, msg);
+	}
+
+	fn record_warn(&mut self, user: &str, count: u32) {
+		let max = self.cfg.warn_max_count;
+		if let Some(sim) = &mut self.sim {
+			sim.warns.push(format!("{} ({count}/{max})", short_id(user)));
+		}
+	}
 }
 
 #[derive(Clone, Debug, Deserialize)]
 struct ReceiveEnvelope {
 	envelope: EnvelopeInner,
-	account: Option<String>,
+}
+
+impl ReceiveEnvelope {
+	fn group_id(&self) -> Option<&str> {
+		let gi = self.envelope.data_message.as_ref()?.group_info.as_ref()?;
+		Some(&gi.group_id)
+	}
+
+	fn sender_id(&self) -> Option<String> {
+		self.envelope
+			.source_uuid
+			.clone()
+			.or_else(|| self.envelope.source_number.clone())
+			.or_else(|| self.envelope.source.clone())
+	}
 }
 
 #[derive(Clone, Debug, Deserialize)]
@@ -112,40 +270,88 @@ struct DataMessage {
 	#[serde(rename = "groupInfo")]
 	group_info: Option<GroupInfo>,
 	quote: Option<Quote>,
-	#[serde(rename = "expiresInSeconds")]
-	expires_in_seconds: Option<u64>,
 }
 
 #[derive(Clone, Debug, Deserialize)]
 struct Quote {
 	author: Option<String>,
-	#[serde(rename = "id")]
-	id: Option<i64>,
-	text: Option<String>,
 }
 
 #[derive(Clone, Debug, Deserialize)]
 struct GroupInfo {
 	#[serde(rename = "groupId")]
 	group_id: String,
-	#[serde(rename = "groupName")]
-	group_name: Option<String>,
-	revision: Option<u64>,
 	#[serde(rename = "type")]
 	kind: String,
 }
 
 fn main() {
 	if let Err(e) = real_main() {
-		eprintln!("[ERR] {e:#}");
-		std::process::exit(1);
+		let code = cli::exit_code(&e);
+		let msg = format!("{e:#}");
+		// 消息为空：子命令已经输出过结果，只差退出码
+		if !msg.is_empty() {
+			if env::args().any(|a| a == "--json") {
+				cli::print_json(&serde_json::json!({ "ok": false, "code": code, "error": msg }));
+			} else {
+				eprintln!("[ERR] {msg}");
+			}
+		}
+		std::process::exit(code);
 	}
 }
 
 fn real_main() -> Result<()> {
 	ensure_default_path();
+	// --state-dir/--run-dir/--log-dir/--systemd-unit/--user 可以放在任意位置，解析完就去掉
+	let args = paths::init(env::args().collect());
+	// 以 root 启动时切到服务用户：守护进程彻底降权，TUI/CLI 只在装依赖、装 unit 时临时切回 root。
+	// --user 模式全程是当前用户
+	crypto::preload();
+	if !paths::user_mode() {
+		if args.len() >= 2 && args[1] == "--daemon" {
+			privs::drop_to_service_user()?;
+		} else {
+			privs::enter_service_user()?;
+		}
+	}
 	ensure_dirs()?;
-	let args: Vec<String> = env::args().collect();
+	if has_flag(&args, "--replay") {
+		return replay::run_replay(&args);
+	}
+	if args.len() >= 2 && args[1] == "group" {
+		return cli::group_cli(&args);
+	}
+	if args.len() >= 2 && args[1] == "rules" {
+		return cli::rules_cli(&args);
+	}
+	if args.len() >= 2 && args[1] == "systemd" {
+		return cli::systemd_cli(&args);
+	}
+	if args.len() >= 2 && args[1] == "captcha" {
+		return cli::captcha_cli(&args);
+	}
+	if args.len() >= 2 && args[1] == "logout" {
+		return cli::logout_cli(&args);
+	}
+	if args.len() >= 2 && args[1] == "config" {
+		return declarative::config_cli(&args);
+	}
+	if args.len() >= 2 && args[1] == "backup" {
+		return backup::backup_cli(&args);
+	}
+	if args.len() >= 2 && args[1] == "restore" {
+		return backup::restore_cli(&args);
+	}
+	if args.len() >= 2 && args[1] == "crypt" {
+		return crypto::crypt_cli(&args);
+	}
+	if args.len() >= 2 && args[1] == "outbox" {
+		return outbox_cli(&args);
+	}
+	if args.len() >= 2 && args[1] == "cache" {
+		return cache_cli(&args);
+	}
 	if args.len() >= 2 && args[1] == "--daemon" {
 		let gc = load_global()?;
 		let acc = gc
@@ -159,6 +365,18 @@ fn real_main() -> Result<()> {
 	Ok(())
 }
 
+fn has_flag(args: &[String], name: &str) -> bool {
+	args.iter().any(|a| a == name)
+}
+
+fn flag_value(args: &[String], name: &str) -> Option<String> {
+	flag_values(args, name).into_iter().next()
+}
+
+fn flag_values(args: &[String], name: &str) -> Vec<String> {
+	args.windows(2).filter(|w| w[0] == name).map(|w| w[1].clone()).collect()
+}
+
 // PATCH 1: 确保 /usr/local/bin 在 PATH（你现场 root PATH 没它，导致 magicbot 内部永远找不到 signal-cli）
 fn ensure_default_path() {
 	let mut p = env::var("PATH").unwrap_or_default();
@@ -176,20 +394,20 @@ fn ensure_default_path() {
 }
 
 fn ensure_dirs() -> Result<()> {
-	for d in [STATE_DIR, RUN_DIR, LOG_DIR] {
-		if !Path::new(d).exists() {
-			fs::create_dir_all(d).with_context(|| format!("create dir {d}"))?;
+	for d in [paths::state_dir(), paths::run_dir(), paths::log_dir()] {
+		if !d.exists() {
+			fs::create_dir_all(d).with_context(|| format!("create dir {}", d.display()))?;
 		}
 	}
 	Ok(())
 }
 
 fn global_path() -> PathBuf {
-	PathBuf::from(STATE_DIR).join("global.json")
+	paths::state_dir().join("global.json")
 }
 
 fn groups_dir() -> PathBuf {
-	PathBuf::from(STATE_DIR).join("groups")
+	paths::state_dir().join("groups")
 }
 
 fn group_cfg_path(gid: &str) -> PathBuf {
@@ -197,34 +415,34 @@ fn group_cfg_path(gid: &str) -> PathBuf {
 }
 
 fn group_mark_dir(gid: &str) -> PathBuf {
-	PathBuf::from(STATE_DIR).join("marks").join(gid)
+	paths::state_dir().join("marks").join(gid)
 }
 
 fn load_global() -> Result<GlobalConfig> {
 	let p = global_path();
 	if !p.exists() {
-		let gc = GlobalConfig {
-			installed_at: Utc::now().timestamp(),
-			account: None,
-			signal_cli_config_dir: None,
-			selected_group: None,
-			daemon_enabled: false,
-		};
+		let gc = GlobalConfig::default();
 		save_global(&gc)?;
 		return Ok(gc);
 	}
-	let mut s = String::new();
-	File::open(&p)?.read_to_string(&mut s)?;
-	let gc: GlobalConfig = serde_json::from_str(&s)?;
+	let s = crypto::read_to_string(&p)?;
+	let mut v: Value = serde_json::from_str(&s).with_context(|| format!("parse {}", p.display()))?;
+	let upgraded = schema::upgrade(&schema::GLOBAL, "global", &p, &mut v)?;
+	let gc: GlobalConfig = serde_json::from_value(v).with_context(|| format!("parse {}", p.display()))?;
+	if upgraded {
+		save_global(&gc)?;
+	}
 	Ok(gc)
 }
 
 fn save_global(gc: &GlobalConfig) -> Result<()> {
 	let p = global_path();
 	let tmp = p.with_extension("json.tmp");
-	fs::write(&tmp, serde_json::to_vec_pretty(gc)?)?;
-	fs::rename(tmp, p)?;
-	Ok(())
+	lock::with_dir_lock(paths::state_dir(), || {
+		crypto::write(&tmp, &serde_json::to_vec_pretty(gc)?)?;
+		fs::rename(&tmp, &p)?;
+		Ok(())
+	})
 }
 
 fn load_group_cfg(gid: &str) -> Result<GroupConfig> {
@@ -232,37 +450,58 @@ fn load_group_cfg(gid: &str) -> Result<GroupConfig> {
 	if !p.exists() {
 		return Ok(GroupConfig {
 			group_id: gid.to_string(),
-			group_name: String::new(),
-			enabled: false,
-			only_admin_can_ban: true,
-			require_bot_admin_to_enforce: true,
-			welcome_template: None,
-			auto_replies: vec![],
-			warn_rules: vec![],
-			ban_rules: vec![],
-			warn_window_minutes: 10,
-			warn_max_count: 3,
-			warn_message: "警告：请停止违规内容，否则将被移出群组。".to_string(),
-			desired_permission_add_member: "EVERY_MEMBER".to_string(),
-			desired_permission_send_message: "EVERY_MEMBER".to_string(),
-			desired_permission_edit_details: "ONLY_ADMINS".to_string(),
-			last_members_snapshot: BTreeSet::new(),
-			bot_has_admin: false,
+			..GroupConfig::default()
 		});
 	}
-	let mut s = String::new();
-	File::open(&p)?.read_to_string(&mut s)?;
-	let cfg: GroupConfig = serde_json::from_str(&s)?;
+	let s = crypto::read_to_string(&p)?;
+	let mut v: Value = serde_json::from_str(&s).with_context(|| format!("parse {}", p.display()))?;
+	// 旧格式里的运行时字段搬到 state/<gid>.json
+	let legacy = state::legacy_state(&v);
+	let upgraded = schema::upgrade(&schema::GROUP, gid, &p, &mut v)?;
+	if upgraded {
+		if let Some(st) = &legacy {
+			state::migrate_legacy(gid, st)?;
+		}
+	}
+	let cfg: GroupConfig = serde_json::from_value(v).with_context(|| format!("parse {}", p.display()))?;
+	if upgraded {
+		save_group_cfg(&cfg)?;
+	}
 	Ok(cfg)
 }
 
+// 热加载：配置文件只有运维字段，直接换掉；运行时状态在 rt.state 里不受影响。返回配置是否真的变了
+fn reload_group_cfg(rt: &mut GroupRuntime) -> Result<bool> {
+	let disk = load_group_cfg(&rt.cfg.group_id)?;
+	if serde_json::to_value(&disk)? == serde_json::to_value(&rt.cfg)? {
+		return Ok(false);
+	}
+	rt.cfg = disk;
+	Ok(true)
+}
+
+// groups/ 下所有配置文件里的 group_id
+fn list_group_cfg_ids() -> Vec<String> {
+	let Ok(rd) = fs::read_dir(groups_dir()) else {
+		return vec![];
+	};
+	rd.flatten()
+		.filter(|e| e.path().extension().is_some_and(|x| x == "json"))
+		.filter_map(|e| crypto::read_to_string(&e.path()).ok())
+		.filter_map(|s| serde_json::from_str::<GroupConfig>(&s).ok())
+		.map(|c| c.group_id)
+		.filter(|g| !g.is_empty())
+		.collect()
+}
+
 fn save_group_cfg(cfg: &GroupConfig) -> Result<()> {
-	fs::create_dir_all(groups_dir())?;
 	let p = group_cfg_path(&cfg.group_id);
 	let tmp = p.with_extension("json.tmp");
-	fs::write(&tmp, serde_json::to_vec_pretty(cfg)?)?;
-	fs::rename(tmp, p)?;
-	Ok(())
+	lock::with_dir_lock(&groups_dir(), || {
+		crypto::write(&tmp, &serde_json::to_vec_pretty(cfg)?)?;
+		fs::rename(&tmp, &p)?;
+		Ok(())
+	})
 }
 
 fn theme() -> ColorfulTheme {
@@ -300,7 +539,7 @@ fn show_menu() -> Result<()> {
 			.interact()?;
 
 		match sel {
-			0 => install_deps()?,
+			0 => privs::as_root(install_deps)?,
 			1 => login_linkdevice(&mut gc)?,
 			2 => register_sms_flow(&mut gc)?,
 			3 => select_group(&mut gc)?,
@@ -329,12 +568,11 @@ fn install_deps() -> Result<()> {
 	Ok(())
 }
 
-// PATCH 2: linkdevice 不再 cmd.output() 死等退出，改为 timeout + 正则抓 URI；并追加 PNG 兜底二维码
+// PATCH 2: linkdevice 不再 cmd.output() 死等退出：边读输出边处理，拿到 URI 立刻出二维码，
+// 子进程留着等手机扫码，看到 "Associated with" 即绑定成功；超过 link_timeout_secs 才放弃
 fn login_linkdevice(gc: &mut GlobalConfig) -> Result<()> {
-	require_root()?;
 	ensure_cmd("signal-cli")?;
 	ensure_cmd("qrencode")?;
-	ensure_cmd("timeout")?; // coreutils timeout
 
 	let cfgdir = Input::<String>::with_theme(&theme())
 		.with_prompt("signal-cli --config 目录(留空用默认)")
@@ -351,56 +589,110 @@ fn login_linkdevice(gc: &mut GlobalConfig) -> Result<()> {
 		.interact_text()?;
 	let name = if name.trim().is_empty() { "magicbot".to_string() } else { name };
 
-	// 用 timeout 包一层：只要拿到 URI 就继续（即使 signal-cli 不退出）
-	let mut cmd = Command::new("timeout");
-	cmd.arg("15s").arg("signal-cli");
+	let mut cmd = Command::new("signal-cli");
 	if let Some(dir) = &gc.signal_cli_config_dir {
 		cmd.arg("--config").arg(dir);
 	}
 	cmd.arg("link").arg("-n").arg(&name);
+	cmd.stdin(Stdio::null()).stdout(Stdio::piped()).stderr(Stdio::piped());
+	// TUI 被 Ctrl+C 或异常退出时 signal-cli 跟着退出，不留下占着账号目录的 link 进程
+	proc::supervise_child(&mut cmd);
+	let mut child = cmd.spawn().context("spawn signal-cli link")?;
+
+	// signal-cli 的 INFO 可能在 stderr；URI 可能在 stdout：两边按行汇到一处
+	let (tx, rx) = mpsc::channel::<String>();
+	let stdout = child.stdout.take().map(|o| Box::new(o) as Box<dyn Read + Send>);
+	let stderr = child.stderr.take().map(|e| Box::new(e) as Box<dyn Read + Send>);
+	for r in [stdout, stderr].into_iter().flatten() {
+		let tx = tx.clone();
+		thread::spawn(move || {
+			for line in BufReader::new(r).lines() {
+				let Ok(line) = line else { break };
+				if tx.send(line).is_err() {
+					break;
+				}
+			}
+		});
+	}
+	drop(tx);
 
-	let out = cmd.output().context("signal-cli link (timeout wrapper) failed")?;
-
-	// signal-cli 的 INFO 可能在 stderr；URI 可能在 stdout
-	let mut combined = String::new();
-	combined.push_str(&String::from_utf8_lossy(&out.stdout));
-	combined.push('\n');
-	combined.push_str(&String::from_utf8_lossy(&out.stderr));
-
-	let uri = extract_linkdevice_uri(&combined).ok_or_else(|| {
-		anyhow!(
-			"signal-cli link did not produce linkdevice URI. status={:?} stdout={} stderr={}",
-			out.status.code(),
-			String::from_utf8_lossy(&out.stdout),
-			String::from_utf8_lossy(&out.stderr)
-		)
-	})?;
-
-	println!("\n[OK] Link URI:\n{uri}\n");
-	std::io::stdout().flush().ok();
-
-	println!("[INF] QRCode(ANSI):\n");
-	let _ = run_ok(Command::new("qrencode").arg("-t").arg("ANSIUTF8").arg(&uri));
-
-	// PNG 兜底：ANSI 在部分终端会看不到/乱码
-	let png = PathBuf::from(RUN_DIR).join("linkdevice.png");
-	let _ = fs::create_dir_all(RUN_DIR);
-	let _ = run_ok(
-		Command::new("qrencode")
-			.arg("-o")
-			.arg(&png)
-			.arg(&uri),
-	);
-	println!("\n[INF] PNG QRCode(兜底): {}", png.display());
-	std::io::stdout().flush().ok();
+	if gc.link_timeout_secs < MIN_LINK_TIMEOUT_SECS {
+		println!(
+			"[WRN] link_timeout_secs={} 太短，按 {MIN_LINK_TIMEOUT_SECS} 秒等待",
+			gc.link_timeout_secs
+		);
+	}
+	let timeout = Duration::from_secs(gc.link_timeout_secs.max(MIN_LINK_TIMEOUT_SECS));
+	let deadline = Instant::now() + timeout;
+	let mut uri = None;
+	let mut linked = None;
+	let mut tail: Vec<String> = vec![];
+	loop {
+		// 已经绑定上了：signal-cli 还要同步联系人/群，不再计时，等它自己退出
+		let r = if linked.is_some() {
+			rx.recv().map_err(|_| RecvTimeoutError::Disconnected)
+		} else {
+			let left = deadline.saturating_duration_since(Instant::now());
+			if left.is_zero() {
+				proc::terminate(&mut child);
+				return Err(anyhow!(
+					"{}s 内没有完成绑定，已停止 signal-cli link（可调大 global.json 的 link_timeout_secs）",
+					timeout.as_secs()
+				));
+			}
+			rx.recv_timeout(left)
+		};
+		match r {
+			Ok(line) => {
+				if uri.is_none() {
+					if let Some(u) = extract_linkdevice_uri(&line) {
+						show_link_qr(&u);
+						println!(
+							"\n[INF] 在手机 Signal → 设置 → 已链接的设备 中扫码（{} 秒内有效），等待绑定完成...",
+							timeout.as_secs()
+						);
+						uri = Some(u);
+						continue;
+					}
+				}
+				if let Some(acc) = extract_linked_account(&line) {
+					linked = Some(acc);
+				}
+				if tail.len() >= 5 {
+					tail.remove(0);
+				}
+				tail.push(line);
+			}
+			Err(RecvTimeoutError::Timeout) => {}
+			// 两个管道都关了：signal-cli 已经（或正要）退出
+			Err(RecvTimeoutError::Disconnected) => break,
+		}
+	}
+	let status = child.wait().context("wait signal-cli link")?;
+	if uri.is_none() {
+		return Err(anyhow!(
+			"signal-cli link did not produce linkdevice URI. status={:?} output={}",
+			status.code(),
+			tail.join(" | ")
+		));
+	}
+	if !status.success() && linked.is_none() {
+		return Err(anyhow!("绑定失败 (status={:?}): {}", status.code(), tail.join(" | ")));
+	}
+	let _ = fs::remove_file(paths::run_dir().join("linkdevice.png"));
 
-	println!("\n[INF] 绑定完成后，需要在本机选择一个账号进行后续操作。");
-	println!("[INF] 如果你是“链接设备”，账号由主设备决定；一般无需输入手机号。");
-	println!("[INF] 现在尝试自动发现本机已有账号...\n");
+	if let Some(acc) = linked {
+		gc.account = Some(acc.clone());
+		save_global(gc)?;
+		println!("[OK] 绑定完成，当前账号 = {acc}");
+		return Ok(());
+	}
 
+	// 旧版 signal-cli 成功时不一定打印号码：退回到从本机账号里选
+	println!("[INF] 绑定已完成，但没有从输出中认出号码，从本机账号里选择...\n");
 	let accs = list_local_accounts(gc)?;
 	if accs.is_empty() {
-		println!("[WRN] 没发现账号。你可能还没在手机端完成绑定，或 config 目录不对。");
+		println!("[WRN] 没发现账号。config 目录可能不对。");
 		return Ok(());
 	}
 
@@ -415,6 +707,24 @@ fn login_linkdevice(gc: &mut GlobalConfig) -> Result<()> {
 	Ok(())
 }
 
+fn show_link_qr(uri: &str) {
+	println!("\n[OK] Link URI:\n{uri}\n");
+	println!("[INF] QRCode(ANSI):\n");
+	let _ = Command::new("qrencode").arg("-t").arg("ANSIUTF8").arg(uri).status();
+
+	// PNG 兜底：ANSI 在部分终端会看不到/乱码
+	let png = paths::run_dir().join("linkdevice.png");
+	let _ = fs::create_dir_all(paths::run_dir());
+	let _ = run_ok(
+		Command::new("qrencode")
+			.arg("-o")
+			.arg(&png)
+			.arg(uri),
+	);
+	println!("\n[INF] PNG QRCode(兜底): {}", png.display());
+	std::io::stdout().flush().ok();
+}
+
 fn extract_linkdevice_uri(s: &str) -> Option<String> {
 	// 例：sgnl://linkdevice?uuid=...&pub_key=...
 	// stdout/stderr 可能混杂其他文本，所以用 regex 取整段
@@ -422,8 +732,13 @@ fn extract_linkdevice_uri(s: &str) -> Option<String> {
 	re.captures(s).map(|c| c[1].to_string())
 }
 
+// 绑定成功时 signal-cli 输出 "Associated with: +4915..."
+fn extract_linked_account(s: &str) -> Option<String> {
+	let re = Regex::new(r"Associated with:?\s*(\+\d{6,20})").ok()?;
+	re.captures(s).map(|c| c[1].to_string())
+}
+
 fn register_sms_flow(gc: &mut GlobalConfig) -> Result<()> {
-	require_root()?;
 	ensure_cmd("signal-cli")?;
 
 	let phone = Input::<String>::with_theme(&theme())
@@ -481,7 +796,7 @@ fn register_sms_flow(gc: &mut GlobalConfig) -> Result<()> {
 
 fn select_group(gc: &mut GlobalConfig) -> Result<()> {
 	let acc = gc.account.clone().ok_or_else(|| anyhow!("未登录"))?;
-	let groups = list_groups(&acc, gc.signal_cli_config_dir.as_deref())?;
+	let groups = list_groups(&SubprocessBackend::new(&acc, gc.signal_cli_config_dir.as_deref()))?;
 	if groups.is_empty() {
 		return Err(anyhow!("No groups found. 先确保该账号已加入群。"));
 	}
@@ -495,19 +810,23 @@ fn select_group(gc: &mut GlobalConfig) -> Result<()> {
 		.items(&names)
 		.default(0)
 		.interact()?;
-	let gid = groups[idx].id.clone();
+	let cfg = apply_group_selection(gc, &groups[idx])?;
+	println!("[OK] 已选择群: {} ({})", cfg.group_name, cfg.group_id);
+	Ok(())
+}
 
-	let mut cfg = load_group_cfg(&gid)?;
-	cfg.group_name = groups[idx].name.clone();
+// 菜单和 magicbot group select 共用：记下群名、建好配置、设为当前群
+fn apply_group_selection(gc: &mut GlobalConfig, g: &GroupSummary) -> Result<GroupConfig> {
+	let mut cfg = load_group_cfg(&g.id)?;
+	cfg.group_name = g.name.clone();
 	if cfg.group_id.is_empty() {
-		cfg.group_id = gid.clone();
+		cfg.group_id = g.id.clone();
 	}
 	save_group_cfg(&cfg)?;
 
-	gc.selected_group = Some(gid.clone());
+	gc.selected_group = Some(g.id.clone());
 	save_global(gc)?;
-	println!("[OK] 已选择群: {} ({})", cfg.group_name, gid);
-	Ok(())
+	Ok(cfg)
 }
 
 fn group_settings_menu(gc: &mut GlobalConfig) -> Result<()> {
@@ -540,7 +859,11 @@ fn group_settings_menu(gc: &mut GlobalConfig) -> Result<()> {
 			"7. 违规词(增加/删除/清空)".to_string(),
 			"8. 警告策略(次数/窗口/警告文案)".to_string(),
 			"9. 接管策略: 当 Bot 被设为管理员后自动设置群权限".to_string(),
-			"10. 返回".to_string(),
+			format!(
+				"10. 外发限速/合并: 每分钟 {} 条, 突发 {}, 合并窗口 {} 秒",
+				cfg.throttle.per_minute, cfg.throttle.burst, cfg.throttle.coalesce_secs
+			),
+			"11. 返回".to_string(),
 		];
 
 		let idx = Select::with_theme(&theme())
@@ -621,12 +944,27 @@ fn group_settings_menu(gc: &mut GlobalConfig) -> Result<()> {
 				cfg.desired_permission_edit_details = normalize_perm(&edit);
 				save_group_cfg(&cfg)?;
 			}
-			9 => break,
+			9 => {
+				cfg.throttle.per_minute = Input::<u32>::with_theme(&theme())
+					.with_prompt("本群每分钟最多发送几条")
+					.default(cfg.throttle.per_minute)
+					.interact_text()?;
+				cfg.throttle.burst = Input::<u32>::with_theme(&theme())
+					.with_prompt("允许连续突发几条")
+					.default(cfg.throttle.burst)
+					.interact_text()?;
+				cfg.throttle.coalesce_secs = Input::<u64>::with_theme(&theme())
+					.with_prompt("欢迎语/警告合并窗口(秒, 0=不合并)")
+					.default(cfg.throttle.coalesce_secs)
+					.interact_text()?;
+				save_group_cfg(&cfg)?;
+			}
+			10 => break,
 			_ => {}
 		}
 	}
 
-	println!("[OK] 配置已保存。");
+	println!("[OK] 配置已保存。运行中的守护进程会自动加载（或 systemctl reload magicbot）。");
 	println!("[INF] 守护进程会在检测到 Bot 获得管理员权限时自动接管并设置群权限。");
 	println!("[INF] 现在可选：从主菜单启动守护(前台测试) 或 systemd 开机自启。");
 	Ok(())
@@ -634,7 +972,7 @@ fn group_settings_menu(gc: &mut GlobalConfig) -> Result<()> {
 
 fn keyword_group_reply_edit(mut cur: Vec<KeywordGroupReply>) -> Result<Vec<KeywordGroupReply>> {
 	loop {
-		let mut items = vec![
+		let items = vec![
 			"增加".to_string(),
 			"删除".to_string(),
 			"删除全部".to_string(),
@@ -671,14 +1009,12 @@ fn keyword_group_reply_edit(mut cur: Vec<KeywordGroupReply>) -> Result<Vec<Keywo
 					cur.remove(d);
 				}
 			}
-			2 => {
-				if Confirm::with_theme(&theme())
-					.with_prompt("确认删除全部自动回复？")
-					.default(false)
-					.interact()?
-				{
-					cur.clear();
-				}
+			2 if Confirm::with_theme(&theme())
+				.with_prompt("确认删除全部自动回复？")
+				.default(false)
+				.interact()? =>
+			{
+				cur.clear();
 			}
 			3 => break,
 			_ => {}
@@ -722,14 +1058,12 @@ fn keyword_group_simple_edit_warn(mut cur: Vec<KeywordGroupWarn>) -> Result<Vec<
 					cur.remove(d);
 				}
 			}
-			2 => {
-				if Confirm::with_theme(&theme())
-					.with_prompt("确认删除全部警告词？")
-					.default(false)
-					.interact()?
-				{
-					cur.clear();
-				}
+			2 if Confirm::with_theme(&theme())
+				.with_prompt("确认删除全部警告词？")
+				.default(false)
+				.interact()? =>
+			{
+				cur.clear();
 			}
 			3 => break,
 			_ => {}
@@ -773,14 +1107,12 @@ fn keyword_group_simple_edit_ban(mut cur: Vec<KeywordGroupBan>) -> Result<Vec<Ke
 					cur.remove(d);
 				}
 			}
-			2 => {
-				if Confirm::with_theme(&theme())
-					.with_prompt("确认删除全部违规词？")
-					.default(false)
-					.interact()?
-				{
-					cur.clear();
-				}
+			2 if Confirm::with_theme(&theme())
+				.with_prompt("确认删除全部违规词？")
+				.default(false)
+				.interact()? =>
+			{
+				cur.clear();
 			}
 			3 => break,
 			_ => {}
@@ -834,30 +1166,73 @@ fn list_reply_groups(cur: &[KeywordGroupReply]) -> Vec<String> {
 		.collect()
 }
 
-fn normalize_perm(s: &str) -> String {
-	let u = s.trim().to_uppercase();
+// 认不出来的返回 None（配置校验、命令行都按错误处理）
+fn canonical_perm(s: &str) -> Option<String> {
+	let u = s.trim().to_uppercase().replace('-', "_");
 	match u.as_str() {
-		"EVERY_MEMBER" | "ONLY_ADMINS" => u,
-		"EVERY-MEMBER" => "EVERY_MEMBER".to_string(),
-		"ONLY-ADMINS" => "ONLY_ADMINS".to_string(),
-		_ => "EVERY_MEMBER".to_string(),
+		"EVERY_MEMBER" | "ONLY_ADMINS" => Some(u),
+		_ => None,
 	}
 }
 
+fn normalize_perm(s: &str) -> String {
+	canonical_perm(s).unwrap_or_else(|| {
+		println!("[WRN] 无法识别的权限 {s:?}，按 EVERY_MEMBER 处理");
+		"EVERY_MEMBER".to_string()
+	})
+}
+
 fn run_daemon_front(gc: &GlobalConfig) -> Result<()> {
 	let acc = gc
 		.account
 		.clone()
 		.ok_or_else(|| anyhow!("未登录"))?;
+	if let Some(pid) = lock::running_pid() {
+		println!("[WRN] 已有守护进程在运行 (pid {pid})，同时运行会重复回复。");
+		let stop = Confirm::with_theme(&theme())
+			.with_prompt("先停止 systemd 服务再前台运行？")
+			.default(true)
+			.interact()?;
+		if !stop {
+			return Ok(());
+		}
+		let unit = paths::unit_name();
+		unit_admin(|| run_ok(&mut systemctl(&["stop", &unit])))?;
+		if let Some(pid) = lock::running_pid() {
+			return Err(anyhow!("守护进程仍在运行 (pid {pid})，可能不是 systemd 启动的，请手动结束"));
+		}
+		let user = if paths::user_mode() { " --user" } else { "" };
+		println!("[OK] 已停止 {unit} 服务。前台测试结束后记得 systemctl{user} start {unit}。");
+	}
 	println!("[INF] 前台运行守护(按 Ctrl+C 退出) ...");
-	run_daemon(&acc)
+	let r = run_daemon(&acc);
+	signals::reset();
+	r
 }
 
 fn run_daemon(acc: &str) -> Result<()> {
 	ensure_cmd("signal-cli")?;
-	let mut gc = load_global()?;
+	// 同一账号只能有一个守护进程（systemd 的和前台测试的会互相抢消息、重复回复）
+	let _pid_lock = PidLock::acquire()?;
+	let gc = load_global()?;
+	crypto::log_status(gc.recorder.enabled)?;
+
+	alerts::configure(&gc.alerts);
+	// PATCH 4: 常驻一个 signal-cli jsonRpc，收消息/发消息/updateGroup/listGroups 全走同一连接；
+	// 启动失败才退回旧的 receive 子进程 + 每次调用起一个 JVM
+	// PATCH 5: 外发失败落盘到 STATE_DIR/outbox 重试，不再直接丢弃
+	// listGroups/listContacts 结果在所有群之间共享缓存
+	let backend = Outbox::new(
+		RateLimitGuard::new(Cached::new(
+			JsonRpcBackend::new(acc, gc.signal_cli_config_dir.as_deref()),
+			&gc.cache,
+		)),
+		&gc.outbox,
+	);
+	let mut events = backend.receive()?;
 
-	let (mut groups, self_id) = load_all_groups_runtime(acc, gc.signal_cli_config_dir.as_deref())?;
+	let account_bucket = throttle::account_bucket(&gc.throttle);
+	let (groups, self_id) = load_all_groups_runtime(&backend, acc, &account_bucket)?;
 	if groups.is_empty() {
 		return Err(anyhow!("No group configs found. 请先选择群组并保存配置。"));
 	}
@@ -865,57 +1240,244 @@ fn run_daemon(acc: &str) -> Result<()> {
 	println!("[INF] self_id = {self_id}");
 	println!("[INF] watching {} group(s)", groups.len());
 
-	let mut child = spawn_receive(acc, gc.signal_cli_config_dir.as_deref())?;
-	let stdout = child.stdout.take().ok_or_else(|| anyhow!("no stdout"))?;
-	let reader = BufReader::new(stdout);
+	let mut recorder = if gc.recorder.enabled {
+		println!("[INF] recording raw envelopes to {}", recorder::raw_dir().display());
+		Some(Recorder::open(&gc.recorder)?)
+	} else {
+		None
+	};
 
-	for line in reader.lines() {
-		let line = match line {
-			Ok(x) => x,
-			Err(_) => break,
-		};
-		let line = line.trim();
-		if line.is_empty() {
-			continue;
+	// PATCH 8: groups/ 下的配置被 TUI/运维改动（inotify）或收到 SIGHUP 时热加载
+	signals::install();
+	let watch = match ConfigWatch::new(&groups_dir()) {
+		Ok(w) => Some(w),
+		Err(e) => {
+			eprintln!("[WRN] config watch disabled ({e:#}); use SIGHUP to reload");
+			None
 		}
-		let ev: ReceiveEnvelope = match serde_json::from_str(line) {
-			Ok(v) => v,
-			Err(_) => continue,
-		};
+	};
 
-		if let Some(dm) = &ev.envelope.data_message {
-			if let Some(gi) = &dm.group_info {
-				let gid = gi.group_id.clone();
-				if !groups.contains_key(&gid) {
-					continue;
+	// PATCH 7: 本线程只负责读取/解析/分发，每个群一个工作线程处理事件和外发，
+	// listGroups 或一串发送只会拖慢本群
+	let started = Instant::now();
+	// PATCH 10: Type=notify：群加载完才算启动成功；看门狗只在接收循环在转、接收进程没卡住时喂
+	let mut watchdog = Watchdog::from_env();
+	if watchdog.enabled() {
+		println!("[INF] systemd watchdog enabled");
+	}
+	thread::scope(|s| -> Result<()> {
+		let mut workers = Workers::spawn(s, &backend, groups, &gc.workers, &gc.reconcile);
+
+		// 重试队列和 CLI 放下的 captcha 放在单独的线程里：一次 jsonRpc 调用最多等 60 秒，
+		// 不能卡住本线程的读取和喂狗。本函数返回时 _stop 被丢掉，线程随之退出
+		let (_stop, stop_rx) = mpsc::channel::<()>();
+		let b = &backend;
+		s.spawn(move || {
+			while let Err(RecvTimeoutError::Timeout) = stop_rx.recv_timeout(DAEMON_TICK) {
+				b.inner().poll_submission();
+				b.drive();
+			}
+		});
+		sdnotify::notify("READY=1");
+		let mut status = String::new();
+		let mut last_event: Option<i64> = None;
+		let mut receiver_ok = true;
+
+		// 接收进程退出时就地重启（指数退避），GroupRuntime 在工作线程里不丢
+		let mut supervisor = Supervisor::new(&gc.supervisor);
+		let mut last_tick = Instant::now();
+		loop {
+			supervisor.started();
+			loop {
+				let r = events.recv_timeout(DAEMON_TICK);
+				if signals::shutdown_requested() {
+					break;
+				}
+				if last_tick.elapsed() >= DAEMON_TICK {
+					last_tick = Instant::now();
+					workers.report();
+					let hup = signals::take_reload();
+					if hup {
+						println!("[INF] SIGHUP: reloading group configs");
+					}
+					if hup || watch.as_ref().is_some_and(|w| w.changed()) {
+						rescan_group_cfgs(&backend, &mut workers, &self_id, &account_bucket);
+					}
+					if signals::take_dump() {
+						dump_daemon_state(&backend, &workers, started);
+					}
+
+					let healthy = backend.receiver_healthy();
+					if healthy {
+						watchdog.ping();
+					}
+					if healthy != receiver_ok {
+						receiver_ok = healthy;
+						if healthy {
+							println!("[INF] signal-cli receiver is responding again");
+						} else {
+							eprintln!("[WRN] signal-cli receiver looks stuck, withholding watchdog pings");
+						}
+					}
+					let st = daemon_status(workers.gids().len(), last_event, healthy);
+					if st != status {
+						sdnotify::notify(&format!("STATUS={st}"));
+						status = st;
+					}
+				}
+				match r {
+					Ok(line) => {
+						last_event = Some(Utc::now().timestamp());
+						intake_line(&line, &mut recorder, &workers);
+					}
+					Err(RecvTimeoutError::Timeout) => continue,
+					Err(RecvTimeoutError::Disconnected) => break,
 				}
-				let mut rt = groups.get(&gid).cloned().unwrap();
-				handle_group_event(acc, &gc, &mut rt, &ev, dm, gi)?;
-				groups.insert(gid, rt);
 			}
+			if signals::shutdown_requested() {
+				break;
+			}
+
+			let mut reason = backend
+				.receiver_exit_reason()
+				.unwrap_or_else(|| "stdout closed".to_string());
+			events = loop {
+				let wait = supervisor.exited(&reason)?;
+				sdnotify::notify(&format!("STATUS=signal-cli receiver restarting in {}s", wait.as_secs()));
+				status.clear();
+				if !backoff_wait(wait, &mut watchdog) {
+					break events;
+				}
+				match backend.receive() {
+					Ok(rx) => break rx,
+					Err(e) => reason = format!("respawn failed: {e:#}"),
+				}
+			};
+			if signals::shutdown_requested() {
+				break;
+			}
+			println!("[INF] signal-cli receiver restarted");
 		}
-	}
 
-	let _ = child.kill();
+		// PATCH 9: 优雅退出：不再接收新事件，已读到的分发完；工作线程处理完积压、
+		// 清空待发消息（发不出去的进重试队列）并写最后一次状态后退出
+		println!("[INF] shutting down ...");
+		sdnotify::notify("STOPPING=1");
+		let mut drained = 0;
+		while let Ok(line) = events.try_recv() {
+			intake_line(&line, &mut recorder, &workers);
+			drained += 1;
+		}
+		if drained > 0 {
+			println!("[INF] dispatched {drained} buffered event(s)");
+		}
+		workers.close();
+		Ok(())
+	})?;
+
+	backend.shutdown();
+	let queued = outbox::load_queue().map(|q| q.len()).unwrap_or(0);
+	println!("[OK] magicbot stopped ({queued} outbound action(s) left in the retry queue)");
 	Ok(())
 }
 
-fn spawn_receive(acc: &str, cfgdir: Option<&str>) -> Result<Child> {
-	let mut cmd = Command::new("signal-cli");
-	if let Some(d) = cfgdir {
-		cmd.arg("--config").arg(d);
+fn daemon_status(groups: usize, last_event: Option<i64>, healthy: bool) -> String {
+	let last = match last_event.and_then(|t| Local.timestamp_opt(t, 0).single()) {
+		Some(t) => t.format("%Y-%m-%d %H:%M:%S").to_string(),
+		None => "none yet".to_string(),
+	};
+	let stuck = if healthy { "" } else { "; receiver not responding" };
+	format!("watching {groups} group(s); last event {last}{stuck}")
+}
+
+// 接收进程重启前的退避：守护进程本身没卡住，照常喂狗
+fn backoff_wait(d: Duration, watchdog: &mut Watchdog) -> bool {
+	let until = Instant::now() + d;
+	loop {
+		watchdog.ping();
+		let left = until.saturating_duration_since(Instant::now());
+		if left.is_zero() {
+			return true;
+		}
+		if !signals::sleep_unless_shutdown(left.min(DAEMON_TICK)) {
+			return false;
+		}
+	}
+}
+
+fn intake_line(line: &str, recorder: &mut Option<Recorder>, workers: &Workers) {
+	let line = line.trim();
+	if line.is_empty() {
+		return;
+	}
+	let ev: ReceiveEnvelope = match serde_json::from_str(line) {
+		Ok(v) => v,
+		Err(e) => {
+			eprintln!("[WRN] unparsable receive line: {e}");
+			if let Some(r) = recorder {
+				r.reject(line, &e.to_string());
+			}
+			return;
+		}
+	};
+	if let Some(r) = recorder {
+		r.record(line, &ev);
+	}
+	workers.dispatch(ev);
+}
+
+// SIGUSR1：全局状态 + 每群队列，工作线程各自再打一行本群的运行时状态
+fn dump_daemon_state(backend: &dyn Backend, workers: &Workers, started: Instant) {
+	let queued = outbox::load_queue().map(|q| q.len()).unwrap_or(0);
+	let dead = outbox::load_dead().map(|q| q.len()).unwrap_or(0);
+	println!(
+		"[INF] dump: pid={} uptime={}s groups={} outbox queued={queued} dead={dead} outbound_paused={}",
+		std::process::id(),
+		started.elapsed().as_secs(),
+		workers.gids().len(),
+		backend.outbound_paused(),
+	);
+	workers.dump();
+}
+
+// 已有的群让工作线程合并新配置；新出现的配置起一个工作线程；被删掉的停掉
+fn rescan_group_cfgs(
+	backend: &dyn Backend,
+	workers: &mut Workers,
+	self_id: &str,
+	account_bucket: &SharedAccountBucket,
+) {
+	let on_disk = list_group_cfg_ids();
+	for gid in workers.gids() {
+		if !on_disk.contains(&gid) {
+			workers.stop(&gid);
+		}
+	}
+	for gid in on_disk {
+		if workers.contains(&gid) {
+			workers.reload(&gid);
+			continue;
+		}
+		match load_group_runtime(backend, &gid, self_id, account_bucket) {
+			Ok(rt) => workers.start(rt),
+			Err(e) => eprintln!("[WRN] new group config {}: {e:#}", short_id(&gid)),
+		}
 	}
-	cmd.arg("-u").arg(acc).arg("-o").arg("json").arg("receive");
-	cmd.arg("-t").arg("-1");
-	cmd.arg("--ignore-attachments");
-	cmd.stdout(Stdio::piped()).stderr(Stdio::piped());
-	let child = cmd.spawn().context("spawn signal-cli receive")?;
-	Ok(child)
+}
+
+fn dispatch_envelope(backend: &dyn Backend, rt: &mut GroupRuntime, ev: &ReceiveEnvelope) -> Result<()> {
+	if let Some(dm) = &ev.envelope.data_message {
+		if let Some(gi) = &dm.group_info {
+			let r = handle_group_event(backend, rt, ev, dm, gi);
+			flush_outgoing(backend, rt, false);
+			r?;
+		}
+	}
+	Ok(())
 }
 
 fn handle_group_event(
-	acc: &str,
-	gc: &GlobalConfig,
+	backend: &dyn Backend,
 	rt: &mut GroupRuntime,
 	ev: &ReceiveEnvelope,
 	dm: &DataMessage,
@@ -924,42 +1486,14 @@ fn handle_group_event(
 	let gid = rt.cfg.group_id.clone();
 
 	if gi.kind == "UPDATE" {
-		let prev_admin = rt.cfg.bot_has_admin;
-
-		refresh_group_state(acc, gc.signal_cli_config_dir.as_deref(), rt)?;
-
-		let now_admin = rt.cfg.bot_has_admin;
+		backend.invalidate(CacheScope::Groups);
+		refresh_group_state(backend, rt)?;
 
-		if now_admin && rt.cfg.enabled {
-			if rt.cfg.require_bot_admin_to_enforce {
-				apply_takeover_permissions(acc, gc.signal_cli_config_dir.as_deref(), rt)?;
-			}
-		}
-
-		let prev = rt.cfg.last_members_snapshot.clone();
-		let cur = rt.members.clone();
-		let added: Vec<String> = cur.difference(&prev).cloned().collect();
-		if !added.is_empty() {
-			rt.cfg.last_members_snapshot = cur.clone();
-			save_group_cfg(&rt.cfg)?;
-
-			if let Some(tpl) = &rt.cfg.welcome_template {
-				for uid in added {
-					let name = rt
-						.member_names
-						.get(&uid)
-						.cloned()
-						.unwrap_or_else(|| short_id(&uid));
-					let msg = tpl.replace("##{@user}##", &name);
-					let _ = send_group_message(acc, gc.signal_cli_config_dir.as_deref(), &gid, &msg);
-				}
-			}
-		} else {
-			rt.cfg.last_members_snapshot = cur.clone();
-			save_group_cfg(&rt.cfg)?;
+		if rt.state.bot_has_admin && rt.cfg.enabled && rt.cfg.require_bot_admin_to_enforce {
+			apply_takeover_permissions(backend, rt)?;
 		}
 
-		let _ = prev_admin;
+		sync_member_snapshot(rt)?;
 		return Ok(());
 	}
 
@@ -971,38 +1505,22 @@ fn handle_group_event(
 		return Ok(());
 	}
 
-	let sender_id = ev
-		.envelope
-		.source_uuid
-		.clone()
-		.or_else(|| ev.envelope.source_number.clone())
-		.or_else(|| ev.envelope.source.clone())
-		.unwrap_or_else(|| "unknown".to_string());
+	let sender_id = ev.sender_id().unwrap_or_else(|| "unknown".to_string());
 
 	let sender_is_admin = rt.admins.contains(&sender_id);
 	let bot_can_enforce = if rt.cfg.require_bot_admin_to_enforce {
-		rt.cfg.bot_has_admin
+		rt.state.bot_has_admin
 	} else {
 		true
 	};
 
 	if is_ban_command(&text) {
 		if rt.cfg.only_admin_can_ban && !sender_is_admin {
-			let _ = send_group_message(
-				acc,
-				gc.signal_cli_config_dir.as_deref(),
-				&gid,
-				"无权限：仅管理员可执行 /ban。",
-			);
+			rt.say("无权限：仅管理员可执行 /ban。");
 			return Ok(());
 		}
 		if !bot_can_enforce {
-			let _ = send_group_message(
-				acc,
-				gc.signal_cli_config_dir.as_deref(),
-				&gid,
-				"Bot 无管理员权限，已暂停踢人/警告。",
-			);
+			rt.say("Bot 无管理员权限，已暂停踢人/警告。");
 			return Ok(());
 		}
 
@@ -1017,26 +1535,17 @@ fn handle_group_event(
 		}
 
 		let Some(t) = target else {
-			let _ = send_group_message(
-				acc,
-				gc.signal_cli_config_dir.as_deref(),
-				&gid,
-				"用法：回复目标消息发送 /ban@magicbot 或 /ban@magicbot <uuid/号码>。",
-			);
+			rt.say(&format!("用法：回复目标消息发送 /ban@{BOT_NAME} 或 /ban@{BOT_NAME} <uuid/号码>。"));
 			return Ok(());
 		};
 
-		match remove_member(acc, gc.signal_cli_config_dir.as_deref(), &gid, &t) {
+		match backend.update_group(&gid, &GroupUpdate::remove_member(&t)) {
 			Ok(_) => {
-				let _ = send_group_message(acc, gc.signal_cli_config_dir.as_deref(), &gid, "已移出群组。");
+				rt.say("已移出群组。");
 			}
 			Err(e) => {
-				let _ = send_group_message(
-					acc,
-					gc.signal_cli_config_dir.as_deref(),
-					&gid,
-					&format!("踢人失败：{e}"),
-				);
+				eprintln!("[WRN] {gid}: kick {t} failed: {e:#}");
+				rt.say(&kick_failed_message(&e));
 			}
 		}
 		return Ok(());
@@ -1048,29 +1557,37 @@ fn handle_group_event(
 
 	if !bot_can_enforce && (hit_any_rule(&rt.cfg.warn_rules, &text) || hit_any_rule_ban(&rt.cfg.ban_rules, &text))
 	{
-		let _ = send_group_message(acc, gc.signal_cli_config_dir.as_deref(), &gid, "Bot 无管理员权限，已暂停踢人/警告。");
+		rt.say("Bot 无管理员权限，已暂停踢人/警告。");
 		return Ok(());
 	}
 
 	if bot_can_enforce && hit_any_rule_ban(&rt.cfg.ban_rules, &text) {
-		let _ = remove_member(acc, gc.signal_cli_config_dir.as_deref(), &gid, &sender_id);
-		clear_warn_mark(&gid, &sender_id)?;
+		let _ = backend.update_group(&gid, &GroupUpdate::remove_member(&sender_id));
+		clear_warn_mark(rt, &sender_id)?;
 		return Ok(());
 	}
 
 	if bot_can_enforce && hit_any_rule(&rt.cfg.warn_rules, &text) {
-		let kicked = warn_and_maybe_kick(acc, gc.signal_cli_config_dir.as_deref(), rt, &sender_id)?;
+		let kicked = warn_and_maybe_kick(backend, rt, &sender_id)?;
 		if kicked {
-			let _ = send_group_message(acc, gc.signal_cli_config_dir.as_deref(), &gid, "已因多次警告移出群组。");
+			rt.say("已因多次警告移出群组。");
 		} else {
-			let _ = send_group_message(acc, gc.signal_cli_config_dir.as_deref(), &gid, &rt.cfg.warn_message);
+			let now = rt.now();
+			let name = rt
+				.member_names
+				.get(&sender_id)
+				.cloned()
+				.unwrap_or_else(|| short_id(&sender_id));
+			let msg = rt.cfg.warn_message.clone();
+			rt.out.warn(now, &msg, &name);
 		}
 		return Ok(());
 	}
 
 	for r in &rt.cfg.auto_replies {
 		if keywords_match(&r.keywords, &text) {
-			let _ = send_group_message(acc, gc.signal_cli_config_dir.as_deref(), &gid, &r.reply);
+			let reply = r.reply.clone();
+			rt.say(&reply);
 			break;
 		}
 	}
@@ -1078,9 +1595,28 @@ fn handle_group_event(
 	Ok(())
 }
 
+// 进了外发重试队列只告诉群里稍后重试，具体错误留在日志
+fn kick_failed_message(e: &anyhow::Error) -> String {
+	match e.downcast_ref::<outbox::Queued>() {
+		Some(q) => format!("踢人暂未成功，{q}，稍后自动重试。"),
+		None => format!("踢人失败：{e}"),
+	}
+}
+
+// 欢迎/警告/回复先进 rt.out，这里按令牌桶和合并窗口真正发出
+fn flush_outgoing(backend: &dyn Backend, rt: &mut GroupRuntime, force: bool) {
+	if rt.out.is_empty() {
+		return;
+	}
+	let now = rt.now();
+	for msg in rt.out.due(now, &rt.cfg.throttle, force) {
+		let _ = backend.send_group_message(&rt.cfg.group_id, &msg);
+	}
+}
+
 fn is_ban_command(s: &str) -> bool {
 	let t = s.trim();
-	t.starts_with("/ban") || t.starts_with("/ban@") || t.contains("/ban@magicbot")
+	t.starts_with("/ban") || t.contains(&format!("/ban@{BOT_NAME}"))
 }
 
 fn extract_target_from_text(s: &str) -> Option<String> {
@@ -1121,101 +1657,144 @@ fn hit_any_rule_ban(rules: &[KeywordGroupBan], text: &str) -> bool {
 	false
 }
 
-fn warn_mark_path(gid: &str, user: &str) -> PathBuf {
-	group_mark_dir(gid).join(format!("{user}.json"))
+// 开了加密时文件名是成员 id 的带密钥散列，id 本身只在（加密的）内容里
+fn warn_mark_path(gid: &str, user: &str) -> Result<PathBuf> {
+	Ok(group_mark_dir(gid).join(format!("{}.json", crypto::mark_name(user)?)))
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 struct WarnMark {
 	first_ts: i64,
 	count: u32,
+	#[serde(default)]
+	user: String,
 }
 
-fn warn_and_maybe_kick(acc: &str, cfgdir: Option<&str>, rt: &GroupRuntime, user: &str) -> Result<bool> {
-	let gid = &rt.cfg.group_id;
-	fs::create_dir_all(group_mark_dir(gid))?;
-	let p = warn_mark_path(gid, user);
-	let now = Utc::now().timestamp();
+fn warn_and_maybe_kick(backend: &dyn Backend, rt: &mut GroupRuntime, user: &str) -> Result<bool> {
+	let gid = rt.cfg.group_id.clone();
+	let now = rt.now();
 
-	let mut mark = if p.exists() {
-		let mut s = String::new();
-		File::open(&p)?.read_to_string(&mut s)?;
-		serde_json::from_str::<WarnMark>(&s).unwrap_or(WarnMark { first_ts: now, count: 0 })
-	} else {
-		WarnMark { first_ts: now, count: 0 }
-	};
+	let mark = with_marks_lock(rt, |rt| {
+		let mut mark = load_warn_mark(rt, user)?.unwrap_or(WarnMark {
+			first_ts: now,
+			count: 0,
+			user: user.to_string(),
+		});
 
-	let window = (rt.cfg.warn_window_minutes as i64) * 60;
-	if now - mark.first_ts > window {
-		mark.first_ts = now;
-		mark.count = 0;
-	}
-	mark.count += 1;
+		let window = (rt.cfg.warn_window_minutes as i64) * 60;
+		if now - mark.first_ts > window {
+			mark.first_ts = now;
+			mark.count = 0;
+		}
+		mark.count += 1;
+		store_warn_mark(rt, user, &mark)?;
+		Ok(mark)
+	})?;
 
-	fs::write(&p, serde_json::to_vec_pretty(&mark)?)?;
+	rt.record_warn(user, mark.count);
 
 	if mark.count > rt.cfg.warn_max_count {
-		let _ = remove_member(acc, cfgdir, gid, user);
-		clear_warn_mark(gid, user)?;
+		let _ = backend.update_group(&gid, &GroupUpdate::remove_member(user));
+		clear_warn_mark(rt, user)?;
 		return Ok(true);
 	}
 
 	Ok(false)
 }
 
-fn clear_warn_mark(gid: &str, user: &str) -> Result<()> {
-	let p = warn_mark_path(gid, user);
-	if p.exists() {
-		let _ = fs::remove_file(p);
+// 读-改-写警告计数期间锁住 marks/<gid>/（回放不碰磁盘，不用锁）
+fn with_marks_lock<T>(rt: &mut GroupRuntime, f: impl FnOnce(&mut GroupRuntime) -> Result<T>) -> Result<T> {
+	if rt.sim.is_some() {
+		return f(rt);
 	}
-	Ok(())
+	let dir = group_mark_dir(&rt.cfg.group_id);
+	lock::with_dir_lock(&dir, || f(rt))
 }
 
-fn apply_takeover_permissions(acc: &str, cfgdir: Option<&str>, rt: &GroupRuntime) -> Result<()> {
-	if !rt.cfg.bot_has_admin {
-		return Ok(());
+fn load_warn_mark(rt: &GroupRuntime, user: &str) -> Result<Option<WarnMark>> {
+	if let Some(sim) = &rt.sim {
+		return Ok(sim.marks.get(user).cloned());
 	}
-
-	let gid = &rt.cfg.group_id;
-	let mut cmd = Command::new("signal-cli");
-	if let Some(d) = cfgdir {
-		cmd.arg("--config").arg(d);
+	let p = warn_mark_path(&rt.cfg.group_id, user)?;
+	if !p.exists() {
+		return Ok(None);
 	}
-	cmd.arg("-u").arg(acc).arg("updateGroup").arg("-g").arg(gid);
-
-	cmd.arg("--set-permission-add-member").arg(&rt.cfg.desired_permission_add_member);
-	cmd.arg("--set-permission-send-messages").arg(&rt.cfg.desired_permission_send_message);
-	cmd.arg("--set-permission-edit-details").arg(&rt.cfg.desired_permission_edit_details);
+	let s = crypto::read_to_string(&p)?;
+	Ok(serde_json::from_str::<WarnMark>(&s).ok())
+}
 
-	let _ = run_ok(&mut cmd);
+fn store_warn_mark(rt: &mut GroupRuntime, user: &str, mark: &WarnMark) -> Result<()> {
+	if let Some(sim) = &mut rt.sim {
+		sim.marks.insert(user.to_string(), mark.clone());
+		return Ok(());
+	}
+	let gid = &rt.cfg.group_id;
+	fs::create_dir_all(group_mark_dir(gid))?;
+	let mark = WarnMark {
+		user: user.to_string(),
+		..mark.clone()
+	};
+	crypto::write(&warn_mark_path(gid, user)?, &serde_json::to_vec_pretty(&mark)?)?;
 	Ok(())
 }
 
-fn remove_member(acc: &str, cfgdir: Option<&str>, gid: &str, who: &str) -> Result<()> {
-	let mut cmd = Command::new("signal-cli");
-	if let Some(d) = cfgdir {
-		cmd.arg("--config").arg(d);
+fn clear_warn_mark(rt: &mut GroupRuntime, user: &str) -> Result<()> {
+	if let Some(sim) = &mut rt.sim {
+		sim.marks.remove(user);
+		return Ok(());
+	}
+	let p = warn_mark_path(&rt.cfg.group_id, user)?;
+	if p.exists() {
+		lock::with_dir_lock(&group_mark_dir(&rt.cfg.group_id), || {
+			let _ = fs::remove_file(p);
+			Ok(())
+		})?;
 	}
-	cmd.arg("-u").arg(acc).arg("updateGroup").arg("-g").arg(gid);
-	cmd.arg("--remove-member").arg(who);
-	run_ok(&mut cmd)?;
 	Ok(())
 }
 
-fn send_group_message(acc: &str, cfgdir: Option<&str>, gid: &str, msg: &str) -> Result<()> {
-	let mut cmd = Command::new("signal-cli");
-	if let Some(d) = cfgdir {
-		cmd.arg("--config").arg(d);
+fn apply_takeover_permissions(backend: &dyn Backend, rt: &GroupRuntime) -> Result<()> {
+	if !rt.state.bot_has_admin {
+		return Ok(());
 	}
-	cmd.arg("-u").arg(acc).arg("send").arg("-g").arg(gid).arg("-m").arg(msg);
-	run_ok(&mut cmd)?;
+
+	let upd = GroupUpdate::permissions(
+		&rt.cfg.desired_permission_add_member,
+		&rt.cfg.desired_permission_send_message,
+		&rt.cfg.desired_permission_edit_details,
+	);
+	let _ = backend.update_group(&rt.cfg.group_id, &upd);
 	Ok(())
 }
 
-fn refresh_group_state(acc: &str, cfgdir: Option<&str>, rt: &mut GroupRuntime) -> Result<()> {
-	let groups = list_groups_full(acc, cfgdir)?;
-	let g = groups
-		.iter()
+// 成员快照对比当前成员：新进群的排队欢迎，退群的只更新快照。返回 (进群, 退群)
+fn sync_member_snapshot(rt: &mut GroupRuntime) -> Result<(Vec<String>, Vec<String>)> {
+	let prev = rt.state.last_members_snapshot.clone();
+	let cur = rt.members.clone();
+	let added: Vec<String> = cur.difference(&prev).cloned().collect();
+	let left: Vec<String> = prev.difference(&cur).cloned().collect();
+	rt.state.last_members_snapshot = cur;
+	rt.save_state()?;
+
+	// PATCH 6: 短时间内进群的人合并成一条欢迎语，外发受令牌桶限制
+	if let Some(tpl) = rt.cfg.welcome_template.clone() {
+		let now = rt.now();
+		for uid in &added {
+			let name = rt
+				.member_names
+				.get(uid)
+				.cloned()
+				.unwrap_or_else(|| short_id(uid));
+			rt.out.welcome(now, &tpl, &name);
+		}
+	}
+	Ok((added, left))
+}
+
+fn refresh_group_state(backend: &dyn Backend, rt: &mut GroupRuntime) -> Result<GroupFull> {
+	let g = backend
+		.list_groups()?
+		.into_iter()
 		.find(|x| x.id == rt.cfg.group_id)
 		.ok_or_else(|| anyhow!("group not found"))?;
 
@@ -1228,20 +1807,31 @@ fn refresh_group_state(acc: &str, cfgdir: Option<&str>, rt: &mut GroupRuntime) -
 
 	rt.admins = admins;
 	rt.members = members;
-	rt.cfg.bot_has_admin = bot_admin;
+	rt.state.bot_has_admin = bot_admin;
 
-	rt.member_names = build_identity_name_map(acc, cfgdir)?;
+	// 新进群的人联系人缓存里还没有名字，让联系人缓存过期重拉一次
+	let unknown = |names: &HashMap<String, String>| {
+		g.members
+			.iter()
+			.any(|m| m.name.is_none() && !rt.state.last_members_snapshot.contains(&m.id) && !names.contains_key(&m.id))
+	};
+	let mut names = build_identity_name_map(backend)?;
+	if !rt.state.last_members_snapshot.is_empty() && unknown(&names) {
+		backend.invalidate(CacheScope::Contacts);
+		names = build_identity_name_map(backend)?;
+	}
+	rt.member_names = names;
 	for m in &g.members {
 		if let Some(n) = &m.name {
 			rt.member_names.insert(m.id.clone(), n.clone());
 		}
 	}
 
-	if rt.cfg.last_members_snapshot.is_empty() {
-		rt.cfg.last_members_snapshot = rt.members.clone();
+	if rt.state.last_members_snapshot.is_empty() {
+		rt.state.last_members_snapshot = rt.members.clone();
 	}
-	save_group_cfg(&rt.cfg)?;
-	Ok(())
+	rt.save_state()?;
+	Ok(g)
 }
 
 fn short_id(s: &str) -> String {
@@ -1263,8 +1853,27 @@ fn truncate(s: &str, n: usize) -> String {
 	out
 }
 
+// --user 模式操作的是用户自己的 systemd 实例
+fn systemctl(args: &[&str]) -> Command {
+	let mut cmd = Command::new("systemctl");
+	if paths::user_mode() {
+		cmd.arg("--user");
+	}
+	cmd.args(args);
+	cmd
+}
+
+// 系统 unit 要 root；--user 模式不需要
+fn unit_admin<T>(f: impl FnOnce() -> Result<T>) -> Result<T> {
+	if paths::user_mode() {
+		f()
+	} else {
+		privs::as_root(f)
+	}
+}
+
+// 只有写 unit 和 systemctl 需要 root，其余（保存配置）仍以服务用户身份
 fn systemd_menu(gc: &mut GlobalConfig) -> Result<()> {
-	require_root()?;
 	let items = vec![
 		"1. 安装/覆盖 systemd unit",
 		"2. 启用开机自启",
@@ -1276,35 +1885,110 @@ fn systemd_menu(gc: &mut GlobalConfig) -> Result<()> {
 		"0. 返回",
 	];
 	let sel = Select::with_theme(&theme()).items(&items).default(0).interact()?;
-	match sel {
-		0 => install_systemd_unit()?,
-		1 => {
-			run_ok(Command::new("systemctl").arg("enable").arg("--now").arg("magicbot"))?;
-			gc.daemon_enabled = true;
-			save_global(gc)?;
-		}
-		2 => {
-			run_ok(Command::new("systemctl").arg("disable").arg("--now").arg("magicbot"))?;
-			gc.daemon_enabled = false;
-			save_global(gc)?;
-		}
-		3 => {
-			run_ok(Command::new("systemctl").arg("start").arg("magicbot"))?;
-		}
-		4 => {
-			run_ok(Command::new("systemctl").arg("stop").arg("magicbot"))?;
-		}
+	let op = match sel {
+		0 => "install",
+		1 => "enable",
+		2 => "disable",
+		3 => "start",
+		4 => "stop",
 		5 => {
-			let _ = Command::new("systemctl").arg("status").arg("magicbot").arg("-l").status();
+			let _ = systemctl(&["status", &paths::unit_name(), "-l"]).status();
+			return Ok(());
+		}
+		6 => "uninstall",
+		_ => return Ok(()),
+	};
+	systemd_op(gc, op)
+}
+
+// 菜单和 magicbot systemd <op> 共用
+fn systemd_op(gc: &mut GlobalConfig, op: &str) -> Result<()> {
+	let unit = paths::unit_name();
+	match op {
+		"install" => unit_admin(|| install_systemd_unit(gc)),
+		"enable" | "disable" => {
+			unit_admin(|| run_ok(&mut systemctl(&[op, "--now", &unit])))?;
+			gc.daemon_enabled = op == "enable";
+			save_global(gc)
 		}
-		6 => uninstall_systemd_unit()?,
-		_ => {}
+		"start" | "stop" => unit_admin(|| run_ok(&mut systemctl(&[op, &unit]))),
+		"uninstall" => unit_admin(uninstall_systemd_unit),
+		_ => Err(anyhow!("unknown systemd op: {op}")),
 	}
-	Ok(())
 }
 
-fn install_systemd_unit() -> Result<()> {
+fn install_systemd_unit(gc: &mut GlobalConfig) -> Result<()> {
 	let exe = env::current_exe().context("current_exe")?;
+	let unit = paths::unit_path();
+	if let Some(parent) = unit.parent() {
+		fs::create_dir_all(parent).with_context(|| format!("create dir {}", parent.display()))?;
+	}
+	let mut env_lines: String = paths::unit_env().iter().map(|e| format!("Environment={e}\n")).collect();
+	// 状态文件加密的密钥由 systemd 从 root 可读的文件传进来（$CREDENTIALS_DIRECTORY）
+	env_lines.push_str(&crypto::unit_credential());
+
+	if paths::user_mode() {
+		let content = format!(
+			"[Unit]
+Description=MagicBot (Signal) daemon
+
+[Service]
+Type=notify
+NotifyAccess=main
+ExecStart={exe} --user --daemon
+WatchdogSec=60
+ExecReload=/bin/kill -HUP $MAINPID
+KillMode=mixed
+TimeoutStopSec=30
+Restart=always
+RestartSec=2
+WorkingDirectory={state}
+Environment=RUST_BACKTRACE=1
+{env_lines}
+[Install]
+WantedBy=default.target
+",
+			exe = exe.display(),
+			state = paths::state_dir().display(),
+		);
+		fs::write(unit, content).with_context(|| format!("write {}", unit.display()))?;
+		run_ok(&mut systemctl(&["daemon-reload"]))?;
+		println!("[OK] Installed unit: {}", unit.display());
+		println!("[INF] 注销后也要保持运行：loginctl enable-linger");
+		return Ok(());
+	}
+
+	// 服务以专用用户运行：建用户、目录归它、以前 root 下的 signal-cli 数据复制过去
+	let user = privs::ensure_service_user()?;
+	privs::prepare_dirs(&user)?;
+	let mut rw: Vec<String> = [paths::state_dir(), paths::run_dir(), paths::log_dir()]
+		.iter()
+		.map(|p| p.display().to_string())
+		.collect();
+	match &gc.signal_cli_config_dir {
+		Some(dir) => {
+			privs::chown_dir(Path::new(dir))?;
+			if !Path::new(dir).starts_with(paths::state_dir()) {
+				rw.push(dir.clone());
+			}
+		}
+		None => privs::migrate_root_signal_data(&user)?,
+	}
+	// 默认的 /run/magicbot 交给 systemd 建；自定义运行目录自己负责
+	let runtime_dir = if paths::default_run_dir() {
+		"RuntimeDirectory=magicbot\nRuntimeDirectoryPreserve=yes\n"
+	} else {
+		""
+	};
+	let in_home = exe.starts_with("/root") || exe.starts_with("/home");
+	if in_home {
+		println!(
+			"[WRN] {} 在 home 目录下，{} 用户可能无权执行，建议安装到 /usr/local/bin",
+			exe.display(),
+			privs::SERVICE_USER
+		);
+	}
+
 	let content = format!(
 		"[Unit]
 Description=MagicBot (Signal) daemon
@@ -1312,31 +1996,56 @@ After=network-online.target
 Wants=network-online.target
 
 [Service]
-Type=simple
-ExecStart={} --daemon
+Type=notify
+NotifyAccess=main
+ExecStart={exe} --daemon
+WatchdogSec=60
+ExecReload=/bin/kill -HUP $MAINPID
+KillMode=mixed
+TimeoutStopSec=30
 Restart=always
 RestartSec=2
-User=root
-WorkingDirectory=/
+User={user}
+Group={group}
+WorkingDirectory={state}
 Environment=RUST_BACKTRACE=1
+{env_lines}{runtime_dir}UMask=0027
+
+# Hardening
+NoNewPrivileges=true
+PrivateTmp=true
+ProtectSystem=strict
+ProtectHome={protect_home}
+ProtectKernelTunables=true
+ProtectKernelModules=true
+ProtectControlGroups=true
+RestrictSUIDSGID=true
+LockPersonality=true
+ReadWritePaths={rw}
 
 [Install]
 WantedBy=multi-user.target
 ",
-		exe.display()
+		exe = exe.display(),
+		user = privs::SERVICE_USER,
+		group = privs::SERVICE_GROUP,
+		state = paths::state_dir().display(),
+		protect_home = if in_home { "read-only" } else { "true" },
+		rw = rw.join(" "),
 	);
 
-	fs::write(SYSTEMD_UNIT, content)?;
-	run_ok(Command::new("systemctl").arg("daemon-reload"))?;
-	println!("[OK] Installed unit: {SYSTEMD_UNIT}");
+	fs::write(unit, content).with_context(|| format!("write {}", unit.display()))?;
+	run_ok(&mut systemctl(&["daemon-reload"]))?;
+	println!("[OK] Installed unit: {}", unit.display());
 	Ok(())
 }
 
 fn uninstall_systemd_unit() -> Result<()> {
-	if Path::new(SYSTEMD_UNIT).exists() {
-		let _ = Command::new("systemctl").arg("disable").arg("--now").arg("magicbot").status();
-		let _ = fs::remove_file(SYSTEMD_UNIT);
-		let _ = Command::new("systemctl").arg("daemon-reload").status();
+	let unit = paths::unit_path();
+	if unit.exists() {
+		let _ = systemctl(&["disable", "--now", &paths::unit_name()]).status();
+		let _ = fs::remove_file(unit);
+		let _ = systemctl(&["daemon-reload"]).status();
 		println!("[OK] Removed unit.");
 	}
 	Ok(())
@@ -1357,22 +2066,110 @@ fn captcha_menu(gc: &GlobalConfig) -> Result<()> {
 		.allow_empty(true)
 		.interact_text()?;
 
-	let mut cmd = Command::new("signal-cli");
-	if let Some(dir) = &gc.signal_cli_config_dir {
-		cmd.arg("--config").arg(dir);
+	let chal = Some(chal.trim().to_string()).filter(|c| !c.is_empty());
+	ratelimit::submit(&acc, gc.signal_cli_config_dir.as_deref(), chal, &cap)?;
+	println!("[OK] 已提交。");
+	Ok(())
+}
+
+// magicbot outbox list [--dead]
+// magicbot outbox retry [<id>...]   死信重新入队（不带 id 表示全部）
+// magicbot outbox drop <id>...      丢弃死信
+fn outbox_cli(args: &[String]) -> Result<()> {
+	let ids = args
+		.iter()
+		.skip(3)
+		.filter(|a| !a.starts_with("--"))
+		.map(|a| a.parse::<u64>().map_err(|_| anyhow!("invalid id: {a}")))
+		.collect::<Result<Vec<_>>>()?;
+	match args.get(2).map(|s| s.as_str()) {
+		Some("list") => {
+			let dead = has_flag(args, "--dead");
+			let entries = if dead { outbox::load_dead()? } else { outbox::load_queue()? };
+			if entries.is_empty() {
+				println!("{}", if dead { "死信队列为空" } else { "重试队列为空" });
+			}
+			for e in entries {
+				let when = if dead {
+					"dead".to_string()
+				} else {
+					Local
+						.timestamp_opt(e.next_at, 0)
+						.single()
+						.map(|t| t.format("next %m-%d %H:%M:%S").to_string())
+						.unwrap_or_default()
+				};
+				println!(
+					"#{:<5} {:<14} tries={:<2} {:<20} {} | {}",
+					e.id,
+					short_id(e.action.gid()),
+					e.attempts,
+					when,
+					e.action.describe(),
+					truncate(&e.last_error, 80)
+				);
+			}
+			Ok(())
+		}
+		Some("retry") => {
+			let n = outbox::redrive(&ids)?;
+			println!("[OK] {n} 条死信已重新入队，守护进程会在下个周期重试。");
+			Ok(())
+		}
+		Some("drop") if !ids.is_empty() => {
+			let n = outbox::drop_dead(&ids)?;
+			println!("[OK] 已丢弃 {n} 条死信。");
+			Ok(())
+		}
+		_ => Err(anyhow!("usage: magicbot outbox list [--dead] | outbox retry [<id>...] | outbox drop <id>...")),
 	}
-	cmd.arg("-u").arg(&acc).arg("submitRateLimitChallenge");
-	if !chal.trim().is_empty() {
-		cmd.arg("--challenge").arg(chal.trim());
+}
+
+// magicbot cache dump [--json]：守护进程里群/联系人缓存的最近一次快照
+fn cache_cli(args: &[String]) -> Result<()> {
+	if args.get(2).map(|s| s.as_str()) != Some("dump") {
+		return Err(anyhow!("usage: magicbot cache dump [--json]"));
+	}
+	let dump = cache::load_dump().ok_or_else(|| {
+		anyhow!(
+			"没有缓存快照（{} 不存在），守护进程可能没在运行",
+			cache::dump_path().display()
+		)
+	})?;
+	if has_flag(args, "--json") {
+		println!("{}", serde_json::to_string_pretty(&dump)?);
+		return Ok(());
+	}
+
+	let now = Utc::now().timestamp();
+	let age = |t: Option<i64>| match t {
+		Some(t) => format!("{}s ago", now - t),
+		None => "empty".to_string(),
+	};
+	println!("snapshot written {}s ago, hits={} misses={}", now - dump.written, dump.hits, dump.misses);
+	println!("\ngroups ({}, fetched {}):", dump.groups.len(), age(dump.groups_fetched));
+	for g in &dump.groups {
+		let admins = g.admins.iter().map(|i| short_id(&i.id)).collect::<Vec<_>>().join(",");
+		println!(
+			"  {:<24} {:<14} members={:<4} admins=[{admins}]",
+			truncate(&g.name, 24),
+			short_id(&g.id),
+			g.members.len()
+		);
+	}
+	println!("\ncontacts ({}, fetched {}):", dump.contacts.len(), age(dump.contacts_fetched));
+	for c in &dump.contacts {
+		println!(
+			"  {:<14} {:<16} {}",
+			short_id(&c.id),
+			c.number.as_deref().unwrap_or("-"),
+			c.name.as_deref().unwrap_or("-")
+		);
 	}
-	cmd.arg("--captcha").arg(cap.trim());
-	run_ok(&mut cmd)?;
-	println!("[OK] 已提交。");
 	Ok(())
 }
 
 fn logout_and_cleanup(gc: &mut GlobalConfig) -> Result<()> {
-	require_root()?;
 	let acc = gc.account.clone().unwrap_or_default();
 	if acc.is_empty() {
 		println!("[WRN] 未登录。");
@@ -1390,115 +2187,61 @@ fn logout_and_cleanup(gc: &mut GlobalConfig) -> Result<()> {
 		.default(false)
 		.interact()?
 	{
-		let _ = fs::remove_dir_all(groups_dir());
-		let _ = fs::remove_dir_all(PathBuf::from(STATE_DIR).join("marks"));
-
-		gc.selected_group = None;
-		gc.account = None;
-		save_global(gc)?;
-
-		if also_delete_signal {
-			let mut cmd = Command::new("signal-cli");
-			if let Some(dir) = &gc.signal_cli_config_dir {
-				cmd.arg("--config").arg(dir);
-			}
-			cmd.arg("-u").arg(&acc).arg("deleteLocalAccountData").arg("--ignore-registered");
-			let _ = run_ok(&mut cmd);
-		}
-
+		logout(gc, &acc, also_delete_signal)?;
 		println!("[OK] 已退出并清理。");
 	}
 
 	Ok(())
 }
 
-#[derive(Clone, Debug)]
-struct GroupSummary {
-	id: String,
-	name: String,
-}
-
-#[derive(Clone, Debug)]
-struct GroupFull {
-	id: String,
-	name: String,
-	admins: Vec<Identity>,
-	members: Vec<Identity>,
-}
-
-fn list_groups(acc: &str, cfgdir: Option<&str>) -> Result<Vec<GroupSummary>> {
-	let v = run_signal_json(Command::new("signal-cli"), cfgdir, Some(acc), &["-o", "json", "listGroups"])?;
-	let arr = v.as_array().ok_or_else(|| anyhow!("listGroups not array"))?;
-	let mut out = vec![];
-	for g in arr {
-		let id = g.get("id").and_then(|x| x.as_str()).unwrap_or("").to_string();
-		let name = g.get("name").and_then(|x| x.as_str()).unwrap_or("").to_string();
-		if !id.is_empty() {
-			out.push(GroupSummary { id, name });
-		}
-	}
-	Ok(out)
-}
-
-fn list_groups_full(acc: &str, cfgdir: Option<&str>) -> Result<Vec<GroupFull>> {
-	let v = run_signal_json(Command::new("signal-cli"), cfgdir, Some(acc), &["-o", "json", "listGroups"])?;
-	let arr = v.as_array().ok_or_else(|| anyhow!("listGroups not array"))?;
-	let mut out = vec![];
-	for g in arr {
-		let id = g.get("id").and_then(|x| x.as_str()).unwrap_or("").to_string();
-		let name = g.get("name").and_then(|x| x.as_str()).unwrap_or("").to_string();
+// 菜单和 magicbot logout 共用
+fn logout(gc: &mut GlobalConfig, acc: &str, delete_signal: bool) -> Result<()> {
+	// 群配置、警告计数、成员快照/管理员状态、外发重试队列、限流暂停都属于旧账号，一起清掉，
+	// 重新登录后不会冒出假的进群/退群差异
+	let _ = fs::remove_dir_all(groups_dir());
+	let _ = fs::remove_dir_all(paths::state_dir().join("marks"));
+	let _ = fs::remove_dir_all(state::state_dir());
+	let _ = fs::remove_dir_all(outbox::outbox_dir());
+	ratelimit::clear_pause();
+	let _ = fs::remove_file(cache::dump_path());
 
-		let admins = parse_identities(g.get("admins"));
-		let members = parse_identities(g.get("members"));
+	gc.selected_group = None;
+	gc.account = None;
+	save_global(gc)?;
 
-		if !id.is_empty() {
-			out.push(GroupFull { id, name, admins, members });
+	if delete_signal {
+		let mut cmd = Command::new("signal-cli");
+		if let Some(dir) = &gc.signal_cli_config_dir {
+			cmd.arg("--config").arg(dir);
 		}
+		cmd.arg("-u").arg(acc).arg("deleteLocalAccountData").arg("--ignore-registered");
+		let _ = run_ok(&mut cmd);
 	}
-	Ok(out)
+	Ok(())
 }
 
-fn parse_identities(v: Option<&Value>) -> Vec<Identity> {
-	let mut out = vec![];
-	let Some(v) = v else { return out };
-	let Some(arr) = v.as_array() else { return out };
-
-	for it in arr {
-		let uuid = it.get("uuid").and_then(|x| x.as_str()).map(|s| s.to_string());
-		let number = it.get("number").and_then(|x| x.as_str()).map(|s| s.to_string());
-
-		let id = uuid.or(number.clone()).unwrap_or_else(|| "".to_string());
-		if id.is_empty() {
-			continue;
-		}
+#[derive(Clone, Debug)]
+struct GroupSummary {
+	id: String,
+	name: String,
+}
 
-		out.push(Identity { id, number, name: None });
-	}
-	out
+fn list_groups(backend: &dyn Backend) -> Result<Vec<GroupSummary>> {
+	Ok(backend
+		.list_groups()?
+		.into_iter()
+		.map(|g| GroupSummary { id: g.id, name: g.name })
+		.collect())
 }
 
-fn build_identity_name_map(acc: &str, cfgdir: Option<&str>) -> Result<HashMap<String, String>> {
+fn build_identity_name_map(backend: &dyn Backend) -> Result<HashMap<String, String>> {
 	let mut map = HashMap::new();
-	let v = run_signal_json(
-		Command::new("signal-cli"),
-		cfgdir,
-		Some(acc),
-		&["-o", "json", "listContacts", "--all-recipients", "--detailed"],
-	)?;
-	let empty = vec![];
-	let arr = v.as_array().unwrap_or(&empty);
-	for c in arr {
-		let uuid = c.get("uuid").and_then(|x| x.as_str()).map(|s| s.to_string());
-		let number = c.get("number").and_then(|x| x.as_str()).map(|s| s.to_string());
-		let name = c.get("name").and_then(|x| x.as_str()).map(|s| s.to_string());
-
-		if let Some(n) = name {
-			if let Some(u) = uuid.clone() {
-				map.insert(u, n.clone());
-			}
-			if let Some(p) = number.clone() {
-				map.insert(p, n);
+	for c in backend.list_contacts()? {
+		if let Some(n) = c.name {
+			if let Some(p) = c.number {
+				map.insert(p, n.clone());
 			}
+			map.insert(c.id, n);
 		}
 	}
 	Ok(map)
@@ -1527,8 +2270,12 @@ fn list_local_accounts(gc: &GlobalConfig) -> Result<Vec<String>> {
 	Ok(cand.into_iter().collect())
 }
 
-fn load_all_groups_runtime(acc: &str, cfgdir: Option<&str>) -> Result<(HashMap<String, GroupRuntime>, String)> {
-	let full = list_groups_full(acc, cfgdir)?;
+fn load_all_groups_runtime(
+	backend: &dyn Backend,
+	acc: &str,
+	account_bucket: &SharedAccountBucket,
+) -> Result<(HashMap<String, GroupRuntime>, String)> {
+	let full = backend.list_groups()?;
 	let mut runtime = HashMap::new();
 
 	let mut self_id = acc.to_string();
@@ -1543,47 +2290,74 @@ fn load_all_groups_runtime(acc: &str, cfgdir: Option<&str>) -> Result<(HashMap<S
 		}
 	}
 
+	// 联系人只拉一次，所有群共用
+	let names = build_identity_name_map(backend)?;
 	for g in &full {
 		let p = group_cfg_path(&g.id);
 		if !p.exists() {
 			continue;
 		}
-		let mut cfg = load_group_cfg(&g.id)?;
-		if cfg.group_name.is_empty() {
-			cfg.group_name = g.name.clone();
-		}
+		let rt = build_group_runtime(g, &self_id, &names, account_bucket)?;
+		runtime.insert(g.id.clone(), rt);
+	}
 
-		let admins = g.admins.iter().map(|i| i.id.clone()).collect::<BTreeSet<_>>();
-		let members = g.members.iter().map(|i| i.id.clone()).collect::<BTreeSet<_>>();
+	Ok((runtime, self_id))
+}
 
-		cfg.bot_has_admin = admins.contains(&self_id);
+// 守护进程运行中新出现的群配置
+fn load_group_runtime(
+	backend: &dyn Backend,
+	gid: &str,
+	self_id: &str,
+	account_bucket: &SharedAccountBucket,
+) -> Result<GroupRuntime> {
+	let g = backend
+		.list_groups()?
+		.into_iter()
+		.find(|g| g.id == gid)
+		.ok_or_else(|| anyhow!("group {gid} not found in listGroups"))?;
+	let names = build_identity_name_map(backend)?;
+	build_group_runtime(&g, self_id, &names, account_bucket)
+}
+
+fn build_group_runtime(
+	g: &GroupFull,
+	self_id: &str,
+	names: &HashMap<String, String>,
+	account_bucket: &SharedAccountBucket,
+) -> Result<GroupRuntime> {
+	let cfg = load_group_cfg(&g.id)?;
+	let mut state = load_group_state(&g.id)?;
+	state.group_name = g.name.clone();
 
-		let mut member_names = build_identity_name_map(acc, cfgdir)?;
-		for m in &g.members {
-			if let Some(n) = &m.name {
-				member_names.insert(m.id.clone(), n.clone());
-			}
-		}
+	let admins = g.admins.iter().map(|i| i.id.clone()).collect::<BTreeSet<_>>();
+	let members = g.members.iter().map(|i| i.id.clone()).collect::<BTreeSet<_>>();
 
-		if cfg.last_members_snapshot.is_empty() {
-			cfg.last_members_snapshot = members.clone();
-		}
+	state.bot_has_admin = admins.contains(self_id);
 
-		save_group_cfg(&cfg)?;
+	let mut member_names = names.clone();
+	for m in &g.members {
+		if let Some(n) = &m.name {
+			member_names.insert(m.id.clone(), n.clone());
+		}
+	}
 
-		runtime.insert(
-			g.id.clone(),
-			GroupRuntime {
-				cfg,
-				admins,
-				members,
-				member_names,
-				self_id: self_id.clone(),
-			},
-		);
+	if state.last_members_snapshot.is_empty() {
+		state.last_members_snapshot = members.clone();
 	}
 
-	Ok((runtime, self_id))
+	save_group_state(&g.id, &state)?;
+
+	Ok(GroupRuntime {
+		cfg,
+		state,
+		admins,
+		members,
+		member_names,
+		self_id: self_id.to_string(),
+		out: Outgoing::new(account_bucket.clone()),
+		sim: None,
+	})
 }
 
 fn run_signal_json(mut base: Command, cfgdir: Option<&str>, acc: Option<&str>, args: &[&str]) -> Result<Value> {
@@ -1622,7 +2396,7 @@ fn ensure_cmd(cmd: &str) -> Result<()> {
 
 fn require_root() -> Result<()> {
 	if unsafe { libc::geteuid() } != 0 {
-		return Err(anyhow!("Must run as root."));
+		return Err(cli::fail(cli::EXIT_DENIED, "Must run as root."));
 	}
 	Ok(())
 }
@@ -1641,9 +2415,285 @@ fn run_ok(cmd: &mut Command) -> Result<()> {
 	let out = cmd.output().context("run command")?;
 	if !out.status.success() {
 		return Err(anyhow!(
-			"Command failed. stderr={}",
+			"Command failed. status={:?} stderr={}",
+			out.status.code(),
 			String::from_utf8_lossy(&out.stderr)
 		));
 	}
+	// signal-cli 群发部分失败(限流/proof required)时退出码仍可能为 0，只在 stderr 里说明
+	if cmd.get_program() == "signal-cli" {
+		let stderr = String::from_utf8_lossy(&out.stderr);
+		if let Some(hit) = ratelimit::detect(&stderr) {
+			return Err(anyhow::Error::new(hit).context(format!("signal-cli stderr={stderr}")));
+		}
+	}
 	Ok(())
 }
+
+fn signal_cli(cfgdir: Option<&str>, acc: &str) -> Command {
+	let mut cmd = Command::new("signal-cli");
+	if let Some(d) = cfgdir {
+		cmd.arg("--config").arg(d);
+	}
+	cmd.arg("-u").arg(acc);
+	cmd
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use backend::{FakeAction, FakeBackend, GroupFull, Identity};
+	use serde_json::json;
+
+	const GID: &str = "G1";
+	const BOT: &str = "bot";
+	const ADMIN: &str = "adm";
+
+	fn ident(id: &str) -> Identity {
+		Identity {
+			id: id.to_string(),
+			number: None,
+			name: None,
+		}
+	}
+
+	fn fake_group(members: &[&str]) -> GroupFull {
+		GroupFull {
+			id: GID.to_string(),
+			name: "test".to_string(),
+			admins: [BOT, ADMIN].iter().map(|m| ident(m)).collect(),
+			members: members.iter().map(|m| ident(m)).collect(),
+			permissions: Default::default(),
+		}
+	}
+
+	// 已启用、bot 是管理员、不限流不合并；挂 Simulation，不落盘
+	fn runtime(fake: &FakeBackend) -> GroupRuntime {
+		fake.set_groups(vec![fake_group(&[BOT, ADMIN, "u1"])]);
+		let members: BTreeSet<String> = [BOT, ADMIN, "u1"].iter().map(|s| s.to_string()).collect();
+		let cfg = GroupConfig {
+			group_id: GID.to_string(),
+			enabled: true,
+			only_admin_can_ban: true,
+			warn_window_minutes: 10,
+			warn_max_count: 2,
+			warn_message: "警告".to_string(),
+			throttle: ThrottleConfig {
+				per_minute: 600,
+				burst: 100,
+				coalesce_secs: 0,
+			},
+			..GroupConfig::default()
+		};
+		let state = GroupState {
+			bot_has_admin: true,
+			last_members_snapshot: members.clone(),
+			..GroupState::default()
+		};
+		GroupRuntime {
+			cfg,
+			state,
+			admins: [BOT, ADMIN].iter().map(|s| s.to_string()).collect(),
+			members,
+			member_names: HashMap::new(),
+			self_id: BOT.to_string(),
+			out: Outgoing::new(throttle::account_bucket(&AccountThrottleConfig {
+				per_minute: 600,
+				burst: 100,
+			})),
+			sim: Some(Simulation::default()),
+		}
+	}
+
+	fn envelope(from: &str, text: Option<&str>, kind: &str) -> ReceiveEnvelope {
+		serde_json::from_value(json!({
+			"envelope": {
+				"sourceUuid": from,
+				"timestamp": 0,
+				"dataMessage": {
+					"message": text,
+					"groupInfo": { "groupId": GID, "type": kind },
+				},
+			},
+		}))
+		.unwrap()
+	}
+
+	fn step(fake: &FakeBackend, rt: &mut GroupRuntime, ev: ReceiveEnvelope) -> Vec<FakeAction> {
+		if let Some(sim) = &mut rt.sim {
+			sim.now += 1;
+		}
+		let dm = ev.envelope.data_message.clone().unwrap();
+		let gi = dm.group_info.clone().unwrap();
+		handle_group_event(fake, rt, &ev, &dm, &gi).unwrap();
+		flush_outgoing(fake, rt, false);
+		fake.take_actions()
+	}
+
+	fn sent(actions: &[FakeAction]) -> Vec<String> {
+		actions
+			.iter()
+			.filter_map(|a| match a {
+				FakeAction::Send { msg, .. } => Some(msg.clone()),
+				_ => None,
+			})
+			.collect()
+	}
+
+	fn kicked(actions: &[FakeAction]) -> Vec<String> {
+		actions
+			.iter()
+			.flat_map(|a| match a {
+				FakeAction::UpdateGroup { upd, .. } => upd.remove_members.clone(),
+				_ => vec![],
+			})
+			.collect()
+	}
+
+	#[test]
+	fn ban_keyword_kicks_sender() {
+		let fake = FakeBackend::new();
+		let mut rt = runtime(&fake);
+		rt.cfg.ban_rules = vec![KeywordGroupBan {
+			keywords: vec!["casino".to_string()],
+		}];
+		let actions = step(&fake, &mut rt, envelope("u1", Some("best CASINO here"), "DELIVER"));
+		assert_eq!(kicked(&actions), vec!["u1"]);
+		assert!(sent(&actions).is_empty());
+	}
+
+	#[test]
+	fn warn_then_kick_after_max_count() {
+		let fake = FakeBackend::new();
+		let mut rt = runtime(&fake);
+		rt.cfg.warn_rules = vec![KeywordGroupWarn {
+			keywords: vec!["spam".to_string()],
+		}];
+		for _ in 0..rt.cfg.warn_max_count {
+			let actions = step(&fake, &mut rt, envelope("u1", Some("spam"), "DELIVER"));
+			assert!(kicked(&actions).is_empty());
+			assert_eq!(sent(&actions), vec![rt.cfg.warn_message.clone()]);
+		}
+		let actions = step(&fake, &mut rt, envelope("u1", Some("spam"), "DELIVER"));
+		assert_eq!(kicked(&actions), vec!["u1"]);
+		assert_eq!(sent(&actions), vec!["已因多次警告移出群组。"]);
+		assert!(rt.sim.as_ref().unwrap().marks.is_empty());
+	}
+
+	#[test]
+	fn auto_reply_on_keyword() {
+		let fake = FakeBackend::new();
+		let mut rt = runtime(&fake);
+		rt.cfg.auto_replies = vec![KeywordGroupReply {
+			keywords: vec!["help".to_string()],
+			reply: "see pinned".to_string(),
+		}];
+		let actions = step(&fake, &mut rt, envelope("u1", Some("Help me"), "DELIVER"));
+		assert_eq!(sent(&actions), vec!["see pinned"]);
+		assert!(step(&fake, &mut rt, envelope("u1", Some("hello"), "DELIVER")).is_empty());
+	}
+
+	#[test]
+	fn welcome_on_join() {
+		let fake = FakeBackend::new();
+		let mut rt = runtime(&fake);
+		rt.cfg.welcome_template = Some("欢迎 ##{@user}##".to_string());
+		fake.set_groups(vec![fake_group(&[BOT, ADMIN, "u1", "u2"])]);
+		fake.set_contacts(vec![Identity {
+			name: Some("n-u2".to_string()),
+			..ident("u2")
+		}]);
+		let actions = step(&fake, &mut rt, envelope(ADMIN, None, "UPDATE"));
+		assert_eq!(sent(&actions), vec!["欢迎 n-u2"]);
+		assert!(rt.state.last_members_snapshot.contains("u2"));
+	}
+
+	#[test]
+	fn received_lines_only_reach_managed_groups() {
+		let fake = FakeBackend::new();
+		let mut groups = HashMap::from([(GID.to_string(), runtime(&fake))]);
+		groups.get_mut(GID).unwrap().cfg.auto_replies = vec![KeywordGroupReply {
+			keywords: vec!["ping".to_string()],
+			reply: "pong".to_string(),
+		}];
+		for g in [GID, "G2"] {
+			let line = serde_json::to_string(&json!({
+				"envelope": {
+					"sourceUuid": "u1",
+					"dataMessage": { "message": "ping", "groupInfo": { "groupId": g, "type": "DELIVER" } },
+				},
+			}))
+			.unwrap();
+			fake.push_incoming(&line);
+		}
+		fake.push_incoming("not json");
+		for line in fake.receive().unwrap() {
+			let Ok(ev) = serde_json::from_str::<ReceiveEnvelope>(&line) else { continue };
+			let Some(rt) = ev.group_id().and_then(|g| groups.get_mut(g)) else { continue };
+			dispatch_envelope(&fake, rt, &ev).unwrap();
+		}
+		assert_eq!(
+			fake.take_actions().iter().map(|a| serde_json::to_value(a).unwrap()).collect::<Vec<_>>(),
+			vec![json!({ "action": "send", "gid": GID, "msg": "pong" })]
+		);
+	}
+
+	#[test]
+	fn ban_command_reports_backend_failure() {
+		let fake = FakeBackend::new();
+		let mut rt = runtime(&fake);
+		fake.fail_next("network down");
+		let actions = step(&fake, &mut rt, envelope(ADMIN, Some("/ban +15550001"), "DELIVER"));
+		assert!(kicked(&actions).is_empty());
+		assert_eq!(sent(&actions), vec!["踢人失败：network down"]);
+		assert!(fake.actions().is_empty());
+	}
+
+	#[test]
+	fn queued_kick_reports_retry_only() {
+		let e = anyhow!("PROOF_REQUIRED_FAILURE secret detail").context(outbox::Queued { id: 7 });
+		assert_eq!(kick_failed_message(&e), "踢人暂未成功，已加入重试队列 #7，稍后自动重试。");
+		assert_eq!(kick_failed_message(&anyhow!("network down")), "踢人失败：network down");
+	}
+
+	#[test]
+	fn non_admin_cannot_ban() {
+		let fake = FakeBackend::new();
+		let mut rt = runtime(&fake);
+		let actions = step(&fake, &mut rt, envelope("u1", Some("/ban +15550001"), "DELIVER"));
+		assert!(kicked(&actions).is_empty());
+		assert_eq!(sent(&actions), vec!["无权限：仅管理员可执行 /ban。"]);
+	}
+	#[test]
+	fn reconcile_skips_unreported_permissions() {
+		let fake = FakeBackend::new();
+		let mut rt = runtime(&fake);
+		rt.cfg.require_bot_admin_to_enforce = true;
+		rt.cfg.desired_permission_add_member = "EVERY_MEMBER".to_string();
+		rt.cfg.desired_permission_send_message = "EVERY_MEMBER".to_string();
+		rt.cfg.desired_permission_edit_details = "ONLY_ADMINS".to_string();
+		reconcile::reconcile_group(&fake, &mut rt).unwrap();
+		assert!(fake.actions().is_empty());
+
+		// 只报了一项且和期望不同：照样重新接管
+		let mut g = fake_group(&[BOT, ADMIN, "u1"]);
+		g.permissions.add_member = Some("ONLY_ADMINS".to_string());
+		fake.set_groups(vec![g]);
+		reconcile::reconcile_group(&fake, &mut rt).unwrap();
+		assert!(matches!(fake.actions().as_slice(), [FakeAction::UpdateGroup { .. }]));
+	}
+	#[test]
+	fn link_output_is_parsed() {
+		assert_eq!(
+			extract_linked_account("INFO  LinkCommand - Associated with: +4915112345678").as_deref(),
+			Some("+4915112345678")
+		);
+		assert_eq!(extract_linked_account("Associated with +15550001234").as_deref(), Some("+15550001234"));
+		assert_eq!(extract_linked_account("Associated with: unknown"), None);
+		assert_eq!(extract_linked_account("Waiting for +15550001234"), None);
+		assert_eq!(
+			extract_linkdevice_uri("INFO sgnl://linkdevice?uuid=abc&pub_key=x%2By more").as_deref(),
+			Some("sgnl://linkdevice?uuid=abc&pub_key=x%2By")
+		);
+	}
+}
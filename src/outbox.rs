@@ -0,0 +1,465 @@
+use crate::backend::{Backend, CacheScope, GroupFull, GroupUpdate, Identity};
+use crate::{crypto, lock, paths, signals};
+use anyhow::{anyhow, Context, Result};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::Receiver;
+
+// global.json 里的 "outbox" 段
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct OutboxConfig {
+	// 失败多少次后移进死信队列
+	pub max_attempts: u32,
+	pub backoff_initial_secs: u64,
+	pub backoff_max_secs: u64,
+}
+
+impl Default for OutboxConfig {
+	fn default() -> Self {
+		OutboxConfig {
+			max_attempts: 8,
+			backoff_initial_secs: 10,
+			backoff_max_secs: 3600,
+		}
+	}
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum OutboundAction {
+	Send { gid: String, msg: String },
+	UpdateGroup { gid: String, upd: GroupUpdate },
+}
+
+impl OutboundAction {
+	pub fn gid(&self) -> &str {
+		match self {
+			OutboundAction::Send { gid, .. } | OutboundAction::UpdateGroup { gid, .. } => gid,
+		}
+	}
+
+	pub fn describe(&self) -> String {
+		match self {
+			OutboundAction::Send { msg, .. } => format!("send {:?}", msg.chars().take(40).collect::<String>()),
+			OutboundAction::UpdateGroup { upd, .. } if !upd.remove_members.is_empty() => {
+				format!("kick {}", upd.remove_members.join(","))
+			}
+			OutboundAction::UpdateGroup { .. } => "set permissions".to_string(),
+		}
+	}
+
+	fn run(&self, backend: &dyn Backend) -> Result<()> {
+		match self {
+			OutboundAction::Send { gid, msg } => backend.send_group_message(gid, msg),
+			OutboundAction::UpdateGroup { gid, upd } => backend.update_group(gid, upd),
+		}
+	}
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct OutboxEntry {
+	pub id: u64,
+	pub created: i64,
+	pub attempts: u32,
+	pub next_at: i64,
+	pub last_error: String,
+	#[serde(flatten)]
+	pub action: OutboundAction,
+}
+
+pub fn outbox_dir() -> PathBuf {
+	paths::state_dir().join("outbox")
+}
+
+fn queue_path() -> PathBuf {
+	outbox_dir().join("queue.json")
+}
+
+fn dead_path() -> PathBuf {
+	outbox_dir().join("dead.json")
+}
+
+fn load_entries(p: &Path) -> Result<Vec<OutboxEntry>> {
+	if !p.exists() {
+		return Ok(Vec::new());
+	}
+	let s = crypto::read_to_string(p)?;
+	serde_json::from_str(&s).with_context(|| format!("parse {}", p.display()))
+}
+
+fn save_entries(p: &Path, entries: &[OutboxEntry]) -> Result<()> {
+	fs::create_dir_all(outbox_dir())?;
+	let tmp = p.with_extension("json.tmp");
+	crypto::write(&tmp, &serde_json::to_vec_pretty(entries)?)?;
+	fs::rename(tmp, p)?;
+	Ok(())
+}
+
+pub fn load_queue() -> Result<Vec<OutboxEntry>> {
+	load_entries(&queue_path())
+}
+
+pub fn load_dead() -> Result<Vec<OutboxEntry>> {
+	load_entries(&dead_path())
+}
+
+// submit 落盘排队后返回的错误上下文：调用方据此区分“稍后重试”和彻底失败
+#[derive(Debug)]
+pub struct Queued {
+	pub id: u64,
+}
+
+impl fmt::Display for Queued {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "已加入重试队列 #{}", self.id)
+	}
+}
+
+fn next_id(queue: &[OutboxEntry], dead: &[OutboxEntry]) -> u64 {
+	queue.iter().chain(dead).map(|e| e.id).max().unwrap_or(0) + 1
+}
+
+// 包在外发 backend 外面：send/updateGroup 失败就落盘排队，守护进程每个 tick 调 drive() 按退避重试，
+// 超过 max_attempts 进死信队列。同一群的动作保持先后顺序：队列里还有该群的条目时，新动作直接排在后面
+pub struct Outbox<B: Backend> {
+	inner: B,
+	cfg: OutboxConfig,
+}
+
+impl<B: Backend> Outbox<B> {
+	pub fn new(inner: B, cfg: &OutboxConfig) -> Self {
+		match load_queue() {
+			Ok(q) if !q.is_empty() => println!("[INF] outbox: {} queued action(s) from previous run", q.len()),
+			Ok(_) => {}
+			Err(e) => eprintln!("[WRN] outbox: {e:#}"),
+		}
+		Outbox {
+			inner,
+			cfg: cfg.clone(),
+		}
+	}
+
+	pub fn inner(&self) -> &B {
+		&self.inner
+	}
+
+	// 各群工作线程和 `magicbot outbox` 会并发读写：只在读写队列文件时锁 outbox 目录，
+	// 真正的发送不持锁，免得群与群互相等待
+	fn submit(&self, action: OutboundAction) -> Result<()> {
+		let blocked = self.inner.outbound_paused()
+			|| lock::with_dir_lock(&outbox_dir(), || {
+				Ok(load_queue()?.iter().any(|e| e.action.gid() == action.gid()))
+			})?;
+
+		let err = if blocked {
+			"queued behind earlier actions".to_string()
+		} else {
+			match action.run(&self.inner) {
+				Ok(()) => return Ok(()),
+				Err(e) => format!("{e:#}"),
+			}
+		};
+
+		let id = lock::with_dir_lock(&outbox_dir(), || {
+			let mut queue = load_queue()?;
+			let now = Utc::now().timestamp();
+			let id = next_id(&queue, &load_dead().unwrap_or_default());
+			let attempts = if blocked { 0 } else { 1 };
+			eprintln!("[WRN] outbox #{id}: {} for {} queued for retry: {err}", action.describe(), action.gid());
+			queue.push(OutboxEntry {
+				id,
+				created: now,
+				attempts,
+				next_at: if blocked { now } else { now + backoff(&self.cfg, attempts) },
+				last_error: err.clone(),
+				action,
+			});
+			save_entries(&queue_path(), &queue)?;
+			Ok(id)
+		})?;
+		Err(anyhow!(err).context(Queued { id }))
+	}
+
+	// 守护进程每个 tick 调用：重试到期的条目
+	pub fn drive(&self) {
+		if let Err(e) = self.drive_inner() {
+			eprintln!("[WRN] outbox: {e:#}");
+		}
+	}
+
+	// 发送不持锁（和 submit 一样）：持锁取快照，放锁逐条重试，再持锁把结果合并回当时的队列。
+	// 重试期间 submit 看到该群还有排队条目，新动作会排在后面，顺序不变
+	fn drive_inner(&self) -> Result<()> {
+		let snapshot = lock::with_dir_lock(&outbox_dir(), load_queue)?;
+		if snapshot.is_empty() || self.inner.outbound_paused() {
+			return Ok(());
+		}
+
+		let now = Utc::now().timestamp();
+		let mut results: Vec<(u64, Outcome)> = Vec::new();
+		let mut stalled: Vec<&str> = Vec::new();
+		for e in &snapshot {
+			// 要退出了：已重试的结果先落盘，剩下的留给下次启动
+			if signals::shutdown_requested() {
+				break;
+			}
+			let gid = e.action.gid();
+			if e.next_at > now || stalled.contains(&gid) || self.inner.outbound_paused() {
+				stalled.push(gid);
+				continue;
+			}
+			match e.action.run(&self.inner) {
+				Ok(()) => results.push((e.id, Outcome::Delivered)),
+				Err(err) => {
+					results.push((e.id, Outcome::Failed(format!("{err:#}"))));
+					stalled.push(gid);
+				}
+			}
+		}
+		if results.is_empty() {
+			return Ok(());
+		}
+
+		lock::with_dir_lock(&outbox_dir(), || {
+			let mut dead = load_dead()?;
+			let keep = apply_results(&self.cfg, load_queue()?, &mut dead, &results, now);
+			save_entries(&dead_path(), &dead)?;
+			save_entries(&queue_path(), &keep)?;
+			Ok(())
+		})
+	}
+}
+
+fn backoff(cfg: &OutboxConfig, attempts: u32) -> i64 {
+	let secs = cfg
+		.backoff_initial_secs
+		.max(1)
+		.saturating_mul(1u64 << attempts.saturating_sub(1).min(20));
+	secs.min(cfg.backoff_max_secs.max(1)) as i64
+}
+
+#[derive(Clone, Debug)]
+enum Outcome {
+	Delivered,
+	Failed(String),
+}
+
+// 把一轮重试的结果合并进队列：送达的删掉，失败的退避或进死信；重试期间新加的条目原样保留
+fn apply_results(
+	cfg: &OutboxConfig,
+	queue: Vec<OutboxEntry>,
+	dead: &mut Vec<OutboxEntry>,
+	results: &[(u64, Outcome)],
+	now: i64,
+) -> Vec<OutboxEntry> {
+	let mut keep = Vec::new();
+	for mut e in queue {
+		let Some((_, outcome)) = results.iter().find(|(id, _)| *id == e.id) else {
+			keep.push(e);
+			continue;
+		};
+		match outcome {
+			Outcome::Delivered => println!(
+				"[OK] outbox #{}: {} for {} delivered after {} attempt(s)",
+				e.id,
+				e.action.describe(),
+				e.action.gid(),
+				e.attempts + 1
+			),
+			Outcome::Failed(err) => {
+				e.attempts += 1;
+				e.last_error = err.clone();
+				if e.attempts >= cfg.max_attempts {
+					eprintln!(
+						"[WRN] outbox #{}: {} for {} dead-lettered after {} attempt(s): {}",
+						e.id,
+						e.action.describe(),
+						e.action.gid(),
+						e.attempts,
+						e.last_error
+					);
+					dead.push(e);
+				} else {
+					e.next_at = now + backoff(cfg, e.attempts);
+					keep.push(e);
+				}
+			}
+		}
+	}
+	keep
+}
+
+impl<B: Backend> Backend for Outbox<B> {
+	fn receive(&self) -> Result<Receiver<String>> {
+		self.inner.receive()
+	}
+
+	fn send_group_message(&self, gid: &str, msg: &str) -> Result<()> {
+		self.submit(OutboundAction::Send {
+			gid: gid.to_string(),
+			msg: msg.to_string(),
+		})
+	}
+
+	fn update_group(&self, gid: &str, upd: &GroupUpdate) -> Result<()> {
+		self.submit(OutboundAction::UpdateGroup {
+			gid: gid.to_string(),
+			upd: upd.clone(),
+		})
+	}
+
+	fn list_groups(&self) -> Result<Vec<GroupFull>> {
+		self.inner.list_groups()
+	}
+
+	fn list_contacts(&self) -> Result<Vec<Identity>> {
+		self.inner.list_contacts()
+	}
+
+	fn submit_rate_limit_challenge(&self, challenge: &str, captcha: &str) -> Result<()> {
+		self.inner.submit_rate_limit_challenge(challenge, captcha)
+	}
+
+	fn receiver_exit_reason(&self) -> Option<String> {
+		self.inner.receiver_exit_reason()
+	}
+
+	fn outbound_paused(&self) -> bool {
+		self.inner.outbound_paused()
+	}
+	fn invalidate(&self, scope: CacheScope) {
+		self.inner.invalidate(scope)
+	}
+
+	fn shutdown(&self) {
+		self.inner.shutdown();
+	}
+
+	fn receiver_healthy(&self) -> bool {
+		self.inner.receiver_healthy()
+	}
+}
+
+// 死信重新入队（ids 为空表示全部），守护进程下个 tick 就会重试
+// 和守护进程并发改同一份队列文件，所以也锁 outbox 目录
+pub fn redrive(ids: &[u64]) -> Result<usize> {
+	lock::with_dir_lock(&outbox_dir(), || {
+		let mut dead = load_dead()?;
+		let mut queue = load_queue()?;
+		let now = Utc::now().timestamp();
+		let (take, rest): (Vec<_>, Vec<_>) = dead.drain(..).partition(|e| ids.is_empty() || ids.contains(&e.id));
+		let n = take.len();
+		for mut e in take {
+			e.attempts = 0;
+			e.next_at = now;
+			queue.push(e);
+		}
+		queue.sort_by_key(|e| e.id);
+		save_entries(&queue_path(), &queue)?;
+		save_entries(&dead_path(), &rest)?;
+		Ok(n)
+	})
+}
+
+pub fn drop_dead(ids: &[u64]) -> Result<usize> {
+	lock::with_dir_lock(&outbox_dir(), || {
+		let dead = load_dead()?;
+		let before = dead.len();
+		let rest: Vec<_> = dead.into_iter().filter(|e| !ids.is_empty() && !ids.contains(&e.id)).collect();
+		save_entries(&dead_path(), &rest)?;
+		Ok(before - rest.len())
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn cfg() -> OutboxConfig {
+		OutboxConfig {
+			max_attempts: 3,
+			backoff_initial_secs: 10,
+			backoff_max_secs: 60,
+		}
+	}
+
+	fn entry(id: u64, gid: &str, attempts: u32) -> OutboxEntry {
+		OutboxEntry {
+			id,
+			created: 0,
+			attempts,
+			next_at: 0,
+			last_error: String::new(),
+			action: OutboundAction::Send {
+				gid: gid.to_string(),
+				msg: format!("m{id}"),
+			},
+		}
+	}
+
+	#[test]
+	fn backoff_doubles_then_caps() {
+		let c = cfg();
+		assert_eq!(backoff(&c, 0), 10);
+		assert_eq!(backoff(&c, 1), 10);
+		assert_eq!(backoff(&c, 2), 20);
+		assert_eq!(backoff(&c, 3), 40);
+		assert_eq!(backoff(&c, 4), 60);
+		assert_eq!(backoff(&c, 1000), 60);
+	}
+
+	#[test]
+	fn backoff_handles_zero_config() {
+		let c = OutboxConfig {
+			max_attempts: 1,
+			backoff_initial_secs: 0,
+			backoff_max_secs: 0,
+		};
+		assert_eq!(backoff(&c, 5), 1);
+		let big = OutboxConfig {
+			backoff_initial_secs: u64::MAX,
+			backoff_max_secs: 3600,
+			..c
+		};
+		assert_eq!(backoff(&big, 30), 3600);
+	}
+
+	#[test]
+	fn failure_below_max_attempts_backs_off() {
+		let mut dead = vec![];
+		let results = [(1, Outcome::Failed("boom".to_string()))];
+		let keep = apply_results(&cfg(), vec![entry(1, "G1", 1)], &mut dead, &results, 100);
+		assert!(dead.is_empty());
+		assert_eq!(keep.len(), 1);
+		assert_eq!(keep[0].attempts, 2);
+		assert_eq!(keep[0].next_at, 120);
+		assert_eq!(keep[0].last_error, "boom");
+	}
+
+	#[test]
+	fn dead_letters_at_max_attempts() {
+		let mut dead = vec![];
+		let results = [(1, Outcome::Failed("boom".to_string()))];
+		let keep = apply_results(&cfg(), vec![entry(1, "G1", 2), entry(2, "G1", 0)], &mut dead, &results, 100);
+		assert_eq!(dead.len(), 1);
+		assert_eq!(dead[0].id, 1);
+		assert_eq!(dead[0].attempts, 3);
+		assert_eq!(keep.iter().map(|e| e.id).collect::<Vec<_>>(), vec![2]);
+	}
+
+	#[test]
+	fn delivered_removed_and_new_entries_kept() {
+		let mut dead = vec![];
+		let results = [(1, Outcome::Delivered)];
+		// #3 是重试期间 submit 追加的，不在结果里
+		let queue = vec![entry(1, "G1", 1), entry(3, "G2", 0)];
+		let keep = apply_results(&cfg(), queue, &mut dead, &results, 100);
+		assert!(dead.is_empty());
+		assert_eq!(keep.iter().map(|e| e.id).collect::<Vec<_>>(), vec![3]);
+		assert_eq!(keep[0].attempts, 0);
+	}
+}
@@ -0,0 +1,145 @@
+use crate::privs;
+use std::env;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+// root / 服务用户（系统部署）的默认路径
+const SYSTEM_STATE_DIR: &str = "/var/lib/magicbot";
+const SYSTEM_RUN_DIR: &str = "/run/magicbot";
+const SYSTEM_LOG_DIR: &str = "/var/log/magicbot";
+const SYSTEM_UNIT: &str = "/etc/systemd/system/magicbot.service";
+
+// 每项依次取：命令行参数 > 环境变量 > 默认值
+const OVERRIDES: [(&str, &str); 4] = [
+	("--state-dir", "MAGICBOT_STATE_DIR"),
+	("--run-dir", "MAGICBOT_RUN_DIR"),
+	("--log-dir", "MAGICBOT_LOG_DIR"),
+	("--systemd-unit", "MAGICBOT_SYSTEMD_UNIT"),
+];
+
+#[derive(Clone, Debug)]
+pub struct Paths {
+	pub state: PathBuf,
+	pub run: PathBuf,
+	pub log: PathBuf,
+	pub unit: PathBuf,
+	// systemd --user 模式：unit 装到 ~/.config/systemd/user，不需要 root 也不切服务用户
+	pub user: bool,
+}
+
+static PATHS: OnceLock<Paths> = OnceLock::new();
+
+fn home() -> PathBuf {
+	env::var_os("HOME").map(PathBuf::from).unwrap_or_else(|| PathBuf::from("/"))
+}
+
+fn xdg(var: &str, fallback: &str) -> PathBuf {
+	env::var_os(var)
+		.map(PathBuf::from)
+		.filter(|p| p.is_absolute())
+		.unwrap_or_else(|| home().join(fallback))
+}
+
+// root 或者就是服务用户本身（systemd 以 User=magicbot 启动守护进程）时用系统路径
+fn system_account() -> bool {
+	let uid = unsafe { libc::getuid() };
+	uid == 0 || privs::lookup().is_some_and(|u| u.uid == uid)
+}
+
+fn defaults(user: bool) -> [PathBuf; 4] {
+	if !user {
+		return [SYSTEM_STATE_DIR, SYSTEM_RUN_DIR, SYSTEM_LOG_DIR, SYSTEM_UNIT].map(PathBuf::from);
+	}
+	let state = xdg("XDG_STATE_HOME", ".local/state").join("magicbot");
+	let run = match env::var_os("XDG_RUNTIME_DIR").map(PathBuf::from).filter(|p| p.is_absolute()) {
+		Some(d) => d.join("magicbot"),
+		None => state.join("run"),
+	};
+	let log = state.join("log");
+	let unit = xdg("XDG_CONFIG_HOME", ".config").join("systemd/user/magicbot.service");
+	[state, run, log, unit]
+}
+
+fn resolve(args: &[String], user: bool) -> Paths {
+	let [mut state, mut run, mut log, mut unit] = defaults(user);
+	for (i, slot) in [&mut state, &mut run, &mut log, &mut unit].into_iter().enumerate() {
+		let (flag, var) = OVERRIDES[i];
+		let from_flag = args.windows(2).find(|w| w[0] == flag).map(|w| w[1].clone());
+		if let Some(v) = from_flag.or_else(|| env::var(var).ok()).filter(|v| !v.is_empty()) {
+			*slot = PathBuf::from(v);
+		}
+	}
+	Paths {
+		state,
+		run,
+		log,
+		unit,
+		user,
+	}
+}
+
+// real_main 最先调用：解析并去掉路径相关参数，返回剩下的参数（子命令仍在 args[1]）
+pub fn init(args: Vec<String>) -> Vec<String> {
+	let user = args.iter().any(|a| a == "--user") || !system_account();
+	let _ = PATHS.set(resolve(&args, user));
+
+	let mut rest = Vec::with_capacity(args.len());
+	let mut it = args.into_iter();
+	while let Some(a) = it.next() {
+		if a == "--user" {
+			continue;
+		}
+		if OVERRIDES.iter().any(|(flag, _)| *flag == a) {
+			it.next();
+			continue;
+		}
+		rest.push(a);
+	}
+	rest
+}
+
+fn get() -> &'static Paths {
+	PATHS.get_or_init(|| resolve(&[], !system_account()))
+}
+
+pub fn state_dir() -> &'static Path {
+	&get().state
+}
+
+pub fn run_dir() -> &'static Path {
+	&get().run
+}
+
+pub fn log_dir() -> &'static Path {
+	&get().log
+}
+
+pub fn unit_path() -> &'static Path {
+	&get().unit
+}
+
+pub fn user_mode() -> bool {
+	get().user
+}
+
+// systemctl 用的服务名：unit 文件名去掉 .service（第二个实例可以装成 magicbot-2.service）
+pub fn unit_name() -> String {
+	unit_path()
+		.file_stem()
+		.map(|s| s.to_string_lossy().into_owned())
+		.unwrap_or_else(|| crate::APP.to_string())
+}
+
+// 写进 unit 的 Environment=，让 systemd 启动的守护进程用同一套路径
+pub fn unit_env() -> Vec<String> {
+	vec![
+		format!("MAGICBOT_STATE_DIR={}", state_dir().display()),
+		format!("MAGICBOT_RUN_DIR={}", run_dir().display()),
+		format!("MAGICBOT_LOG_DIR={}", log_dir().display()),
+	]
+}
+
+// 系统部署且运行目录就是 /run/magicbot 时交给 systemd 的 RuntimeDirectory= 管
+pub fn default_run_dir() -> bool {
+	run_dir() == Path::new(SYSTEM_RUN_DIR)
+}
@@ -0,0 +1,202 @@
+use crate::{cli, paths, run_ok};
+use anyhow::{anyhow, Context, Result};
+use std::env;
+use std::ffi::{CStr, CString};
+use std::fs;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+// 与 package-rhel.sh 里的 SERVICE_USER / SERVICE_GROUP 一致
+pub const SERVICE_USER: &str = "magicbot";
+pub const SERVICE_GROUP: &str = "magicbot";
+
+#[derive(Clone, Debug)]
+pub struct ServiceUser {
+	pub uid: libc::uid_t,
+	pub gid: libc::gid_t,
+	pub home: PathBuf,
+}
+
+pub fn lookup() -> Option<ServiceUser> {
+	let name = CString::new(SERVICE_USER).ok()?;
+	let pw = unsafe { libc::getpwnam(name.as_ptr()) };
+	if pw.is_null() {
+		return None;
+	}
+	let pw = unsafe { &*pw };
+	let home = unsafe { CStr::from_ptr(pw.pw_dir) }.to_string_lossy().into_owned();
+	Some(ServiceUser {
+		uid: pw.pw_uid,
+		gid: pw.pw_gid,
+		home: PathBuf::from(home),
+	})
+}
+
+pub fn is_root() -> bool {
+	unsafe { libc::getuid() == 0 }
+}
+
+// signal-cli 默认把账号数据放在 $XDG_DATA_HOME/signal-cli（没有就是 ~/.local/share）；
+// 这里让它落在服务用户的 home 下，守护进程和 TUI 用同一份
+pub fn signal_cli_data_dir(u: &ServiceUser) -> PathBuf {
+	u.home.join(".local/share/signal-cli")
+}
+
+fn set_user_env(u: &ServiceUser) {
+	env::set_var("HOME", &u.home);
+	env::set_var("USER", SERVICE_USER);
+	env::set_var("XDG_DATA_HOME", u.home.join(".local/share"));
+}
+
+// 守护进程不需要 root：以 root 启动时彻底切到服务用户（回不去）
+pub fn drop_to_service_user() -> Result<()> {
+	if unsafe { libc::geteuid() } != 0 {
+		return Ok(());
+	}
+	let Some(u) = lookup() else {
+		println!("[WRN] service user {SERVICE_USER} not found, daemon keeps running as root (install the systemd unit to create it)");
+		return Ok(());
+	};
+	prepare_dirs(&u)?;
+	unsafe {
+		if libc::setgroups(1, &u.gid) != 0 || libc::setgid(u.gid) != 0 || libc::setuid(u.uid) != 0 {
+			return Err(anyhow!("switch to {SERVICE_USER}: {}", std::io::Error::last_os_error()));
+		}
+	}
+	set_user_env(&u);
+	println!("[INF] running as {SERVICE_USER} (uid {})", u.uid);
+	Ok(())
+}
+
+// TUI 以 root 打开时：平时以服务用户身份读写（文件属主正确），
+// 只有安装依赖/systemd unit 这类操作临时切回 root（见 as_root）
+pub fn enter_service_user() -> Result<()> {
+	if unsafe { libc::geteuid() } != 0 {
+		return Ok(());
+	}
+	let Some(u) = lookup() else {
+		println!("[WRN] 服务用户 {SERVICE_USER} 不存在，本次以 root 运行；安装 systemd unit 时会自动创建。");
+		return Ok(());
+	};
+	prepare_dirs(&u)?;
+	unsafe {
+		if libc::setgroups(1, &u.gid) != 0 || libc::setegid(u.gid) != 0 || libc::seteuid(u.uid) != 0 {
+			return Err(anyhow!("switch to {SERVICE_USER}: {}", std::io::Error::last_os_error()));
+		}
+	}
+	set_user_env(&u);
+	Ok(())
+}
+
+// 临时切回 root 执行 f。enter_service_user 只改了有效 uid/gid，真实 uid 一直是 0，
+// 所以进程随时能切回来：这是为了文件属主正确，不是安全边界。切回服务用户失败时直接 abort，
+// 不能带着 root 身份继续跑
+pub fn as_root<T>(f: impl FnOnce() -> Result<T>) -> Result<T> {
+	let (euid, egid) = unsafe { (libc::geteuid(), libc::getegid()) };
+	if euid == 0 {
+		return f();
+	}
+	if !is_root() {
+		return Err(cli::fail(cli::EXIT_DENIED, "Must run as root."));
+	}
+	unsafe {
+		if libc::seteuid(0) != 0 || libc::setegid(0) != 0 {
+			return Err(anyhow!("regain root: {}", std::io::Error::last_os_error()));
+		}
+	}
+	let r = f();
+	unsafe {
+		if libc::setegid(egid) != 0 || libc::seteuid(euid) != 0 {
+			eprintln!("[ERR] drop back to {SERVICE_USER}: {}", std::io::Error::last_os_error());
+			std::process::abort();
+		}
+	}
+	r
+}
+
+// 和 package-rhel.sh 的 %pre 一样建系统用户，home 指向 STATE_DIR
+pub fn ensure_service_user() -> Result<ServiceUser> {
+	if let Some(u) = lookup() {
+		return Ok(u);
+	}
+	println!("[INF] creating service user {SERVICE_USER}");
+	let has_group = Command::new("getent")
+		.arg("group")
+		.arg(SERVICE_GROUP)
+		.output()
+		.is_ok_and(|o| o.status.success());
+	if !has_group {
+		run_ok(Command::new("groupadd").arg("-r").arg(SERVICE_GROUP))?;
+	}
+	run_ok(
+		Command::new("useradd")
+			.arg("-r")
+			.arg("-g")
+			.arg(SERVICE_GROUP)
+			.arg("-d")
+			.arg(paths::state_dir())
+			.arg("-s")
+			.arg("/sbin/nologin")
+			.arg("-c")
+			.arg("magicbot service user")
+			.arg(SERVICE_USER),
+	)?;
+	lookup().ok_or_else(|| anyhow!("useradd {SERVICE_USER} succeeded but user not found"))
+}
+
+fn owned_by(p: &Path, u: &ServiceUser) -> bool {
+	fs::metadata(p).is_ok_and(|m| m.uid() == u.uid && m.gid() == u.gid)
+}
+
+// 状态/运行/日志目录归服务用户、0750。老版本以 root 跑过的目录整棵改属主
+pub fn prepare_dirs(u: &ServiceUser) -> Result<()> {
+	for p in [paths::state_dir(), paths::run_dir(), paths::log_dir()] {
+		let d = p.display();
+		if !p.exists() {
+			fs::create_dir_all(p).with_context(|| format!("create dir {d}"))?;
+		}
+		if !owned_by(p, u) {
+			println!("[INF] chown -R {SERVICE_USER}:{SERVICE_GROUP} {d}");
+			run_ok(
+				Command::new("chown")
+					.arg("-R")
+					.arg(format!("{SERVICE_USER}:{SERVICE_GROUP}"))
+					.arg(p),
+			)?;
+		}
+		fs::set_permissions(p, fs::Permissions::from_mode(0o750)).with_context(|| format!("chmod {d}"))?;
+	}
+	Ok(())
+}
+
+// 以前以 root 跑时 signal-cli 数据在 /root/.local/share/signal-cli：复制一份给服务用户
+pub fn migrate_root_signal_data(u: &ServiceUser) -> Result<()> {
+	let old = Path::new("/root/.local/share/signal-cli");
+	let new = signal_cli_data_dir(u);
+	if !old.exists() || new.exists() {
+		return Ok(());
+	}
+	println!("[INF] copying signal-cli data {} -> {}", old.display(), new.display());
+	if let Some(parent) = new.parent() {
+		fs::create_dir_all(parent)?;
+	}
+	run_ok(Command::new("cp").arg("-a").arg(old).arg(&new))?;
+	run_ok(
+		Command::new("chown")
+			.arg("-R")
+			.arg(format!("{SERVICE_USER}:{SERVICE_GROUP}"))
+			.arg(u.home.join(".local")),
+	)?;
+	Ok(())
+}
+
+// 自定义的 signal-cli --config 目录也要归服务用户
+pub fn chown_dir(dir: &Path) -> Result<()> {
+	run_ok(
+		Command::new("chown")
+			.arg("-R")
+			.arg(format!("{SERVICE_USER}:{SERVICE_GROUP}"))
+			.arg(dir),
+	)
+}
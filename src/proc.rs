@@ -0,0 +1,105 @@
+use crate::alerts;
+use std::collections::VecDeque;
+use std::io::{BufRead, BufReader};
+use std::os::unix::process::CommandExt;
+use std::process::{Child, ChildStderr, Command};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const TAIL_LINES: usize = 20;
+// SIGTERM 之后等 signal-cli 自己收尾（保存状态、断开连接）的时间
+const TERM_GRACE: Duration = Duration::from_secs(5);
+
+// 持续读 signal-cli 的 stderr（避免管道写满卡住子进程）：按级别转发到日志、识别需告警的状况，
+// 并保留最后几行用于退出诊断
+#[derive(Clone, Default)]
+pub struct StderrTail(Arc<Mutex<VecDeque<String>>>);
+
+impl StderrTail {
+	pub fn lines(&self) -> Vec<String> {
+		self.0.lock().unwrap().iter().cloned().collect()
+	}
+}
+
+pub fn drain_stderr(stderr: ChildStderr) -> StderrTail {
+	let tail = StderrTail::default();
+	let t = tail.clone();
+	thread::spawn(move || {
+		for line in BufReader::new(stderr).lines() {
+			let Ok(line) = line else { break };
+			alerts::forward_stderr_line(&line);
+			let mut q = t.0.lock().unwrap();
+			if q.len() >= TAIL_LINES {
+				q.pop_front();
+			}
+			q.push_back(line);
+		}
+	});
+	tail
+}
+
+// stdout 已关闭后调用：等子进程退出（最多几秒，否则强杀），返回 "exit code + stderr 末尾"
+pub fn describe_exit(child: &mut Child, tail: &StderrTail) -> String {
+	let deadline = Instant::now() + Duration::from_secs(3);
+	let status = loop {
+		match child.try_wait() {
+			Ok(Some(st)) => break Some(st),
+			Ok(None) if Instant::now() < deadline => thread::sleep(Duration::from_millis(100)),
+			Ok(None) => {
+				let _ = child.kill();
+				break child.wait().ok();
+			}
+			Err(_) => break None,
+		}
+	};
+
+	let code = match status {
+		Some(st) => match st.code() {
+			Some(c) => format!("exit code {c}"),
+			None => format!("terminated ({st})"),
+		},
+		None => "exit status unknown".to_string(),
+	};
+
+	// stderr 线程可能还没读完最后几行
+	thread::sleep(Duration::from_millis(200));
+	let lines = tail.lines();
+	if lines.is_empty() {
+		return code;
+	}
+	let last = lines.iter().rev().take(5).rev().cloned().collect::<Vec<_>>().join(" | ");
+	format!("{code}; stderr: {last}")
+}
+
+// 常驻子进程（receive / jsonRpc）放进自己的进程组：终端 Ctrl+C 不会直接打到它，由守护进程收尾；
+// 守护进程被强杀时内核给它发 SIGTERM，不会留下孤儿进程
+pub fn supervise_child(cmd: &mut Command) {
+	cmd.process_group(0);
+	unsafe {
+		cmd.pre_exec(|| {
+			libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGTERM);
+			Ok(())
+		});
+	}
+}
+
+// 先 SIGTERM，宽限期内没退出再强杀
+pub fn terminate(child: &mut Child) {
+	if !matches!(child.try_wait(), Ok(None)) {
+		return;
+	}
+	unsafe {
+		libc::kill(child.id() as libc::pid_t, libc::SIGTERM);
+	}
+	let deadline = Instant::now() + TERM_GRACE;
+	while Instant::now() < deadline {
+		if !matches!(child.try_wait(), Ok(None)) {
+			return;
+		}
+		thread::sleep(Duration::from_millis(100));
+	}
+	eprintln!("[WRN] signal-cli (pid {}) ignored SIGTERM, killing", child.id());
+	let _ = child.kill();
+	let _ = child.wait();
+}
@@ -0,0 +1,420 @@
+use crate::alerts::{self, Condition, DaemonEvent};
+use crate::backend::{Backend, CacheScope, GroupFull, GroupUpdate, Identity};
+use crate::{crypto, lock, paths};
+use anyhow::{anyhow, Context, Result};
+use chrono::Utc;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::Receiver;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HitKind {
+	ProofRequired,
+	RateLimit,
+}
+
+// 作为错误沿 anyhow 链往上抛，RateLimitGuard 直接 downcast 拿到结构化信息；
+// retry_after 为 None 表示没给时间，只能等 captcha 提交
+#[derive(Clone, Debug)]
+pub struct RateLimitHit {
+	pub kind: HitKind,
+	pub challenge: Option<String>,
+	pub retry_after: Option<u64>,
+}
+
+impl fmt::Display for RateLimitHit {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self.kind {
+			HitKind::ProofRequired => write!(f, "proof required")?,
+			HitKind::RateLimit => write!(f, "rate limited")?,
+		}
+		if let Some(c) = &self.challenge {
+			write!(f, ", challenge token {c}")?;
+		}
+		if let Some(s) = self.retry_after {
+			write!(f, ", retry after {s}s")?;
+		}
+		Ok(())
+	}
+}
+
+impl std::error::Error for RateLimitHit {}
+
+// 暂停状态落盘在 STATE_DIR/ratelimit.json，CLI 据此知道 challenge token 和是否由守护进程代为提交
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PauseState {
+	pub since: i64,
+	pub challenge: Option<String>,
+	pub resume_at: Option<i64>,
+	pub detail: String,
+	pub daemon_pid: u32,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct Submission {
+	challenge: Option<String>,
+	captcha: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct SubmissionResult {
+	ok: bool,
+	error: Option<String>,
+}
+
+pub fn pause_path() -> PathBuf {
+	paths::state_dir().join("ratelimit.json")
+}
+
+fn submission_path() -> PathBuf {
+	paths::run_dir().join("ratelimit-submit.json")
+}
+
+fn result_path() -> PathBuf {
+	paths::run_dir().join("ratelimit-result.json")
+}
+
+// 只有文本可用时（子进程 stderr、jsonRpc 错误消息）才从里面识别限流与 proof required，并抓 challenge token
+pub fn detect(text: &str) -> Option<RateLimitHit> {
+	let l = text.to_lowercase();
+	let kind = if l.contains("proof required") || l.contains("proof_required") || l.contains("proofrequired") {
+		HitKind::ProofRequired
+	} else if l.contains("rate limit") || l.contains("rate_limit") || l.contains("ratelimit") {
+		HitKind::RateLimit
+	} else {
+		return None;
+	};
+
+	let challenge = [
+		r#"challenge token "([A-Za-z0-9_\-]+)""#,
+		r"--challenge\s+([A-Za-z0-9_\-]{8,})",
+		r#"(?i)"?token"?\s*[:=]\s*"?([0-9a-f]{8}-[0-9a-f]{4}-[0-9a-f]{4}-[0-9a-f]{4}-[0-9a-f]{12})"#,
+	]
+	.iter()
+	.filter_map(|p| Regex::new(p).ok())
+	.find_map(|re| re.captures(text).map(|c| c[1].to_string()))
+	.filter(|t| t != "<CHALLENGE_TOKEN>");
+
+	let retry_after = Regex::new(r#"(?i)(?:wait\s+"?|retryAfterSeconds"?\s*[:=]\s*)(\d+)"#)
+		.ok()
+		.and_then(|re| re.captures(text))
+		.and_then(|c| c[1].parse().ok())
+		.filter(|s| *s > 0);
+
+	Some(RateLimitHit {
+		kind,
+		challenge,
+		retry_after,
+	})
+}
+
+fn pause_state(hit: &RateLimitHit, detail: &str, now: i64) -> PauseState {
+	PauseState {
+		since: now,
+		challenge: hit.challenge.clone(),
+		resume_at: hit.retry_after.map(|s| now + s as i64),
+		detail: detail.chars().take(500).collect(),
+		daemon_pid: std::process::id(),
+	}
+}
+
+pub fn load_pause() -> Option<PauseState> {
+	let s = crypto::read_to_string(&pause_path()).ok()?;
+	serde_json::from_str(&s).ok()
+}
+
+// 和其它状态文件一样：锁目录、写临时文件再 rename（challenge token 随状态文件一起加密）
+fn write_locked(p: &Path, data: &[u8]) -> Result<()> {
+	let dir = p.parent().ok_or_else(|| anyhow!("no parent dir: {}", p.display()))?;
+	let tmp = p.with_extension("json.tmp");
+	lock::with_dir_lock(dir, || {
+		crypto::write(&tmp, data)?;
+		fs::rename(&tmp, p).with_context(|| format!("write {}", p.display()))?;
+		Ok(())
+	})
+}
+
+fn remove_locked(p: &Path) {
+	if let Some(dir) = p.parent() {
+		let _ = lock::with_dir_lock(dir, || {
+			let _ = fs::remove_file(p);
+			Ok(())
+		});
+	}
+}
+
+fn save_pause(st: &PauseState) -> Result<()> {
+	write_locked(&pause_path(), &serde_json::to_vec_pretty(st)?)
+}
+
+pub fn clear_pause() {
+	remove_locked(&pause_path());
+}
+
+// 包在真正的 backend 外面：发现限流就暂停所有外发（send/updateGroup），告警，
+// 等 challenge 提交成功或 retry-after 到期再恢复
+pub struct RateLimitGuard<B: Backend> {
+	inner: B,
+	paused: Mutex<Option<PauseState>>,
+}
+
+impl<B: Backend> RateLimitGuard<B> {
+	pub fn new(inner: B) -> Self {
+		let mut paused = load_pause();
+		if let Some(st) = &mut paused {
+			eprintln!("[WRN] outbound paused since {} (rate limited): {}", st.since, st.detail);
+			st.daemon_pid = std::process::id();
+			let _ = save_pause(st);
+		}
+		RateLimitGuard {
+			inner,
+			paused: Mutex::new(paused),
+		}
+	}
+
+	pub fn is_paused(&self) -> bool {
+		let mut guard = self.paused.lock().unwrap();
+		if let Some(at) = guard.as_ref().and_then(|st| st.resume_at) {
+			if Utc::now().timestamp() >= at {
+				println!("[OK] rate limit retry-after elapsed, resuming outbound traffic");
+				*guard = None;
+				clear_pause();
+			}
+		}
+		guard.is_some()
+	}
+
+	fn outbound<T>(&self, what: &str, f: impl FnOnce() -> Result<T>) -> Result<T> {
+		if self.is_paused() {
+			return Err(anyhow!("outbound paused (rate limited), skipped {what}"));
+		}
+		let r = f();
+		if let Err(e) = &r {
+			let text = format!("{e:#}");
+			let hit = e.downcast_ref::<RateLimitHit>().cloned().or_else(|| detect(&text));
+			if let Some(hit) = hit {
+				self.pause(hit, &text);
+			}
+		}
+		r
+	}
+
+	fn pause(&self, hit: RateLimitHit, detail: &str) {
+		let st = pause_state(&hit, detail, Utc::now().timestamp());
+		if let Err(e) = save_pause(&st) {
+			eprintln!("[WRN] save {}: {e:#}", pause_path().display());
+		}
+		*self.paused.lock().unwrap() = Some(st);
+
+		let how = match (&hit.challenge, hit.retry_after) {
+			(Some(c), _) => format!(
+				"challenge token {c}; 生成 captcha 后执行: magicbot captcha submit --captcha 'signalcaptcha://...'"
+			),
+			(None, Some(s)) => format!("{s} 秒后自动恢复"),
+			(None, None) => "执行: magicbot captcha submit --challenge <token> --captcha 'signalcaptcha://...'".to_string(),
+		};
+		alerts::raise(&DaemonEvent::new(
+			Condition::OutboundPaused,
+			&format!("outbound paused ({hit}). {how}"),
+		));
+	}
+
+	// 守护进程空闲时调用：CLI 放下的 captcha 由这里经现有连接提交（账号被守护进程占用时子进程会卡住）
+	pub fn poll_submission(&self) {
+		let p = submission_path();
+		if !p.exists() {
+			return;
+		}
+		let Ok(s) = crypto::read_to_string(&p) else { return };
+		remove_locked(&p);
+		let Ok(sub) = serde_json::from_str::<Submission>(&s) else { return };
+
+		let challenge = sub
+			.challenge
+			.or_else(|| self.paused.lock().unwrap().as_ref().and_then(|st| st.challenge.clone()))
+			.unwrap_or_default();
+		let r = self.inner.submit_rate_limit_challenge(&challenge, &sub.captcha);
+		let res = match &r {
+			Ok(_) => {
+				println!("[OK] rate limit challenge accepted, resuming outbound traffic");
+				*self.paused.lock().unwrap() = None;
+				clear_pause();
+				SubmissionResult { ok: true, error: None }
+			}
+			Err(e) => {
+				eprintln!("[WRN] submitRateLimitChallenge failed: {e:#}");
+				SubmissionResult {
+					ok: false,
+					error: Some(format!("{e:#}")),
+				}
+			}
+		};
+		if let Ok(b) = serde_json::to_vec(&res) {
+			if let Err(e) = write_locked(&result_path(), &b) {
+				eprintln!("[WRN] save {}: {e:#}", result_path().display());
+			}
+		}
+	}
+}
+
+impl<B: Backend> Backend for RateLimitGuard<B> {
+	fn receive(&self) -> Result<Receiver<String>> {
+		self.inner.receive()
+	}
+
+	fn send_group_message(&self, gid: &str, msg: &str) -> Result<()> {
+		self.outbound("send", || self.inner.send_group_message(gid, msg))
+	}
+
+	fn update_group(&self, gid: &str, upd: &GroupUpdate) -> Result<()> {
+		self.outbound("updateGroup", || self.inner.update_group(gid, upd))
+	}
+
+	fn list_groups(&self) -> Result<Vec<GroupFull>> {
+		self.inner.list_groups()
+	}
+
+	fn list_contacts(&self) -> Result<Vec<Identity>> {
+		self.inner.list_contacts()
+	}
+
+	fn submit_rate_limit_challenge(&self, challenge: &str, captcha: &str) -> Result<()> {
+		self.inner.submit_rate_limit_challenge(challenge, captcha)
+	}
+
+	fn receiver_exit_reason(&self) -> Option<String> {
+		self.inner.receiver_exit_reason()
+	}
+
+	fn outbound_paused(&self) -> bool {
+		self.is_paused()
+	}
+	fn invalidate(&self, scope: CacheScope) {
+		self.inner.invalidate(scope)
+	}
+
+	fn shutdown(&self) {
+		self.inner.shutdown();
+	}
+
+	fn receiver_healthy(&self) -> bool {
+		self.inner.receiver_healthy()
+	}
+}
+
+// `magicbot captcha submit`：守护进程在跑就交给它提交（账号被它的 jsonRpc 占着，另起 signal-cli 会卡住），
+// 否则直接起 signal-cli
+pub fn submit(acc: &str, cfgdir: Option<&str>, challenge: Option<String>, captcha: &str) -> Result<()> {
+	let paused = load_pause();
+	let challenge = challenge.or_else(|| paused.as_ref().and_then(|st| st.challenge.clone()));
+
+	if let Some(pid) = lock::running_pid() {
+		println!("[INF] 守护进程(pid {pid})在运行，交给它提交 ...");
+		remove_locked(&result_path());
+		let sub = Submission {
+			challenge,
+			captcha: captcha.to_string(),
+		};
+		write_locked(&submission_path(), &serde_json::to_vec(&sub)?)?;
+
+		let deadline = Instant::now() + Duration::from_secs(90);
+		while Instant::now() < deadline {
+			if result_path().exists() {
+				let s = crypto::read_to_string(&result_path())?;
+				remove_locked(&result_path());
+				let res: SubmissionResult = serde_json::from_str(&s)?;
+				if res.ok {
+					return Ok(());
+				}
+				return Err(anyhow!(
+					"submitRateLimitChallenge failed: {}",
+					res.error.unwrap_or_default()
+				));
+			}
+			thread::sleep(Duration::from_millis(500));
+		}
+		remove_locked(&submission_path());
+		return Err(anyhow!("守护进程 90 秒内没有处理提交"));
+	}
+
+	let mut cmd = crate::signal_cli(cfgdir, acc);
+	cmd.arg("submitRateLimitChallenge");
+	if let Some(c) = challenge.as_deref().filter(|c| !c.trim().is_empty()) {
+		cmd.arg("--challenge").arg(c.trim());
+	}
+	cmd.arg("--captcha").arg(captcha.trim());
+	crate::run_ok(&mut cmd)?;
+	clear_pause();
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn detect_ignores_unrelated_errors() {
+		assert!(detect("Failed to send message: network unreachable").is_none());
+		assert!(detect("").is_none());
+	}
+
+	#[test]
+	fn detect_cli_proof_required_with_token() {
+		let text = "Failed to send message due to rate limiting: ProofRequired. \
+			To continue, solve the captcha and run: \
+			signal-cli -a +15550001 submitRateLimitChallenge --challenge 0123abcd-ef --captcha CAPTCHA";
+		let hit = detect(text).unwrap();
+		assert_eq!(hit.kind, HitKind::ProofRequired);
+		assert_eq!(hit.challenge.as_deref(), Some("0123abcd-ef"));
+		assert_eq!(hit.retry_after, None);
+	}
+
+	#[test]
+	fn detect_quoted_challenge_token() {
+		let hit = detect(r#"Proof required, challenge token "abc_DEF-123""#).unwrap();
+		assert_eq!(hit.challenge.as_deref(), Some("abc_DEF-123"));
+	}
+
+	#[test]
+	fn detect_rate_limit_wait_without_token() {
+		let hit = detect("Rate limit exceeded, wait 120 seconds").unwrap();
+		assert_eq!(hit.kind, HitKind::RateLimit);
+		assert_eq!(hit.challenge, None);
+		assert_eq!(hit.retry_after, Some(120));
+	}
+
+	#[test]
+	fn detect_zero_wait_is_no_retry() {
+		assert_eq!(detect("rate limited, wait 0 seconds").unwrap().retry_after, None);
+	}
+
+	#[test]
+	fn detect_skips_placeholder_token() {
+		let hit = detect("proof required: submitRateLimitChallenge --challenge <CHALLENGE_TOKEN> --captcha x").unwrap();
+		assert_eq!(hit.challenge, None);
+	}
+
+	#[test]
+	fn pause_without_retry_waits_for_captcha() {
+		let hit = RateLimitHit {
+			kind: HitKind::ProofRequired,
+			challenge: Some("tok".to_string()),
+			retry_after: None,
+		};
+		let st = pause_state(&hit, "x", 1000);
+		assert_eq!(st.resume_at, None);
+		assert_eq!(st.challenge.as_deref(), Some("tok"));
+
+		let hit = RateLimitHit {
+			retry_after: Some(60),
+			..hit
+		};
+		assert_eq!(pause_state(&hit, "x", 1000).resume_at, Some(1060));
+	}
+}
@@ -0,0 +1,87 @@
+use crate::backend::Backend;
+use crate::{apply_takeover_permissions, refresh_group_state, short_id, sync_member_snapshot, GroupRuntime};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+// 旧版 signal-cli 的 listGroups 不带权限字段，只提示一次
+static PERM_UNKNOWN_WARNED: AtomicBool = AtomicBool::new(false);
+
+// global.json 里的 "reconcile" 段
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ReconcileConfig {
+	// 每隔多久主动 listGroups 校对一次，0 表示只靠 UPDATE 事件
+	pub interval_secs: u64,
+}
+
+impl Default for ReconcileConfig {
+	fn default() -> Self {
+		ReconcileConfig { interval_secs: 300 }
+	}
+}
+
+// None 表示 signal-cli 没报这一项，当作未知而不是漂移
+fn same_perm(actual: Option<&str>, desired: &str) -> Option<bool> {
+	actual.map(|a| a.eq_ignore_ascii_case(desired))
+}
+
+fn ids(set: &BTreeSet<String>) -> String {
+	set.iter().map(|s| short_id(s)).collect::<Vec<_>>().join(",")
+}
+
+// 不依赖 UPDATE 事件：重新拉一次群信息，对比管理员/成员/群权限，
+// 错过的进退群补上，群权限被改掉时重新接管
+pub fn reconcile_group(backend: &dyn Backend, rt: &mut GroupRuntime) -> Result<()> {
+	let gid = short_id(&rt.cfg.group_id);
+	let prev_admins = rt.admins.clone();
+	let prev_bot_admin = rt.state.bot_has_admin;
+
+	let g = refresh_group_state(backend, rt)?;
+
+	let gained: BTreeSet<String> = rt.admins.difference(&prev_admins).cloned().collect();
+	let lost: BTreeSet<String> = prev_admins.difference(&rt.admins).cloned().collect();
+	if !gained.is_empty() || !lost.is_empty() {
+		println!("[INF] reconcile {gid}: admins +[{}] -[{}]", ids(&gained), ids(&lost));
+	}
+	if prev_bot_admin != rt.state.bot_has_admin {
+		println!("[INF] reconcile {gid}: bot_has_admin {prev_bot_admin} -> {}", rt.state.bot_has_admin);
+	}
+	if !g.name.is_empty() && g.name != rt.state.group_name {
+		println!("[INF] reconcile {gid}: renamed {:?} -> {:?}", rt.state.group_name, g.name);
+		rt.state.group_name = g.name.clone();
+		rt.save_state()?;
+	}
+
+	let (joined, left) = sync_member_snapshot(rt)?;
+	if !joined.is_empty() || !left.is_empty() {
+		println!(
+			"[INF] reconcile {gid}: late member changes +{} -{}",
+			joined.len(),
+			left.len()
+		);
+	}
+
+	if rt.state.bot_has_admin && rt.cfg.enabled && rt.cfg.require_bot_admin_to_enforce {
+		let p = &g.permissions;
+		let checks = [
+			same_perm(p.add_member.as_deref(), &rt.cfg.desired_permission_add_member),
+			same_perm(p.send_messages.as_deref(), &rt.cfg.desired_permission_send_message),
+			same_perm(p.edit_details.as_deref(), &rt.cfg.desired_permission_edit_details),
+		];
+		if checks.contains(&None) && !PERM_UNKNOWN_WARNED.swap(true, Ordering::SeqCst) {
+			eprintln!("[WRN] reconcile {gid}: signal-cli did not report some group permissions, skipping drift check for them");
+		}
+		if checks.contains(&Some(false)) {
+			println!(
+				"[INF] reconcile {gid}: permissions drifted (add_member={} send_messages={} edit_details={}), re-applying takeover",
+				p.add_member.as_deref().unwrap_or("?"),
+				p.send_messages.as_deref().unwrap_or("?"),
+				p.edit_details.as_deref().unwrap_or("?"),
+			);
+			apply_takeover_permissions(backend, rt)?;
+		}
+	}
+	Ok(())
+}
@@ -0,0 +1,145 @@
+use crate::{paths, ReceiveEnvelope};
+use anyhow::{Context, Result};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+
+// global.json 里的 "recorder" 段；默认关闭
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RecorderConfig {
+	pub enabled: bool,
+	pub max_file_bytes: u64,
+	pub max_files: u32,
+	// 为空 = 记录全部；否则只记录这些群的消息（解析失败的行总是保留）
+	pub groups: Vec<String>,
+}
+
+impl Default for RecorderConfig {
+	fn default() -> Self {
+		RecorderConfig {
+			enabled: false,
+			max_file_bytes: 16 * 1024 * 1024,
+			max_files: 5,
+			groups: vec![],
+		}
+	}
+}
+
+pub fn raw_dir() -> PathBuf {
+	paths::log_dir().join("raw")
+}
+
+// 原样落盘 receive 的每一行：receive.jsonl 可直接喂给 --replay，rejected.jsonl 存解析失败的行和原因
+pub struct Recorder {
+	cfg: RecorderConfig,
+	received: RotatingFile,
+	rejected: RotatingFile,
+}
+
+impl Recorder {
+	pub fn open(cfg: &RecorderConfig) -> Result<Recorder> {
+		let dir = raw_dir();
+		fs::create_dir_all(&dir).with_context(|| format!("create dir {}", dir.display()))?;
+		Ok(Recorder {
+			cfg: cfg.clone(),
+			received: RotatingFile::new(dir.join("receive.jsonl"), cfg.max_file_bytes, cfg.max_files),
+			rejected: RotatingFile::new(dir.join("rejected.jsonl"), cfg.max_file_bytes, cfg.max_files),
+		})
+	}
+
+	pub fn record(&mut self, line: &str, ev: &ReceiveEnvelope) {
+		if !self.cfg.groups.is_empty() {
+			let gid = ev
+				.envelope
+				.data_message
+				.as_ref()
+				.and_then(|dm| dm.group_info.as_ref())
+				.map(|gi| gi.group_id.as_str());
+			if !gid.is_some_and(|g| self.cfg.groups.iter().any(|x| x == g)) {
+				return;
+			}
+		}
+		if let Err(e) = self.received.append(line) {
+			eprintln!("[WRN] recorder: {e:#}");
+		}
+	}
+
+	pub fn reject(&mut self, line: &str, reason: &str) {
+		let entry = json!({
+			"ts": Utc::now().timestamp(),
+			"reason": reason,
+			"line": line,
+		});
+		if let Err(e) = self.rejected.append(&entry.to_string()) {
+			eprintln!("[WRN] recorder: {e:#}");
+		}
+	}
+}
+
+struct RotatingFile {
+	path: PathBuf,
+	max_bytes: u64,
+	keep: u32,
+	file: Option<File>,
+	size: u64,
+}
+
+impl RotatingFile {
+	fn new(path: PathBuf, max_bytes: u64, keep: u32) -> Self {
+		RotatingFile {
+			path,
+			max_bytes,
+			keep,
+			file: None,
+			size: 0,
+		}
+	}
+
+	fn append(&mut self, line: &str) -> Result<()> {
+		let n = line.len() as u64 + 1;
+		if self.file.is_none() {
+			self.size = fs::metadata(&self.path).map(|m| m.len()).unwrap_or(0);
+		}
+		if self.size > 0 && self.size + n > self.max_bytes {
+			self.rotate()?;
+		}
+		if self.file.is_none() {
+			let f = OpenOptions::new()
+				.create(true)
+				.append(true)
+				.open(&self.path)
+				.with_context(|| format!("open {}", self.path.display()))?;
+			self.file = Some(f);
+		}
+		let f = self.file.as_mut().unwrap();
+		writeln!(f, "{line}")?;
+		self.size += n;
+		Ok(())
+	}
+
+	// receive.jsonl -> receive.jsonl.1 -> ... -> receive.jsonl.<keep>，最老的丢掉
+	fn rotate(&mut self) -> Result<()> {
+		self.file = None;
+		self.size = 0;
+		let rotated = |i: u32| PathBuf::from(format!("{}.{i}", self.path.display()));
+		if self.keep == 0 {
+			let _ = fs::remove_file(&self.path);
+			return Ok(());
+		}
+		let _ = fs::remove_file(rotated(self.keep));
+		for i in (1..self.keep).rev() {
+			let from = rotated(i);
+			if from.exists() {
+				fs::rename(&from, rotated(i + 1))?;
+			}
+		}
+		if self.path.exists() {
+			fs::rename(&self.path, rotated(1))?;
+		}
+		Ok(())
+	}
+}
@@ -0,0 +1,245 @@
+use crate::backend::{Backend, FakeAction, FakeBackend, GroupFull, Identity};
+use crate::state::{legacy_state, load_group_state, GroupState};
+use crate::throttle::{self, Outgoing};
+use crate::{
+	flag_value, flag_values, flush_outgoing, global_path, group_cfg_path, handle_group_event, has_flag, load_group_cfg,
+	schema, short_id, truncate, GlobalConfig, GroupConfig, GroupRuntime, ReceiveEnvelope, Simulation,
+};
+use anyhow::{anyhow, Context, Result};
+use chrono::{TimeZone, Utc};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// magicbot --replay <file.jsonl> --group <id> [--config <group.json>] [--admin <id>]... [--bot-admin] [--self <id>]
+pub fn run_replay(args: &[String]) -> Result<()> {
+	let file = flag_value(args, "--replay").ok_or_else(|| anyhow!("--replay <file.jsonl> required"))?;
+	let gid = flag_value(args, "--group").ok_or_else(|| anyhow!("--group <id> required"))?;
+
+	// 回放是模拟：配置只读，不创建 global.json、不写回群配置；
+	// 旧版本只在内存里升级，不备份、不生成 state/<gid>.json
+	let cfg_path = flag_value(args, "--config").map(PathBuf::from);
+	let cfg_file = cfg_path.clone().unwrap_or_else(|| group_cfg_path(&gid));
+	let (mut cfg, mut state): (GroupConfig, GroupState) = match read_value(&cfg_file)? {
+		Some(mut v) => {
+			let legacy = legacy_state(&v);
+			schema::migrate(&schema::GROUP, &gid, &cfg_file, &mut v)?;
+			let cfg = serde_json::from_value(v).with_context(|| format!("parse {}", cfg_file.display()))?;
+			let state = match (legacy, &cfg_path) {
+				(Some(st), _) => st,
+				(None, Some(_)) => GroupState::default(),
+				(None, None) => load_group_state(&gid)?,
+			};
+			(cfg, state)
+		}
+		None if cfg_path.is_some() => return Err(anyhow!("{} 不存在", cfg_file.display())),
+		None => (load_group_cfg(&gid)?, load_group_state(&gid)?),
+	};
+	cfg.group_id = gid.clone();
+	if !cfg.enabled {
+		println!("[INF] 配置中 enabled=false，回放时按已启用处理。");
+		cfg.enabled = true;
+	}
+
+	let gc = read_global()?;
+	let self_id = match flag_value(args, "--self") {
+		Some(s) => s,
+		None => gc
+			.as_ref()
+			.and_then(|gc| gc.account.clone())
+			.unwrap_or_else(|| "self".to_string()),
+	};
+	let bot_admin = has_flag(args, "--bot-admin") || state.bot_has_admin;
+
+	let raw = fs::read_to_string(&file).with_context(|| format!("read {file}"))?;
+	let mut lines = vec![];
+	let mut events = vec![];
+	for (n, line) in raw.lines().enumerate() {
+		let line = line.trim();
+		if line.is_empty() {
+			continue;
+		}
+		match serde_json::from_str::<ReceiveEnvelope>(line) {
+			Ok(ev) => {
+				lines.push(line);
+				events.push(ev);
+			}
+			Err(e) => println!("[WRN] {file}:{}: skip unparsable line: {e}", n + 1),
+		}
+	}
+
+	// 用录下来的发送者拼出一个离线群：成员 = 快照 ∪ 发言者，昵称取 sourceName
+	let mut members: BTreeSet<String> = state.last_members_snapshot.clone();
+	let mut names: BTreeMap<String, String> = BTreeMap::new();
+	for ev in &events {
+		let Some(id) = ev.sender_id() else { continue };
+		if let Some(n) = &ev.envelope.source_name {
+			names.insert(id.clone(), n.clone());
+		}
+		members.insert(id);
+	}
+	let mut admins: BTreeSet<String> = flag_values(args, "--admin").into_iter().collect();
+	if bot_admin {
+		admins.insert(self_id.clone());
+	}
+	members.insert(self_id.clone());
+
+	let ident = |id: &String| Identity {
+		id: id.clone(),
+		number: None,
+		name: None,
+	};
+	let fake = FakeBackend::new();
+	fake.set_groups(vec![GroupFull {
+		id: gid.clone(),
+		name: cfg.group_name.clone(),
+		admins: admins.iter().map(ident).collect(),
+		members: members.iter().map(ident).collect(),
+		permissions: Default::default(),
+	}]);
+	fake.set_contacts(
+		names
+			.iter()
+			.map(|(id, n)| Identity {
+				id: id.clone(),
+				number: None,
+				name: Some(n.clone()),
+			})
+			.collect(),
+	);
+
+	state.bot_has_admin = bot_admin;
+	let mut rt = GroupRuntime {
+		cfg,
+		state,
+		admins,
+		members,
+		member_names: names.into_iter().collect(),
+		self_id,
+		out: Outgoing::new(throttle::account_bucket(&gc.map(|gc| gc.throttle).unwrap_or_default())),
+		sim: Some(Simulation::default()),
+	};
+
+	for line in lines {
+		fake.push_incoming(line);
+	}
+
+	let mut counts: BTreeMap<&str, usize> = BTreeMap::new();
+	let mut seen = 0usize;
+	for line in fake.receive()? {
+		let Ok(ev) = serde_json::from_str::<ReceiveEnvelope>(&line) else { continue };
+		let Some(dm) = &ev.envelope.data_message else { continue };
+		let Some(gi) = &dm.group_info else { continue };
+		if gi.group_id != gid {
+			continue;
+		}
+		seen += 1;
+
+		let ts = ev.envelope.timestamp.unwrap_or(0);
+		if let Some(sim) = &mut rt.sim {
+			sim.now = ts / 1000;
+			sim.warns.clear();
+		}
+
+		if let Err(e) = handle_group_event(&fake, &mut rt, &ev, dm, gi) {
+			println!("[WRN] handler error: {e:#}");
+		}
+		flush_outgoing(&fake, &mut rt, false);
+
+		let warns = rt.sim.as_ref().map(|s| s.warns.clone()).unwrap_or_default();
+		let actions = fake.take_actions();
+		if warns.is_empty() && actions.is_empty() {
+			continue;
+		}
+
+		let when = Utc
+			.timestamp_millis_opt(ts)
+			.single()
+			.map(|t| t.format("%Y-%m-%d %H:%M:%S").to_string())
+			.unwrap_or_else(|| "-".to_string());
+		let who = ev.sender_id().map(|s| short_id(&s)).unwrap_or_else(|| "?".to_string());
+		let what = if gi.kind == "UPDATE" {
+			"<group update>".to_string()
+		} else {
+			truncate(dm.message.as_deref().unwrap_or(""), 60)
+		};
+		println!("[{when}] {who}: {what}");
+
+		for w in warns {
+			*counts.entry("warn").or_default() += 1;
+			println!("    WARN  {w}");
+		}
+		print_actions(actions, &mut counts);
+	}
+
+	// 合并窗口/令牌桶里还压着的消息：账号令牌够的照发，不够的列为 HELD
+	flush_outgoing(&fake, &mut rt, true);
+	let rest = fake.take_actions();
+	let held = rt.out.drain(&rt.cfg.throttle);
+	if !rest.is_empty() || !held.is_empty() {
+		println!("[end of replay, still pending]");
+		print_actions(rest, &mut counts);
+		for m in held {
+			*counts.entry("held").or_default() += 1;
+			println!("    HELD  {}", truncate(&m, 80));
+		}
+	}
+
+	println!(
+		"\n[OK] replayed {seen} message(s) for {gid}: send={} warn={} kick={} perm={} held={}",
+		counts.get("send").unwrap_or(&0),
+		counts.get("warn").unwrap_or(&0),
+		counts.get("kick").unwrap_or(&0),
+		counts.get("perm").unwrap_or(&0),
+		counts.get("held").unwrap_or(&0),
+	);
+	Ok(())
+}
+
+// load_global 在文件不存在时会生成一份，回放不能有这个副作用
+fn read_value(p: &Path) -> Result<Option<serde_json::Value>> {
+	if !p.exists() {
+		return Ok(None);
+	}
+	let s = fs::read_to_string(p).with_context(|| format!("read {}", p.display()))?;
+	Ok(Some(serde_json::from_str(&s).with_context(|| format!("parse {}", p.display()))?))
+}
+
+fn read_global() -> Result<Option<GlobalConfig>> {
+	let p = global_path();
+	let Some(mut v) = read_value(&p)? else {
+		return Ok(None);
+	};
+	schema::migrate(&schema::GLOBAL, "global", &p, &mut v)?;
+	Ok(Some(serde_json::from_value(v).with_context(|| format!("parse {}", p.display()))?))
+}
+
+fn print_actions(actions: Vec<FakeAction>, counts: &mut BTreeMap<&'static str, usize>) {
+	for a in actions {
+		match a {
+			FakeAction::Send { msg, .. } => {
+				*counts.entry("send").or_default() += 1;
+				println!("    SEND  {}", truncate(&msg, 80));
+			}
+			FakeAction::UpdateGroup { upd, .. } => {
+				for who in &upd.remove_members {
+					*counts.entry("kick").or_default() += 1;
+					println!("    KICK  {}", short_id(who));
+				}
+				if upd.permission_add_member.is_some()
+					|| upd.permission_send_messages.is_some()
+					|| upd.permission_edit_details.is_some()
+				{
+					*counts.entry("perm").or_default() += 1;
+					println!(
+						"    PERM  add_member={} send_messages={} edit_details={}",
+						upd.permission_add_member.as_deref().unwrap_or("-"),
+						upd.permission_send_messages.as_deref().unwrap_or("-"),
+						upd.permission_edit_details.as_deref().unwrap_or("-"),
+					);
+				}
+			}
+			FakeAction::SubmitChallenge { .. } => {}
+		}
+	}
+}
@@ -0,0 +1,177 @@
+use crate::proc::{describe_exit, drain_stderr, supervise_child, terminate, StderrTail};
+use anyhow::{anyhow, Context, Result};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Write};
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const CALL_TIMEOUT: Duration = Duration::from_secs(60);
+
+type Pending = Arc<Mutex<HashMap<u64, Sender<Result<Value>>>>>;
+
+// 常驻 signal-cli jsonRpc 进程：请求/响应与 receive 通知共用同一条 stdin/stdout
+pub struct JsonRpc {
+	child: Mutex<Child>,
+	stdin: Mutex<ChildStdin>,
+	next_id: AtomicU64,
+	pending: Pending,
+	alive: Arc<AtomicBool>,
+	stderr_tail: StderrTail,
+	// stdout 最后一次有输出的时间；调用超时且此后一直没输出 = 进程卡住了
+	last_read: Arc<Mutex<Instant>>,
+	stuck_since: Mutex<Option<Instant>>,
+}
+
+impl JsonRpc {
+	// events 收到的每一行都是 `receive -o json` 同格式的 {"envelope":...,"account":...}
+	pub fn spawn(acc: &str, cfgdir: Option<&str>) -> Result<(JsonRpc, Receiver<String>)> {
+		let mut cmd = Command::new("signal-cli");
+		if let Some(d) = cfgdir {
+			cmd.arg("--config").arg(d);
+		}
+		cmd.arg("-u").arg(acc).arg("jsonRpc");
+		cmd.stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::piped());
+		supervise_child(&mut cmd);
+		let mut child = cmd.spawn().context("spawn signal-cli jsonRpc")?;
+
+		let stdin = child.stdin.take().ok_or_else(|| anyhow!("no stdin"))?;
+		let stdout = child.stdout.take().ok_or_else(|| anyhow!("no stdout"))?;
+		let stderr = child.stderr.take().ok_or_else(|| anyhow!("no stderr"))?;
+		let stderr_tail = drain_stderr(stderr);
+
+		let pending: Pending = Arc::new(Mutex::new(HashMap::new()));
+		let alive = Arc::new(AtomicBool::new(true));
+		let last_read = Arc::new(Mutex::new(Instant::now()));
+		let (tx, rx) = mpsc::channel();
+
+		{
+			let pending = pending.clone();
+			let alive = alive.clone();
+			let last_read = last_read.clone();
+			thread::spawn(move || read_loop(BufReader::new(stdout), pending, alive, last_read, tx));
+		}
+
+		let rpc = JsonRpc {
+			child: Mutex::new(child),
+			stdin: Mutex::new(stdin),
+			next_id: AtomicU64::new(1),
+			pending,
+			alive,
+			stderr_tail,
+			last_read,
+			stuck_since: Mutex::new(None),
+		};
+		Ok((rpc, rx))
+	}
+
+	pub fn is_alive(&self) -> bool {
+		self.alive.load(Ordering::SeqCst)
+	}
+
+	// 进程还在，且没有"请求超时后再也没吐过任何东西"的情况
+	pub fn is_healthy(&self) -> bool {
+		let last_read = *self.last_read.lock().unwrap();
+		self.is_alive() && self.stuck_since.lock().unwrap().is_none_or(|t| last_read > t)
+	}
+
+	pub fn call(&self, method: &str, params: Value) -> Result<Value> {
+		if !self.is_alive() {
+			return Err(anyhow!("jsonRpc connection closed"));
+		}
+		let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+		let (tx, rx) = mpsc::channel();
+		self.pending.lock().unwrap().insert(id, tx);
+		let sent_at = Instant::now();
+
+		let req = json!({ "jsonrpc": "2.0", "method": method, "params": params, "id": id });
+		let written = {
+			let mut w = self.stdin.lock().unwrap();
+			writeln!(w, "{req}").and_then(|_| w.flush())
+		};
+		if let Err(e) = written {
+			self.pending.lock().unwrap().remove(&id);
+			self.alive.store(false, Ordering::SeqCst);
+			return Err(anyhow!("jsonRpc write failed: {e}"));
+		}
+
+		match rx.recv_timeout(CALL_TIMEOUT) {
+			Ok(r) => r,
+			Err(_) => {
+				self.pending.lock().unwrap().remove(&id);
+				if *self.last_read.lock().unwrap() < sent_at {
+					*self.stuck_since.lock().unwrap() = Some(sent_at);
+				}
+				Err(anyhow!("jsonRpc {method} timed out"))
+			}
+		}
+	}
+
+	pub fn exit_reason(&self) -> String {
+		describe_exit(&mut self.child.lock().unwrap(), &self.stderr_tail)
+	}
+
+	pub fn shutdown(&self) {
+		self.alive.store(false, Ordering::SeqCst);
+		terminate(&mut self.child.lock().unwrap());
+	}
+}
+
+impl Drop for JsonRpc {
+	fn drop(&mut self) {
+		self.shutdown();
+	}
+}
+
+fn read_loop(
+	reader: impl BufRead,
+	pending: Pending,
+	alive: Arc<AtomicBool>,
+	last_read: Arc<Mutex<Instant>>,
+	events: Sender<String>,
+) {
+	for line in reader.lines() {
+		let Ok(line) = line else { break };
+		*last_read.lock().unwrap() = Instant::now();
+		let line = line.trim();
+		if line.is_empty() {
+			continue;
+		}
+		let Ok(v) = serde_json::from_str::<Value>(line) else {
+			continue;
+		};
+
+		if let Some(id) = v.get("id").and_then(|x| x.as_u64()) {
+			let Some(tx) = pending.lock().unwrap().remove(&id) else {
+				continue;
+			};
+			let r = match v.get("error") {
+				Some(err) => {
+					let msg = err.get("message").and_then(|m| m.as_str()).unwrap_or("unknown error");
+					match err.get("data") {
+						Some(data) => Err(anyhow!("signal-cli failed: {msg} {data}")),
+						None => Err(anyhow!("signal-cli failed: {msg}")),
+					}
+				}
+				None => Ok(v.get("result").cloned().unwrap_or(Value::Null)),
+			};
+			let _ = tx.send(r);
+			continue;
+		}
+
+		if v.get("method").and_then(|m| m.as_str()) == Some("receive") {
+			if let Some(p) = v.get("params") {
+				let _ = events.send(p.to_string());
+			}
+		}
+	}
+
+	alive.store(false, Ordering::SeqCst);
+	for (_, tx) in pending.lock().unwrap().drain() {
+		let _ = tx.send(Err(anyhow!("jsonRpc connection closed")));
+	}
+}
@@ -0,0 +1,191 @@
+use anyhow::{Context, Result};
+use serde_json::Value;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// 配置文件的版本号和升级链：steps[n] 把 schema_version n 升到 n+1。
+// 没有 schema_version 的文件当作 0（加版本号之前写的）
+pub struct Schema {
+	pub name: &'static str,
+	pub steps: &'static [fn(&str, &mut Value) -> Result<()>],
+}
+
+impl Schema {
+	pub fn current(&self) -> u32 {
+		self.steps.len() as u32
+	}
+}
+
+pub const GLOBAL: Schema = Schema {
+	name: "global",
+	steps: &[global_v0_to_v1],
+};
+
+pub const GROUP: Schema = Schema {
+	name: "group",
+	steps: &[group_v0_to_v1],
+};
+
+// v1 只是补上版本号
+fn global_v0_to_v1(_key: &str, _v: &mut Value) -> Result<()> {
+	Ok(())
+}
+
+// v1：成员快照/Bot 管理员状态从群配置搬到 state/<gid>.json（写状态文件的是 load_group_cfg，这里只删字段）
+fn group_v0_to_v1(_gid: &str, v: &mut Value) -> Result<()> {
+	crate::state::strip_legacy(v);
+	Ok(())
+}
+
+pub fn version_of(v: &Value) -> u32 {
+	v.get("schema_version").and_then(|x| x.as_u64()).unwrap_or(0) as u32
+}
+
+fn backup_path(path: &Path, from: u32) -> PathBuf {
+	let mut name = path.file_name().unwrap_or_default().to_os_string();
+	name.push(format!(".v{from}.bak"));
+	path.with_file_name(name)
+}
+
+// 只在内存里逐级升级（不备份、不写回），返回原来的版本号。
+// 比当前程序新的版本不动它：不认识的字段原样保留，缺的字段用默认值
+pub fn migrate(schema: &Schema, key: &str, path: &Path, v: &mut Value) -> Result<u32> {
+	let from = version_of(v);
+	let current = schema.current();
+	if from > current {
+		println!(
+			"[WRN] {} has {} schema_version {from}, newer than this build ({current}); unknown fields are kept as-is",
+			path.display(),
+			schema.name
+		);
+		return Ok(from);
+	}
+	for (n, step) in schema.steps.iter().enumerate().skip(from as usize) {
+		step(key, v).with_context(|| format!("migrate {} {} v{n} -> v{}", schema.name, path.display(), n + 1))?;
+	}
+	if from < current {
+		if let Some(obj) = v.as_object_mut() {
+			obj.insert("schema_version".to_string(), Value::from(current));
+		}
+	}
+	Ok(from)
+}
+
+// 读到旧版本的文件：先把原文件备份成 <file>.v<n>.bak（已有就不覆盖），再逐级升级。
+// 返回 true 表示 v 被升级过，调用方要写回。
+pub fn upgrade(schema: &Schema, key: &str, path: &Path, v: &mut Value) -> Result<bool> {
+	let from = version_of(v);
+	let current = schema.current();
+	if from >= current {
+		migrate(schema, key, path, v)?;
+		return Ok(false);
+	}
+
+	let bak = backup_path(path, from);
+	if !bak.exists() {
+		fs::copy(path, &bak).with_context(|| format!("backup {} -> {}", path.display(), bak.display()))?;
+	}
+	migrate(schema, key, path, v)?;
+	println!(
+		"[INF] upgraded {} from schema v{from} to v{current} (backup: {})",
+		path.display(),
+		bak.display()
+	);
+	Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use serde_json::json;
+
+	// 每个测试自己的临时目录
+	fn scratch(name: &str) -> PathBuf {
+		let d = std::env::temp_dir().join(format!("magicbot-schema-{}-{name}", std::process::id()));
+		let _ = fs::remove_dir_all(&d);
+		fs::create_dir_all(&d).unwrap();
+		d
+	}
+
+	fn write(p: &Path, v: &Value) {
+		fs::write(p, serde_json::to_vec(v).unwrap()).unwrap();
+	}
+
+	#[test]
+	fn group_v0_upgrades_to_current_and_backs_up() {
+		let d = scratch("v0");
+		let p = d.join("G1.json");
+		let orig = json!({ "group_id": "G1", "bot_has_admin": true, "last_members_snapshot": ["a"] });
+		write(&p, &orig);
+
+		let mut v = orig.clone();
+		assert!(upgrade(&GROUP, "G1", &p, &mut v).unwrap());
+		assert_eq!(version_of(&v), GROUP.current());
+		assert!(v.get("bot_has_admin").is_none());
+		assert!(v.get("last_members_snapshot").is_none());
+		assert_eq!(v["group_id"], "G1");
+
+		let bak: Value = serde_json::from_slice(&fs::read(d.join("G1.json.v0.bak")).unwrap()).unwrap();
+		assert_eq!(bak, orig);
+		// 升级本身不写回原文件，由调用方保存
+		let on_disk: Value = serde_json::from_slice(&fs::read(&p).unwrap()).unwrap();
+		assert_eq!(on_disk, orig);
+		let _ = fs::remove_dir_all(&d);
+	}
+
+	#[test]
+	fn existing_backup_is_not_overwritten() {
+		let d = scratch("bak");
+		let p = d.join("global.json");
+		let bak = d.join("global.json.v0.bak");
+		fs::write(&bak, b"first").unwrap();
+		write(&p, &json!({ "account": "+1" }));
+
+		let mut v = json!({ "account": "+1" });
+		assert!(upgrade(&GLOBAL, "global", &p, &mut v).unwrap());
+		assert_eq!(fs::read(&bak).unwrap(), b"first");
+		assert_eq!(version_of(&v), GLOBAL.current());
+		let _ = fs::remove_dir_all(&d);
+	}
+
+	#[test]
+	fn current_version_is_left_alone() {
+		let d = scratch("cur");
+		let p = d.join("G1.json");
+		let mut v = json!({ "schema_version": GROUP.current(), "group_id": "G1" });
+		let before = v.clone();
+		assert!(!upgrade(&GROUP, "G1", &p, &mut v).unwrap());
+		assert_eq!(v, before);
+		assert_eq!(fs::read_dir(&d).unwrap().count(), 0);
+		let _ = fs::remove_dir_all(&d);
+	}
+
+	#[test]
+	fn future_version_is_left_unchanged() {
+		let d = scratch("future");
+		let p = d.join("G1.json");
+		let future = GROUP.current() + 5;
+		let mut v = json!({ "schema_version": future, "group_id": "G1", "new_field": [1, 2], "bot_has_admin": true });
+		let before = v.clone();
+		write(&p, &v);
+		assert!(!upgrade(&GROUP, "G1", &p, &mut v).unwrap());
+		assert_eq!(v, before);
+		assert_eq!(migrate(&GROUP, "G1", &p, &mut v).unwrap(), future);
+		assert_eq!(v, before);
+		// 没有备份
+		assert_eq!(fs::read_dir(&d).unwrap().count(), 1);
+		let _ = fs::remove_dir_all(&d);
+	}
+
+	#[test]
+	fn migrate_in_memory_does_not_touch_disk() {
+		let d = scratch("mem");
+		let p = d.join("G1.json");
+		let mut v = json!({ "group_id": "G1", "bot_has_admin": false });
+		assert_eq!(migrate(&GROUP, "G1", &p, &mut v).unwrap(), 0);
+		assert_eq!(version_of(&v), GROUP.current());
+		assert!(v.get("bot_has_admin").is_none());
+		assert_eq!(fs::read_dir(&d).unwrap().count(), 0);
+		let _ = fs::remove_dir_all(&d);
+	}
+}
@@ -0,0 +1,57 @@
+use std::env;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::time::{Duration, Instant};
+
+// systemd 的 sd_notify 协议：往 $NOTIFY_SOCKET 发一个数据报。不是 Type=notify 启动的就什么都不做
+pub fn notify(msg: &str) {
+	let Ok(path) = env::var("NOTIFY_SOCKET") else {
+		return;
+	};
+	let addr = match path.strip_prefix('@') {
+		Some(name) => SocketAddr::from_abstract_name(name.as_bytes()),
+		None => SocketAddr::from_pathname(&path),
+	};
+	let sent = addr.and_then(|addr| UnixDatagram::unbound()?.send_to_addr(msg.as_bytes(), &addr));
+	if let Err(e) = sent {
+		eprintln!("[WRN] sd_notify {path}: {e}");
+	}
+}
+
+// WatchdogSec= 会通过 WATCHDOG_USEC 传进来，按一半的间隔喂狗
+pub struct Watchdog {
+	every: Option<Duration>,
+	last: Instant,
+}
+
+impl Watchdog {
+	pub fn from_env() -> Self {
+		let ours = env::var("WATCHDOG_PID")
+			.ok()
+			.and_then(|p| p.parse::<u32>().ok())
+			.is_none_or(|p| p == std::process::id());
+		let every = env::var("WATCHDOG_USEC")
+			.ok()
+			.and_then(|u| u.parse::<u64>().ok())
+			.filter(|&u| u > 0 && ours)
+			.map(|u| Duration::from_micros(u / 2));
+		Watchdog {
+			every,
+			last: Instant::now(),
+		}
+	}
+
+	pub fn enabled(&self) -> bool {
+		self.every.is_some()
+	}
+
+	pub fn ping(&mut self) {
+		let Some(every) = self.every else {
+			return;
+		};
+		if self.last.elapsed() >= every {
+			self.last = Instant::now();
+			notify("WATCHDOG=1");
+		}
+	}
+}
@@ -0,0 +1,65 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+static RELOAD: AtomicBool = AtomicBool::new(false);
+static SHUTDOWN: AtomicBool = AtomicBool::new(false);
+static DUMP: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_signal(sig: libc::c_int) {
+	// 信号处理函数里只置标志，真正的处理在守护进程主循环的 tick 里
+	match sig {
+		libc::SIGHUP => RELOAD.store(true, Ordering::SeqCst),
+		libc::SIGTERM | libc::SIGINT => SHUTDOWN.store(true, Ordering::SeqCst),
+		libc::SIGUSR1 => DUMP.store(true, Ordering::SeqCst),
+		_ => {}
+	}
+}
+
+pub fn install() {
+	for sig in [libc::SIGHUP, libc::SIGTERM, libc::SIGINT, libc::SIGUSR1] {
+		unsafe {
+			libc::signal(sig, on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t);
+		}
+	}
+}
+
+// 前台测试结束回到菜单：恢复默认处理（Ctrl+C 直接退出），清掉残留标志
+pub fn reset() {
+	for sig in [libc::SIGHUP, libc::SIGTERM, libc::SIGINT, libc::SIGUSR1] {
+		unsafe {
+			libc::signal(sig, libc::SIG_DFL);
+		}
+	}
+	for f in [&RELOAD, &SHUTDOWN, &DUMP] {
+		f.store(false, Ordering::SeqCst);
+	}
+}
+
+// SIGHUP：重新加载群配置
+pub fn take_reload() -> bool {
+	RELOAD.swap(false, Ordering::SeqCst)
+}
+
+// SIGTERM / Ctrl+C：停止接收，处理完手头的再退出
+pub fn shutdown_requested() -> bool {
+	SHUTDOWN.load(Ordering::SeqCst)
+}
+
+// SIGUSR1：把运行时状态打到日志
+pub fn take_dump() -> bool {
+	DUMP.swap(false, Ordering::SeqCst)
+}
+
+// 分段睡眠，期间收到退出信号就提前返回 false
+pub fn sleep_unless_shutdown(d: Duration) -> bool {
+	let until = Instant::now() + d;
+	while !shutdown_requested() {
+		let left = until.saturating_duration_since(Instant::now());
+		if left.is_zero() {
+			return true;
+		}
+		thread::sleep(left.min(Duration::from_millis(200)));
+	}
+	false
+}
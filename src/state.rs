@@ -0,0 +1,87 @@
+use crate::lock::with_dir_lock;
+use crate::{crypto, paths};
+use anyhow::{Context, Result};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::PathBuf;
+
+// 旧版本写在 groups/<gid>.json 里的运行时字段
+const LEGACY_KEYS: [&str; 2] = ["last_members_snapshot", "bot_has_admin"];
+
+// 守护进程维护的每群运行时状态，存 STATE_DIR/state/<gid>.json；
+// groups/<gid>.json 只放运维配置，可以手改、放进版本库
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GroupState {
+	pub last_members_snapshot: BTreeSet<String>,
+	pub bot_has_admin: bool,
+	// Signal 上当前的群名（配置里的 group_name 是选群时记下的）
+	pub group_name: String,
+	pub updated_at: i64,
+}
+
+pub fn state_dir() -> PathBuf {
+	paths::state_dir().join("state")
+}
+
+pub fn state_path(gid: &str) -> PathBuf {
+	state_dir().join(format!("{gid}.json"))
+}
+
+pub fn load_group_state(gid: &str) -> Result<GroupState> {
+	let p = state_path(gid);
+	if !p.exists() {
+		return Ok(GroupState::default());
+	}
+	let s = crypto::read_to_string(&p)?;
+	serde_json::from_str(&s).with_context(|| format!("parse {}", p.display()))
+}
+
+pub fn save_group_state(gid: &str, st: &GroupState) -> Result<()> {
+	let mut st = st.clone();
+	st.updated_at = Utc::now().timestamp();
+	let p = state_path(gid);
+	let tmp = p.with_extension("json.tmp");
+	with_dir_lock(&state_dir(), || {
+		crypto::write(&tmp, &serde_json::to_vec_pretty(&st)?)?;
+		fs::rename(&tmp, &p).with_context(|| format!("write {}", p.display()))?;
+		Ok(())
+	})
+}
+
+// 从旧格式的群配置里取出运行时字段（没有就是 None）
+pub fn legacy_state(v: &Value) -> Option<GroupState> {
+	let obj = v.as_object()?;
+	if !LEGACY_KEYS.iter().any(|k| obj.contains_key(*k)) {
+		return None;
+	}
+	let mut st = GroupState::default();
+	if let Some(snap) = obj.get("last_members_snapshot") {
+		st.last_members_snapshot = serde_json::from_value(snap.clone()).unwrap_or_default();
+	}
+	st.bot_has_admin = obj.get("bot_has_admin").and_then(|x| x.as_bool()).unwrap_or(false);
+	st.group_name = obj.get("group_name").and_then(|x| x.as_str()).unwrap_or("").to_string();
+	Some(st)
+}
+
+// 旧格式的运行时字段从配置里删掉
+pub fn strip_legacy(v: &mut Value) {
+	if let Some(obj) = v.as_object_mut() {
+		for k in LEGACY_KEYS {
+			obj.remove(k);
+		}
+	}
+}
+
+// 迁移旧群配置时把取出来的运行时字段写到状态文件（已有状态文件则以它为准）
+pub fn migrate_legacy(gid: &str, st: &GroupState) -> Result<()> {
+	if state_path(gid).exists() {
+		return Ok(());
+	}
+	save_group_state(gid, st)?;
+	println!("[INF] migrated runtime state of {gid} to {}", state_path(gid).display());
+	Ok(())
+}
@@ -0,0 +1,107 @@
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+
+// global.json 里的 "supervisor" 段
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SupervisorConfig {
+	// 连续崩溃后最多重启多少次，再崩就放弃（交给 systemd 处理）
+	pub max_restarts: u32,
+	pub backoff_initial_secs: u64,
+	pub backoff_max_secs: u64,
+	// 接收进程稳定运行这么久后，崩溃计数清零
+	pub reset_after_secs: u64,
+}
+
+impl Default for SupervisorConfig {
+	fn default() -> Self {
+		SupervisorConfig {
+			max_restarts: 10,
+			backoff_initial_secs: 1,
+			backoff_max_secs: 300,
+			reset_after_secs: 600,
+		}
+	}
+}
+
+// 接收进程退出后决定等多久重启，或者放弃
+pub struct Supervisor {
+	cfg: SupervisorConfig,
+	crashes: u32,
+	total_crashes: u64,
+	backoff: Duration,
+	started: Option<Instant>,
+}
+
+impl Supervisor {
+	pub fn new(cfg: &SupervisorConfig) -> Self {
+		Supervisor {
+			cfg: cfg.clone(),
+			crashes: 0,
+			total_crashes: 0,
+			backoff: Duration::from_secs(cfg.backoff_initial_secs.max(1)),
+			started: None,
+		}
+	}
+
+	pub fn started(&mut self) {
+		self.started = Some(Instant::now());
+	}
+
+	// 重启失败时没有经过 started()，运行时长按 0 计
+	pub fn exited(&mut self, reason: &str) -> Result<Duration> {
+		let uptime = self.started.take().map(|t| t.elapsed()).unwrap_or_default();
+		if uptime >= Duration::from_secs(self.cfg.reset_after_secs) {
+			self.crashes = 0;
+			self.backoff = Duration::from_secs(self.cfg.backoff_initial_secs.max(1));
+		}
+		self.crashes += 1;
+		self.total_crashes += 1;
+
+		eprintln!(
+			"[WRN] signal-cli receiver exited after {}s ({reason}); crash {} in a row, max restarts {} (total {})",
+			uptime.as_secs(),
+			self.crashes,
+			self.cfg.max_restarts,
+			self.total_crashes
+		);
+		if self.crashes > self.cfg.max_restarts {
+			return Err(anyhow!(
+				"signal-cli receiver crashed {} times in a row, giving up. last exit: {reason}",
+				self.crashes
+			));
+		}
+
+		let wait = self.backoff;
+		self.backoff = (self.backoff * 2).min(Duration::from_secs(self.cfg.backoff_max_secs.max(1)));
+		Ok(wait)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn restarts_max_restarts_times_then_gives_up() {
+		let mut sup = Supervisor::new(&SupervisorConfig {
+			max_restarts: 3,
+			backoff_initial_secs: 1,
+			backoff_max_secs: 3,
+			reset_after_secs: 600,
+		});
+		let waits: Vec<u64> = (0..3).map(|_| sup.exited("exit 1").unwrap().as_secs()).collect();
+		assert_eq!(waits, vec![1, 2, 3]);
+		assert!(sup.exited("exit 1").is_err());
+	}
+
+	#[test]
+	fn zero_max_restarts_gives_up_on_first_crash() {
+		let mut sup = Supervisor::new(&SupervisorConfig {
+			max_restarts: 0,
+			..SupervisorConfig::default()
+		});
+		assert!(sup.exited("exit 1").is_err());
+	}
+}
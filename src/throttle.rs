@@ -0,0 +1,373 @@
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+// 合并消息里最多列出几个名字
+const MAX_NAMES: usize = 20;
+// 限流时每个群最多积压几条普通消息，超出丢最旧的
+const MAX_PENDING_TEXT: usize = 50;
+
+// GroupConfig 里的 "throttle" 段：本群的外发令牌桶 + 欢迎/警告合并窗口
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ThrottleConfig {
+	pub per_minute: u32,
+	pub burst: u32,
+	// 窗口内的多条欢迎语/警告合成一条发出，0 表示不合并
+	pub coalesce_secs: u64,
+}
+
+impl Default for ThrottleConfig {
+	fn default() -> Self {
+		ThrottleConfig {
+			per_minute: 6,
+			burst: 3,
+			coalesce_secs: 10,
+		}
+	}
+}
+
+// global.json 里的 "throttle" 段：整个账号所有群加起来的上限
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AccountThrottleConfig {
+	pub per_minute: u32,
+	pub burst: u32,
+}
+
+impl Default for AccountThrottleConfig {
+	fn default() -> Self {
+		AccountThrottleConfig {
+			per_minute: 20,
+			burst: 5,
+		}
+	}
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct TokenBucket {
+	tokens: Option<f64>,
+	last: i64,
+}
+
+impl TokenBucket {
+	fn refill(&mut self, now: i64, per_minute: u32, burst: u32) -> f64 {
+		let cap = burst.max(1) as f64;
+		let t = match self.tokens {
+			Some(t) => t + (now - self.last).max(0) as f64 * per_minute as f64 / 60.0,
+			None => cap,
+		};
+		self.tokens = Some(t.min(cap));
+		self.last = now;
+		t.min(cap)
+	}
+
+	fn take(&mut self) {
+		if let Some(t) = &mut self.tokens {
+			*t -= 1.0;
+		}
+	}
+}
+
+#[derive(Debug, Default)]
+pub struct AccountBucket {
+	cfg: AccountThrottleConfig,
+	bucket: TokenBucket,
+}
+
+pub type SharedAccountBucket = Arc<Mutex<AccountBucket>>;
+
+pub fn account_bucket(cfg: &AccountThrottleConfig) -> SharedAccountBucket {
+	Arc::new(Mutex::new(AccountBucket {
+		cfg: cfg.clone(),
+		bucket: TokenBucket::default(),
+	}))
+}
+
+#[derive(Clone, Debug)]
+enum Item {
+	Welcome { tpl: String, name: String },
+	Warn { msg: String, name: String },
+	Text(String),
+}
+
+// 每个群的待发消息：欢迎/警告先攒 coalesce_secs 再合并，所有消息都要同时拿到群和账号的令牌才发
+#[derive(Clone, Debug)]
+pub struct Outgoing {
+	bucket: TokenBucket,
+	account: SharedAccountBucket,
+	items: VecDeque<(i64, Item)>,
+}
+
+impl Outgoing {
+	pub fn new(account: SharedAccountBucket) -> Self {
+		Outgoing {
+			bucket: TokenBucket::default(),
+			account,
+			items: VecDeque::new(),
+		}
+	}
+
+	pub fn welcome(&mut self, now: i64, tpl: &str, name: &str) {
+		self.items.push_back((
+			now,
+			Item::Welcome {
+				tpl: tpl.to_string(),
+				name: name.to_string(),
+			},
+		));
+	}
+
+	pub fn warn(&mut self, now: i64, msg: &str, name: &str) {
+		self.items.push_back((
+			now,
+			Item::Warn {
+				msg: msg.to_string(),
+				name: name.to_string(),
+			},
+		));
+	}
+
+	pub fn text(&mut self, now: i64, msg: &str) {
+		self.items.push_back((now, Item::Text(msg.to_string())));
+		let texts = self.items.iter().filter(|(_, i)| matches!(i, Item::Text(_))).count();
+		if texts > MAX_PENDING_TEXT {
+			if let Some(pos) = self.items.iter().position(|(_, i)| matches!(i, Item::Text(_))) {
+				if let Some((_, Item::Text(m))) = self.items.remove(pos) {
+					eprintln!("[WRN] outbound throttled, dropped: {m}");
+				}
+			}
+		}
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.items.is_empty()
+	}
+
+	pub fn len(&self) -> usize {
+		self.items.len()
+	}
+
+	// 取出现在可以发的消息（已合并）。force（回放结束、守护进程退出时清空）不等合并窗口、不看本群令牌，
+	// 但账号令牌照扣，一次最多发出账号 burst 条；拿不到令牌的留给 drain()
+	pub fn due(&mut self, now: i64, cfg: &ThrottleConfig, force: bool) -> Vec<String> {
+		let mut out = vec![];
+		loop {
+			let ready = self.items.iter().position(|(ts, item)| match item {
+				Item::Text(_) => true,
+				_ => force || now - ts >= cfg.coalesce_secs as i64,
+			});
+			let Some(pos) = ready else { break };
+
+			let mut acc = self.account.lock().unwrap();
+			let (per_minute, burst) = (acc.cfg.per_minute, acc.cfg.burst);
+			let acc_tokens = acc.bucket.refill(now, per_minute, burst);
+			let grp_tokens = self.bucket.refill(now, cfg.per_minute, cfg.burst);
+			if acc_tokens < 1.0 || (!force && grp_tokens < 1.0) {
+				break;
+			}
+			acc.bucket.take();
+			self.bucket.take();
+			drop(acc);
+
+			out.push(self.merge(pos, cfg.coalesce_secs as i64));
+		}
+		out
+	}
+
+	// 取走剩下的全部消息（已合并），不发；调用方决定展示还是记日志丢弃
+	pub fn drain(&mut self, cfg: &ThrottleConfig) -> Vec<String> {
+		let mut out = vec![];
+		while !self.items.is_empty() {
+			out.push(self.merge(0, cfg.coalesce_secs as i64));
+		}
+		out
+	}
+
+	// 取走 pos 处的条目，连同队列里同类（同模板/同文案）的欢迎/警告拼成一条；
+	// 普通文字只去掉合并窗口内的重复，窗口外同样的文字照常再发
+	fn merge(&mut self, pos: usize, window: i64) -> String {
+		let (ts0, item) = self.items[pos].clone();
+		let mut names: Vec<String> = vec![];
+		let mut idx = 0;
+		self.items.retain(|(ts, it)| {
+			let i = idx;
+			idx += 1;
+			let same = match (it, &item) {
+				(Item::Welcome { tpl: a, name }, Item::Welcome { tpl: b, .. }) if a == b => Some(name),
+				(Item::Warn { msg: a, name }, Item::Warn { msg: b, .. }) if a == b => Some(name),
+				(Item::Text(a), Item::Text(b)) => return !(i == pos || (a == b && ts - ts0 < window)),
+				_ => None,
+			};
+			match same {
+				Some(n) => {
+					if !names.contains(n) {
+						names.push(n.clone());
+					}
+					false
+				}
+				None => true,
+			}
+		});
+
+		let list = if names.len() > MAX_NAMES {
+			format!("{} 等 {} 人", names[..MAX_NAMES].join("、"), names.len())
+		} else {
+			names.join("、")
+		};
+		match &item {
+			Item::Welcome { tpl, .. } => tpl.replace("##{@user}##", &list),
+			Item::Warn { msg, .. } if names.len() <= 1 => msg.clone(),
+			Item::Warn { msg, .. } => format!("{msg}（{list}）"),
+			Item::Text(m) => m.clone(),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn group_cfg(per_minute: u32, burst: u32, coalesce_secs: u64) -> ThrottleConfig {
+		ThrottleConfig {
+			per_minute,
+			burst,
+			coalesce_secs,
+		}
+	}
+
+	fn outgoing(per_minute: u32, burst: u32) -> Outgoing {
+		Outgoing::new(account_bucket(&AccountThrottleConfig { per_minute, burst }))
+	}
+
+	#[test]
+	fn bucket_starts_full_and_refills_at_rate() {
+		let mut b = TokenBucket::default();
+		assert_eq!(b.refill(100, 60, 3), 3.0);
+		b.take();
+		b.take();
+		b.take();
+		assert_eq!(b.refill(100, 60, 3), 0.0);
+		assert_eq!(b.refill(101, 60, 3), 1.0);
+		// 不超过 burst
+		assert_eq!(b.refill(1000, 60, 3), 3.0);
+		// 时间倒退不扣令牌
+		assert_eq!(b.refill(900, 60, 3), 3.0);
+	}
+
+	#[test]
+	fn group_limit_holds_back_excess() {
+		let mut out = outgoing(600, 100);
+		let cfg = group_cfg(60, 2, 0);
+		for m in ["a", "b", "c"] {
+			out.text(0, m);
+		}
+		assert_eq!(out.due(0, &cfg, false), vec!["a", "b"]);
+		assert_eq!(out.items.len(), 1);
+		assert!(out.due(0, &cfg, false).is_empty());
+		assert_eq!(out.due(1, &cfg, false), vec!["c"]);
+		assert!(out.is_empty());
+	}
+
+	#[test]
+	fn account_limit_is_shared_between_groups() {
+		let acc = account_bucket(&AccountThrottleConfig {
+			per_minute: 60,
+			burst: 1,
+		});
+		let cfg = group_cfg(600, 100, 0);
+		let mut g1 = Outgoing::new(acc.clone());
+		let mut g2 = Outgoing::new(acc);
+		g1.text(0, "one");
+		g2.text(0, "two");
+		assert_eq!(g1.due(0, &cfg, false), vec!["one"]);
+		assert!(g2.due(0, &cfg, false).is_empty());
+		assert_eq!(g2.due(1, &cfg, false), vec!["two"]);
+	}
+
+	#[test]
+	fn welcomes_coalesce_within_window() {
+		let mut out = outgoing(600, 100);
+		let cfg = group_cfg(600, 100, 10);
+		out.welcome(0, "欢迎 ##{@user}##", "a");
+		out.welcome(3, "欢迎 ##{@user}##", "b");
+		out.welcome(5, "欢迎 ##{@user}##", "a");
+		assert!(out.due(9, &cfg, false).is_empty());
+		assert_eq!(out.due(10, &cfg, false), vec!["欢迎 a、b"]);
+		assert!(out.is_empty());
+	}
+
+	#[test]
+	fn warnings_list_names_only_when_merged() {
+		let cfg = group_cfg(600, 100, 0);
+		let mut out = outgoing(600, 100);
+		out.warn(0, "stop", "a");
+		assert_eq!(out.due(0, &cfg, false), vec!["stop"]);
+		out.warn(0, "stop", "a");
+		out.warn(0, "stop", "b");
+		assert_eq!(out.due(0, &cfg, false), vec!["stop（a、b）"]);
+	}
+
+	#[test]
+	fn merged_name_list_is_capped() {
+		let mut out = outgoing(600, 100);
+		let cfg = group_cfg(600, 100, 0);
+		for i in 0..MAX_NAMES + 5 {
+			out.welcome(0, "hi ##{@user}##", &format!("u{i}"));
+		}
+		let msgs = out.due(0, &cfg, false);
+		assert_eq!(msgs.len(), 1);
+		assert!(msgs[0].starts_with("hi u0、u1"));
+		assert!(msgs[0].ends_with(&format!("等 {} 人", MAX_NAMES + 5)));
+	}
+
+	#[test]
+	fn different_templates_do_not_merge() {
+		let mut out = outgoing(600, 100);
+		let cfg = group_cfg(600, 100, 0);
+		out.welcome(0, "A ##{@user}##", "x");
+		out.welcome(0, "B ##{@user}##", "y");
+		assert_eq!(out.due(0, &cfg, false), vec!["A x", "B y"]);
+	}
+
+	#[test]
+	fn identical_texts_dedup_only_within_window() {
+		let mut out = outgoing(600, 100);
+		let cfg = group_cfg(600, 100, 10);
+		out.text(0, "same");
+		out.text(5, "same");
+		out.text(12, "same");
+		out.text(12, "other");
+		assert_eq!(out.due(12, &cfg, false), vec!["same", "same", "other"]);
+
+		// 不合并时两个人触发同一条自动回复就回两次
+		let cfg = group_cfg(600, 100, 0);
+		out.text(20, "same");
+		out.text(20, "same");
+		assert_eq!(out.due(20, &cfg, false), vec!["same", "same"]);
+	}
+
+	#[test]
+	fn force_skips_window_and_group_bucket_but_not_account() {
+		let mut out = outgoing(60, 2);
+		let cfg = group_cfg(1, 1, 3600);
+		out.welcome(0, "hi ##{@user}##", "a");
+		out.text(0, "t1");
+		out.text(0, "t2");
+		out.text(0, "t3");
+		assert_eq!(out.due(0, &cfg, false), vec!["t1"]);
+		assert_eq!(out.due(0, &cfg, true), vec!["hi a"]);
+		assert_eq!(out.due(1, &cfg, true), vec!["t2"]);
+		assert_eq!(out.drain(&cfg), vec!["t3"]);
+		assert!(out.is_empty());
+	}
+
+	#[test]
+	fn pending_texts_are_bounded() {
+		let mut out = outgoing(600, 100);
+		for i in 0..MAX_PENDING_TEXT + 3 {
+			out.text(0, &format!("m{i}"));
+		}
+		assert_eq!(out.items.len(), MAX_PENDING_TEXT);
+	}
+}
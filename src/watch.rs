@@ -0,0 +1,64 @@
+use anyhow::{anyhow, Result};
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+// inotify 盯着 groups/ 目录：TUI 或运维改了 <gid>.json（写临时文件再 rename）就通知守护进程重载
+pub struct ConfigWatch {
+	fd: libc::c_int,
+}
+
+impl ConfigWatch {
+	pub fn new(dir: &Path) -> Result<Self> {
+		let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+		if fd < 0 {
+			return Err(anyhow!("inotify_init1: {}", std::io::Error::last_os_error()));
+		}
+		let w = ConfigWatch { fd };
+
+		let path = CString::new(dir.as_os_str().as_bytes())?;
+		let mask = libc::IN_CLOSE_WRITE | libc::IN_MOVED_TO | libc::IN_MOVED_FROM | libc::IN_DELETE;
+		if unsafe { libc::inotify_add_watch(fd, path.as_ptr(), mask) } < 0 {
+			return Err(anyhow!(
+				"inotify_add_watch {}: {}",
+				dir.display(),
+				std::io::Error::last_os_error()
+			));
+		}
+		Ok(w)
+	}
+
+	// 非阻塞读完积攒的事件；有 *.json 变化就返回 true（临时文件 *.json.tmp 不算）
+	pub fn changed(&self) -> bool {
+		let mut buf = [0u8; 4096];
+		let mut changed = false;
+		loop {
+			let n = unsafe { libc::read(self.fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+			if n <= 0 {
+				break;
+			}
+			let n = n as usize;
+			let header = std::mem::size_of::<libc::inotify_event>();
+			let mut off = 0;
+			while off + header <= n {
+				let ev = unsafe { std::ptr::read_unaligned(buf.as_ptr().add(off) as *const libc::inotify_event) };
+				let name_bytes = &buf[off + header..(off + header + ev.len as usize).min(n)];
+				let name = String::from_utf8_lossy(name_bytes);
+				let name = name.trim_end_matches('\0');
+				if name.ends_with(".json") {
+					changed = true;
+				}
+				off += header + ev.len as usize;
+			}
+		}
+		changed
+	}
+}
+
+impl Drop for ConfigWatch {
+	fn drop(&mut self) {
+		unsafe {
+			libc::close(self.fd);
+		}
+	}
+}
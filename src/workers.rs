@@ -0,0 +1,299 @@
+use crate::backend::Backend;
+use crate::reconcile::{reconcile_group, ReconcileConfig};
+use crate::{dispatch_envelope, flush_outgoing, paths, reload_group_cfg, short_id, truncate, GroupRuntime, ReceiveEnvelope};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::Arc;
+use std::thread::Scope;
+use std::time::{Duration, Instant};
+
+// 空闲时多久检查一次待发消息（合并窗口/令牌桶）
+const WORKER_TICK: Duration = Duration::from_secs(1);
+
+// global.json 里的 "workers" 段
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WorkerConfig {
+	// 每个群最多积压多少条事件，满了读取线程就停下来等（背压）
+	pub queue_capacity: usize,
+	pub metrics_interval_secs: u64,
+}
+
+impl Default for WorkerConfig {
+	fn default() -> Self {
+		WorkerConfig {
+			queue_capacity: 256,
+			metrics_interval_secs: 60,
+		}
+	}
+}
+
+pub fn metrics_path() -> PathBuf {
+	paths::run_dir().join("queues.json")
+}
+
+#[derive(Default)]
+struct QueueStats {
+	depth: AtomicUsize,
+	max_depth: AtomicUsize,
+	processed: AtomicU64,
+	// 队列满、读取线程被迫等待的次数
+	blocked: AtomicU64,
+}
+
+#[derive(Serialize)]
+struct QueueSnapshot {
+	depth: usize,
+	max_depth: usize,
+	processed: u64,
+	blocked: u64,
+}
+
+enum Job {
+	Event(Box<ReceiveEnvelope>),
+	// 群配置文件变了，重新合并运维字段
+	Reload,
+	// SIGUSR1：把本群的运行时状态打到日志
+	Dump,
+}
+
+struct GroupQueue {
+	tx: SyncSender<Job>,
+	stats: Arc<QueueStats>,
+}
+
+// 读取线程 + 每群一个工作线程：同一群内按顺序处理，群与群之间互不阻塞
+pub struct Workers<'scope, 'env> {
+	scope: &'scope Scope<'scope, 'env>,
+	backend: &'env dyn Backend,
+	cfg: WorkerConfig,
+	reconcile_every: Option<Duration>,
+	queues: HashMap<String, GroupQueue>,
+	interval: Duration,
+	last_report: Instant,
+}
+
+impl<'scope, 'env> Workers<'scope, 'env> {
+	pub fn spawn(
+		scope: &'scope Scope<'scope, 'env>,
+		backend: &'env dyn Backend,
+		groups: HashMap<String, GroupRuntime>,
+		cfg: &WorkerConfig,
+		reconcile: &ReconcileConfig,
+	) -> Self {
+		let mut w = Workers {
+			scope,
+			backend,
+			cfg: cfg.clone(),
+			reconcile_every: Some(Duration::from_secs(reconcile.interval_secs)).filter(|d| !d.is_zero()),
+			queues: HashMap::new(),
+			interval: Duration::from_secs(cfg.metrics_interval_secs.max(1)),
+			last_report: Instant::now(),
+		};
+		for (i, (_, rt)) in groups.into_iter().enumerate() {
+			// 各群错开几秒再校对，免得同时打 listGroups
+			w.add(rt, Duration::from_secs(5 * i as u64));
+		}
+		w
+	}
+
+	fn add(&mut self, rt: GroupRuntime, stagger: Duration) {
+		let gid = rt.cfg.group_id.clone();
+		let (tx, rx) = mpsc::sync_channel(self.cfg.queue_capacity.max(1));
+		let stats = Arc::new(QueueStats::default());
+		let st = stats.clone();
+		let every = self.reconcile_every;
+		let first = every.map(|d| Instant::now() + d + stagger);
+		let backend = self.backend;
+		self.scope.spawn(move || worker_loop(backend, rt, rx, st, every, first));
+		self.queues.insert(gid, GroupQueue { tx, stats });
+	}
+
+	pub fn contains(&self, gid: &str) -> bool {
+		self.queues.contains_key(gid)
+	}
+
+	pub fn gids(&self) -> Vec<String> {
+		self.queues.keys().cloned().collect()
+	}
+
+	// 运行中新增的群配置
+	pub fn start(&mut self, rt: GroupRuntime) {
+		println!("[INF] now watching {}", short_id(&rt.cfg.group_id));
+		self.add(rt, Duration::ZERO);
+	}
+
+	// 配置文件被删掉：关掉发送端，工作线程处理完积压就退出
+	pub fn stop(&mut self, gid: &str) {
+		if self.queues.remove(gid).is_some() {
+			println!("[INF] stopped watching {} (config removed)", short_id(gid));
+		}
+	}
+
+	pub fn reload(&self, gid: &str) {
+		if let Some(q) = self.queues.get(gid) {
+			let _ = q.tx.send(Job::Reload);
+		}
+	}
+
+	// 退出时调用：关掉所有发送端，工作线程处理完积压、清空待发消息、写最后一次状态后退出
+	pub fn close(&mut self) {
+		let pending: usize = self.queues.values().map(|q| q.stats.depth.load(Ordering::SeqCst)).sum();
+		println!("[INF] stopping {} worker(s), {pending} queued event(s) left", self.queues.len());
+		self.queues.clear();
+	}
+
+	pub fn dump(&self) {
+		let mut gids: Vec<&String> = self.queues.keys().collect();
+		gids.sort();
+		for gid in gids {
+			let q = &self.queues[gid];
+			println!(
+				"[INF] dump {}: queue depth={} max={} processed={} blocked={}",
+				short_id(gid),
+				q.stats.depth.load(Ordering::SeqCst),
+				q.stats.max_depth.load(Ordering::SeqCst),
+				q.stats.processed.load(Ordering::SeqCst),
+				q.stats.blocked.load(Ordering::SeqCst),
+			);
+			if q.tx.try_send(Job::Dump).is_err() {
+				println!("[INF] dump {}: worker busy, runtime state skipped", short_id(gid));
+			}
+		}
+	}
+
+	// 不是被管理的群就丢掉；队列满时阻塞到有空位
+	pub fn dispatch(&self, ev: ReceiveEnvelope) {
+		let Some((gid, q)) = ev.group_id().and_then(|g| self.queues.get_key_value(g)) else {
+			return;
+		};
+
+		let depth = q.stats.depth.fetch_add(1, Ordering::SeqCst) + 1;
+		q.stats.max_depth.fetch_max(depth, Ordering::SeqCst);
+		let job = match q.tx.try_send(Job::Event(Box::new(ev))) {
+			Ok(()) => return,
+			Err(TrySendError::Full(job)) => job,
+			Err(TrySendError::Disconnected(_)) => {
+				q.stats.depth.fetch_sub(1, Ordering::SeqCst);
+				eprintln!("[WRN] worker for {} is gone, event dropped", short_id(gid));
+				return;
+			}
+		};
+
+		q.stats.blocked.fetch_add(1, Ordering::SeqCst);
+		eprintln!("[WRN] queue for {} is full ({} events), reader waiting", short_id(gid), depth - 1);
+		if q.tx.send(job).is_err() {
+			q.stats.depth.fetch_sub(1, Ordering::SeqCst);
+		}
+	}
+
+	// 读取线程每个 tick 调用：定期把队列深度写到 RUN_DIR/queues.json，有积压时也打一行日志
+	pub fn report(&mut self) {
+		if self.last_report.elapsed() < self.interval {
+			return;
+		}
+		self.last_report = Instant::now();
+
+		let snap: BTreeMap<&str, QueueSnapshot> = self
+			.queues
+			.iter()
+			.map(|(gid, q)| {
+				(
+					gid.as_str(),
+					QueueSnapshot {
+						depth: q.stats.depth.load(Ordering::SeqCst),
+						max_depth: q.stats.max_depth.load(Ordering::SeqCst),
+						processed: q.stats.processed.load(Ordering::SeqCst),
+						blocked: q.stats.blocked.load(Ordering::SeqCst),
+					},
+				)
+			})
+			.collect();
+
+		let backlog: Vec<String> = snap
+			.iter()
+			.filter(|(_, s)| s.depth > 0)
+			.map(|(gid, s)| format!("{}={}", short_id(gid), s.depth))
+			.collect();
+		if !backlog.is_empty() {
+			println!("[INF] queue depth: {}", backlog.join(" "));
+		}
+
+		let doc = serde_json::json!({ "ts": Utc::now().timestamp(), "groups": snap });
+		let _ = fs::create_dir_all(paths::run_dir());
+		if let Err(e) = fs::write(metrics_path(), doc.to_string()) {
+			eprintln!("[WRN] write {}: {e}", metrics_path().display());
+		}
+	}
+}
+
+fn worker_loop(
+	backend: &dyn Backend,
+	mut rt: GroupRuntime,
+	rx: Receiver<Job>,
+	stats: Arc<QueueStats>,
+	reconcile_every: Option<Duration>,
+	mut next_reconcile: Option<Instant>,
+) {
+	loop {
+		if let (Some(every), Some(at)) = (reconcile_every, next_reconcile) {
+			if Instant::now() >= at {
+				if let Err(e) = reconcile_group(backend, &mut rt) {
+					eprintln!("[WRN] reconcile {} failed: {e:#}", short_id(&rt.cfg.group_id));
+				}
+				next_reconcile = Some(Instant::now() + every);
+			}
+		}
+		match rx.recv_timeout(WORKER_TICK) {
+			Ok(Job::Reload) => match reload_group_cfg(&mut rt) {
+				Ok(true) => {
+					println!("[INF] reloaded config for {}", short_id(&rt.cfg.group_id));
+					// 开关/接管权限可能变了，立刻校对一次
+					if let Err(e) = reconcile_group(backend, &mut rt) {
+						eprintln!("[WRN] reconcile {} failed: {e:#}", short_id(&rt.cfg.group_id));
+					}
+				}
+				Ok(false) => {}
+				Err(e) => eprintln!("[WRN] reload config for {} failed: {e:#}", short_id(&rt.cfg.group_id)),
+			},
+			Ok(Job::Event(ev)) => {
+				stats.depth.fetch_sub(1, Ordering::SeqCst);
+				if let Err(e) = dispatch_envelope(backend, &mut rt, &ev) {
+					eprintln!("[WRN] handle event failed ({}): {e:#}", short_id(&rt.cfg.group_id));
+				}
+				stats.processed.fetch_add(1, Ordering::SeqCst);
+			}
+			Ok(Job::Dump) => dump_runtime(&rt),
+			Err(RecvTimeoutError::Timeout) => flush_outgoing(backend, &mut rt, false),
+			Err(RecvTimeoutError::Disconnected) => break,
+		}
+	}
+
+	// 发不出去的会进重试队列落盘；账号令牌不够、这次没发的记日志后丢弃，免得退出时一口气刷屏
+	flush_outgoing(backend, &mut rt, true);
+	for msg in rt.out.drain(&rt.cfg.throttle) {
+		eprintln!("[WRN] {}: dropped unsent message on shutdown: {}", short_id(&rt.cfg.group_id), truncate(&msg, 80));
+	}
+	if let Err(e) = rt.save_state() {
+		eprintln!("[WRN] save state for {} failed: {e:#}", short_id(&rt.cfg.group_id));
+	}
+}
+
+fn dump_runtime(rt: &GroupRuntime) {
+	println!(
+		"[INF] dump {}: name={:?} enabled={} bot_admin={} admins={} members={} snapshot={} pending_out={}",
+		short_id(&rt.cfg.group_id),
+		rt.state.group_name,
+		rt.cfg.enabled,
+		rt.state.bot_has_admin,
+		rt.admins.len(),
+		rt.members.len(),
+		rt.state.last_members_snapshot.len(),
+		rt.out.len(),
+	);
+}